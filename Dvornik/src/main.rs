@@ -0,0 +1,32 @@
+extern crate tracing_subscriber;
+
+use std::process;
+
+mod server;
+
+use server::DispatchServer;
+use rs_nodeconf::NodeConfig;
+
+fn main() {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::DEBUG)
+        .with_test_writer()
+        .init();
+
+    let nc = match NodeConfig::load() {
+        Ok(nc) => nc,
+        Err(e) => {
+            println!("{}", e);
+            process::exit(2);
+        },
+    };
+
+    let mut ds = match DispatchServer::new(&nc) {
+        Ok(ds) => ds,
+        Err(e) => {
+            println!("{}", e);
+            process::exit(2);
+        },
+    };
+    ds.run();
+}
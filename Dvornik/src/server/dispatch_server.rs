@@ -0,0 +1,227 @@
+use std::fmt;
+use std::collections::HashMap;
+
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private, Public};
+use openssl::rsa::{Padding, Rsa};
+use openssl::sign::Signer;
+
+use prost::Message;
+
+use tiny_http::{Server, Request, Response};
+
+use rs_nodeconf::NodeConfig;
+
+pub struct DispatchServer {
+    server: Server,
+    gateway_addr: String,
+    gateway_port: u16,
+    dispatch_url: String,
+    keys: HashMap<u8, DispatchKeys>,
+}
+
+// Keys used to encrypt and sign query_cur_region responses, prepared once at startup
+struct DispatchKeys {
+    encrypt_key: Rsa<Public>,
+    signing_key: PKey<Private>,
+}
+
+#[derive(Debug, Clone)]
+pub struct DispatchServerError {
+    reason: String,
+}
+
+impl DispatchServerError {
+    pub fn new(reason: &str) -> DispatchServerError {
+        return DispatchServerError {reason: reason.to_string()};
+    }
+}
+
+impl fmt::Display for DispatchServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "DispatchServerError: {}", self.reason)
+    }
+}
+
+impl DispatchServer {
+    const REGION_NAME: &'static str = "os_samovar";
+    const REGION_TITLE: &'static str = "RustySamovar";
+    const REGION_TYPE: &'static str = "DEV_PUBLIC";
+
+    pub fn new(node_config: &NodeConfig) -> Result<DispatchServer, DispatchServerError> {
+        let server = match Server::http(format!("{}:{}", node_config.dispatch_addr, node_config.dispatch_port)) {
+            Ok(server) => server,
+            Err(e) => return Err(DispatchServerError::new(format!("Failed to bind socket: {}", e).as_str())),
+        };
+
+        let ds = DispatchServer {
+            server: server,
+            gateway_addr: node_config.gateway_addr.clone(),
+            gateway_port: node_config.gateway_port,
+            dispatch_url: format!("http://{}:{}/query_cur_region", node_config.dispatch_addr, node_config.dispatch_port),
+            keys: DispatchServer::load_keys(&node_config.keys_dir)?,
+        };
+
+        return Ok(ds);
+    }
+
+    fn load_keys(keys_dir: &str) -> Result<HashMap<u8, DispatchKeys>, DispatchServerError> {
+        let mut keys = HashMap::new();
+
+        for (key_id, key_pair) in mhycrypt::load_rsa_keys("RSAConfig", keys_dir).iter() {
+            let public_components = key_pair.encrypt_key.n().to_owned()
+                .and_then(|n| key_pair.encrypt_key.e().to_owned().map(|e| (n, e)));
+
+            let encrypt_key = match public_components.and_then(|(n, e)| Rsa::from_public_components(n, e)) {
+                Ok(key) => key,
+                Err(e) => return Err(DispatchServerError::new(format!("Bad encryption key {}: {}", key_id, e).as_str())),
+            };
+
+            let signing_key = match PKey::from_rsa(key_pair.signing_key.clone()) {
+                Ok(key) => key,
+                Err(e) => return Err(DispatchServerError::new(format!("Bad signing key {}: {}", key_id, e).as_str())),
+            };
+
+            keys.insert(*key_id, DispatchKeys {
+                encrypt_key: encrypt_key,
+                signing_key: signing_key,
+            });
+        }
+
+        println!("Loaded {} dispatch keys", keys.len());
+
+        return Ok(keys);
+    }
+
+    pub fn run(&mut self) {
+        println!("Starting dispatch server");
+
+        for request in self.server.incoming_requests() {
+            let (path, query) = DispatchServer::parse_url(request.url());
+
+            println!("Dispatch request: {}", request.url());
+
+            let body = match path.as_str() {
+                "/query_region_list" => Some(Ok(self.query_region_list())),
+                // Some versions append region name to the path
+                p if p.starts_with("/query_cur_region") => Some(self.query_cur_region(&query)),
+                _ => None,
+            };
+
+            DispatchServer::respond(request, body);
+        }
+    }
+
+    fn respond(request: Request, body: Option<Result<String, DispatchServerError>>) {
+        let response = match body {
+            Some(Ok(body)) => Response::from_string(body),
+            Some(Err(e)) => {
+                println!("Failed to serve {}: {}", request.url(), e);
+                Response::from_string("Internal server error").with_status_code(500)
+            },
+            None => Response::from_string("Not found").with_status_code(404),
+        };
+
+        match request.respond(response) {
+            Ok(_) => {},
+            Err(e) => println!("Failed to send dispatch response: {}", e),
+        };
+    }
+
+    fn parse_url(url: &str) -> (String, HashMap<String, String>) {
+        let (path, query) = match url.split_once('?') {
+            Some((path, query)) => (path, query),
+            None => (url, ""),
+        };
+
+        let query = query.split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+
+        return (path.to_string(), query);
+    }
+
+    fn query_region_list(&self) -> String {
+        let region = proto::RegionSimpleInfo {
+            name: DispatchServer::REGION_NAME.to_string(),
+            title: DispatchServer::REGION_TITLE.to_string(),
+            r#type: DispatchServer::REGION_TYPE.to_string(),
+            dispatch_url: self.dispatch_url.clone(),
+            ..proto::RegionSimpleInfo::default()
+        };
+
+        let rsp = proto::QueryRegionListHttpRsp {
+            region_list: vec![region],
+            enable_login_pc: true,
+            ..proto::QueryRegionListHttpRsp::default()
+        };
+
+        let mut buf: Vec<u8> = vec!();
+
+        rsp.encode(&mut buf).unwrap();
+
+        return base64::encode(&buf);
+    }
+
+    fn query_cur_region(&self, query: &HashMap<String, String>) -> Result<String, DispatchServerError> {
+        let region_info = proto::RegionInfo {
+            gateserver_ip: self.gateway_addr.clone(),
+            gateserver_port: self.gateway_port as u32,
+            ..proto::RegionInfo::default()
+        };
+
+        let rsp = proto::QueryCurrRegionHttpRsp {
+            region_info: Some(region_info),
+            ..proto::QueryCurrRegionHttpRsp::default()
+        };
+
+        let mut data: Vec<u8> = vec!();
+
+        rsp.encode(&mut data).unwrap();
+
+        // Versions 2.7.5x+ pass key ID and expect the response to be encrypted and signed
+        let key_id: u8 = match query.get("key_id").map(|k| k.parse()) {
+            Some(Ok(key_id)) => key_id,
+            Some(Err(_)) => {
+                println!("Malformed key ID {:?}, replying in plain", query.get("key_id"));
+                return Ok(base64::encode(&data));
+            },
+            None => return Ok(base64::encode(&data)),
+        };
+
+        let keys = match self.keys.get(&key_id) {
+            Some(keys) => keys,
+            None => {
+                println!("Unknown key ID {}, replying in plain", key_id);
+                return Ok(base64::encode(&data));
+            },
+        };
+
+        // Encrypt the data chunk by chunk, as much as PKCS1 padding allows for a single block
+        let block_size = keys.encrypt_key.size() as usize;
+        let mut content: Vec<u8> = Vec::with_capacity((data.len() / (block_size - 11) + 1) * block_size);
+
+        for chunk in data.chunks(block_size - 11) {
+            let mut enc_buf: Vec<u8> = vec![0; block_size];
+
+            let len = match keys.encrypt_key.public_encrypt(chunk, &mut enc_buf, Padding::PKCS1) {
+                Ok(len) => len,
+                Err(e) => return Err(DispatchServerError::new(format!("Failed to encrypt with key {}: {}", key_id, e).as_str())),
+            };
+
+            content.extend_from_slice(&enc_buf[..len]);
+        }
+
+        // Sign the plain data
+        let signature = match Signer::new(MessageDigest::sha256(), &keys.signing_key).and_then(|mut signer| signer.sign_oneshot_to_vec(&data)) {
+            Ok(signature) => signature,
+            Err(e) => return Err(DispatchServerError::new(format!("Failed to sign with key {}: {}", key_id, e).as_str())),
+        };
+
+        return Ok(serde_json::json!({
+            "content": base64::encode(&content),
+            "sign": base64::encode(&signature),
+        }).to_string());
+    }
+}
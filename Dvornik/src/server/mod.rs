@@ -0,0 +1,3 @@
+mod dispatch_server;
+
+pub use self::dispatch_server::DispatchServer;
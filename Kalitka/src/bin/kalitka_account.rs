@@ -0,0 +1,64 @@
+/*
+  Account management for the gateway: unknown accounts are registered on their first login,
+  this is for setting them up in advance, e.g. to log in as an existing player, or removing them.
+  Works on the database from the node config (RS_DATABASE_URL and friends apply as usual).
+ */
+
+#[path = "../dbmanager/mod.rs"]
+#[allow(dead_code)]
+mod dbmanager;
+
+use std::env;
+use std::process;
+
+use rs_nodeconf::NodeConfig;
+
+use dbmanager::DatabaseManager;
+
+fn usage() -> ! {
+    println!("Usage: kalitka_account add <account> <token> [uid]");
+    println!("       kalitka_account remove <account>");
+    process::exit(2);
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    let nc = match NodeConfig::load() {
+        Ok(nc) => nc,
+        Err(e) => {
+            println!("{}", e);
+            process::exit(2);
+        },
+    };
+
+    let db = DatabaseManager::new(&nc.database_url);
+
+    match args.iter().map(|a| a.as_str()).collect::<Vec<&str>>().as_slice() {
+        ["add", account_uid, account_token] | ["add", account_uid, account_token, _] => {
+            let uid = match args.get(3) {
+                Some(uid) => Some(uid.parse().unwrap_or_else(|_| usage())),
+                None => None,
+            };
+
+            match db.create_account(account_uid, account_token, uid) {
+                Ok(account) => println!("Added account {} as UID {}", account.account_uid, account.uid),
+                Err(e) => {
+                    println!("Failed to add account {}: {}", account_uid, e);
+                    process::exit(1);
+                },
+            };
+        },
+        ["remove", account_uid] => {
+            match db.remove_account(account_uid) {
+                Ok(true) => println!("Removed account {}", account_uid),
+                Ok(false) => println!("No account {}", account_uid),
+                Err(e) => {
+                    println!("Failed to remove account {}: {}", account_uid, e);
+                    process::exit(1);
+                },
+            };
+        },
+        _ => usage(),
+    };
+}
@@ -0,0 +1,333 @@
+/*
+  Load test for the gateway: spawns lots of simulated clients, logs them in and makes them ping the server.
+  Packets are built with the very same code gateway uses to parse them.
+  Accounts for the clients are added to the gateway's database before the run and removed after it.
+ */
+
+#[path = "../utils/handshake_packet.rs"]
+#[allow(dead_code)]
+mod handshake_packet;
+#[path = "../utils/data_packet.rs"]
+#[allow(dead_code)]
+mod data_packet;
+#[path = "../dbmanager/mod.rs"]
+#[allow(dead_code)]
+mod dbmanager;
+
+use std::env;
+use std::fs;
+use std::io;
+use std::io::{Cursor, Write};
+use std::convert::TryInto;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use prost::Message;
+
+use kcp::Kcp;
+
+use rs_nodeconf::NodeConfig;
+
+use handshake_packet::HandshakePacket;
+use data_packet::DataPacket;
+use dbmanager::DatabaseManager;
+
+struct LoadConfig {
+    gateway: SocketAddr,
+    sessions: u32,
+    duration: Duration,
+    pings_per_second: u32,
+    database_url: String,
+}
+
+#[derive(Default)]
+struct LoadStats {
+    connected: AtomicU64,
+    logged_in: AtomicU64,
+    failed: AtomicU64,
+    login_time_ms: AtomicU64,
+    packets_sent: AtomicU64,
+    packets_received: AtomicU64,
+}
+
+struct Output {
+    socket: UdpSocket,
+}
+
+impl Write for Output {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        return self.socket.send(data);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Gateway limits handshakes per IP, so on loopback every few sessions get an address of their own
+const SESSIONS_PER_SOURCE: u32 = 10;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+fn usage() -> ! {
+    println!("Usage: kalitka_load [--gateway <host:port>] [--sessions <n>] [--duration <seconds>] [--rate <pings per second>] [--database <url>]");
+    process::exit(2);
+}
+
+fn parse_args() -> LoadConfig {
+    let database_url = match NodeConfig::load() {
+        Ok(nc) => nc.database_url,
+        Err(e) => {
+            println!("{}", e);
+            process::exit(2);
+        },
+    };
+
+    let mut config = LoadConfig {
+        gateway: "127.0.0.1:4242".parse().unwrap(),
+        sessions: 100,
+        duration: Duration::from_secs(30),
+        pings_per_second: 10,
+        database_url: database_url,
+    };
+
+    let mut args = env::args().skip(1);
+
+    while let Some(arg) = args.next() {
+        let value = args.next().unwrap_or_else(|| usage());
+
+        match arg.as_str() {
+            "--gateway" => config.gateway = value.parse().unwrap_or_else(|_| usage()),
+            "--sessions" => config.sessions = value.parse().unwrap_or_else(|_| usage()),
+            "--duration" => config.duration = Duration::from_secs(value.parse().unwrap_or_else(|_| usage())),
+            "--rate" => config.pings_per_second = value.parse().unwrap_or_else(|_| usage()),
+            "--database" => config.database_url = value,
+            _ => usage(),
+        };
+    }
+
+    return config;
+}
+
+fn source_address(gateway: &SocketAddr, session: u32) -> SocketAddr {
+    let ip = match gateway.ip() {
+        IpAddr::V4(ip) if ip.is_loopback() => {
+            let index = session / SESSIONS_PER_SOURCE;
+            IpAddr::V4(Ipv4Addr::new(127, 1, (index / 250) as u8, (index % 250 + 1) as u8))
+        },
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(_) => IpAddr::V6(std::net::Ipv6Addr::UNSPECIFIED),
+    };
+
+    return SocketAddr::new(ip, 0);
+}
+
+fn handshake(socket: &UdpSocket) -> io::Result<(u32, u32)> {
+    socket.send(&HandshakePacket::new_connect().to_bytes())?;
+
+    let started = Instant::now();
+    let mut buffer = [0u8; 1024];
+
+    while started.elapsed() < HANDSHAKE_TIMEOUT {
+        match socket.recv(&mut buffer) {
+            Ok(size) => match HandshakePacket::new(&buffer[..size]) {
+                Ok(reply) => return Ok((reply.conv(), reply.token())),
+                Err(_) => continue,
+            },
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => continue,
+            Err(e) => return Err(e),
+        };
+    }
+
+    return Err(io::Error::new(io::ErrorKind::TimedOut, "No reply to CONNECT"));
+}
+
+fn send_packet<M: prost::Message>(kcp: &mut Kcp<Output>, key: &[u8; 0x1000], packet_id: proto::PacketId, message: &M) -> Result<(), kcp::Error> {
+    let mut data: Vec<u8> = vec![];
+    message.encode(&mut data).unwrap();
+
+    let mut metadata: Vec<u8> = vec![];
+    proto::PacketHead::default().encode(&mut metadata).unwrap();
+
+    let mut bytes = DataPacket::new(packet_id as u16, metadata, data).to_bytes();
+    mhycrypt::mhy_xor(&mut bytes, key);
+
+    kcp.send(&bytes)?;
+    kcp.flush()?;
+
+    return Ok(());
+}
+
+fn account_name(id: u32) -> (String, String) {
+    return (format!("load_test_{}", id), format!("load_test_token_{}", id));
+}
+
+// Leftovers of a run that didn't finish are removed as well, so that accounts can be created anew
+fn remove_accounts(db: &DatabaseManager, sessions: u32) {
+    for id in 0..sessions {
+        let (account_uid, _) = account_name(id);
+
+        match db.remove_account(&account_uid) {
+            Ok(_) => {},
+            Err(e) => println!("Failed to remove account {}: {}", account_uid, e),
+        };
+    }
+}
+
+fn create_accounts(db: &DatabaseManager, sessions: u32) -> Result<(), String> {
+    for id in 0..sessions {
+        let (account_uid, account_token) = account_name(id);
+
+        db.create_account(&account_uid, &account_token, None).map_err(|e| format!("Failed to add account {}: {}", account_uid, e))?;
+    }
+
+    return Ok(());
+}
+
+fn simulate(id: u32, config: Arc<LoadConfig>, stats: Arc<LoadStats>, master_key: Arc<[u8; 0x1000]>) -> Result<(), String> {
+    let socket = UdpSocket::bind(source_address(&config.gateway, id)).map_err(|e| format!("Failed to bind: {}", e))?;
+    socket.connect(config.gateway).map_err(|e| format!("Failed to connect: {}", e))?;
+    socket.set_read_timeout(Some(POLL_INTERVAL)).map_err(|e| format!("Failed to set timeout: {}", e))?;
+
+    let (conv, token) = handshake(&socket).map_err(|e| format!("Handshake failed: {}", e))?;
+    stats.connected.fetch_add(1, Ordering::Relaxed);
+
+    let output = Output {
+        socket: socket.try_clone().map_err(|e| format!("Failed to clone socket: {}", e))?,
+    };
+
+    let mut kcp = Kcp::new(conv, token, output);
+    let mut key = *master_key;
+
+    let started = Instant::now();
+    let (account_uid, account_token) = account_name(id);
+    let login = proto::GetPlayerTokenReq {
+        account_uid: account_uid,
+        account_token: account_token,
+        ..proto::GetPlayerTokenReq::default()
+    };
+
+    send_packet(&mut kcp, &key, proto::PacketId::GetPlayerTokenReq, &login).map_err(|e| format!("Failed to send login: {:?}", e))?;
+
+    let ping_interval = Duration::from_secs(1) / config.pings_per_second.max(1);
+    let mut logged_in = false;
+    let mut last_ping = Instant::now();
+    let mut buffer = [0u8; 0x20000];
+
+    while started.elapsed() < config.duration {
+        match socket.recv(&mut buffer) {
+            Ok(size) => kcp.input(&buffer[..size]).map_err(|e| format!("KCP error: {:?}", e))?,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {},
+            Err(e) => return Err(format!("Failed to receive: {}", e)),
+        };
+
+        kcp.update(started.elapsed().as_millis() as u32).map_err(|e| format!("KCP error: {:?}", e))?;
+
+        while let Ok(size) = kcp.recv(&mut buffer) {
+            mhycrypt::mhy_xor(&mut buffer[..size], &key);
+
+            let packet = DataPacket::new_from_bytes(&buffer[..size]).map_err(|e| format!("Malformed packet: {}", e))?;
+            stats.packets_received.fetch_add(1, Ordering::Relaxed);
+
+            if packet.packet_id == proto::PacketId::GetPlayerTokenRsp as u16 {
+                let rsp = proto::GetPlayerTokenRsp::decode(&mut Cursor::new(&packet.data)).map_err(|e| format!("Malformed login reply: {}", e))?;
+
+                if rsp.retcode != 0 {
+                    return Err(format!("Login rejected with retcode {}", rsp.retcode));
+                }
+
+                stats.login_time_ms.fetch_add(started.elapsed().as_millis() as u64, Ordering::Relaxed);
+                stats.logged_in.fetch_add(1, Ordering::Relaxed);
+
+                // Everything past the login is encrypted with the session key
+                mhycrypt::mhy_generate_key(&mut key, rsp.secret_key_seed, false);
+                logged_in = true;
+            }
+        }
+
+        if logged_in && last_ping.elapsed() >= ping_interval {
+            let ping = proto::PingReq {
+                client_time: started.elapsed().as_secs() as u32,
+                ..proto::PingReq::default()
+            };
+
+            send_packet(&mut kcp, &key, proto::PacketId::PingReq, &ping).map_err(|e| format!("Failed to send ping: {:?}", e))?;
+            stats.packets_sent.fetch_add(1, Ordering::Relaxed);
+            last_ping = Instant::now();
+        }
+    }
+
+    let _ = socket.send(&HandshakePacket::new_disconnect(conv, token, 1).to_bytes());
+
+    return Ok(());
+}
+
+fn main() {
+    let config = Arc::new(parse_args());
+    let stats = Arc::new(LoadStats::default());
+
+    let master_key: Arc<[u8; 0x1000]> = match fs::read("./keys/master.key").map(|key| key.try_into()) {
+        Ok(Ok(key)) => Arc::new(key),
+        Ok(Err(_)) => {
+            println!("Incorrect master key");
+            process::exit(2);
+        },
+        Err(e) => {
+            println!("Failed to read master key: {}", e);
+            process::exit(2);
+        },
+    };
+
+    let db = DatabaseManager::new(&config.database_url);
+
+    remove_accounts(&db, config.sessions);
+
+    match create_accounts(&db, config.sessions) {
+        Ok(_) => {},
+        Err(e) => {
+            println!("{}", e);
+            remove_accounts(&db, config.sessions);
+            process::exit(2);
+        },
+    };
+
+    println!("Starting {} sessions against {} for {} seconds", config.sessions, config.gateway, config.duration.as_secs());
+
+    let started = Instant::now();
+
+    let handles: Vec<thread::JoinHandle<()>> = (0..config.sessions).map(|id| {
+        let config = config.clone();
+        let stats = stats.clone();
+        let master_key = master_key.clone();
+
+        thread::spawn(move || {
+            match simulate(id, config, stats.clone(), master_key) {
+                Ok(_) => {},
+                Err(e) => {
+                    println!("Session {} failed: {}", id, e);
+                    stats.failed.fetch_add(1, Ordering::Relaxed);
+                },
+            };
+        })
+    }).collect();
+
+    for handle in handles {
+        let _ = handle.join();
+    }
+
+    remove_accounts(&db, config.sessions);
+
+    let elapsed = started.elapsed().as_secs_f64();
+    let logged_in = stats.logged_in.load(Ordering::Relaxed);
+
+    println!("Sessions: {} requested, {} connected, {} logged in, {} failed",
+             config.sessions, stats.connected.load(Ordering::Relaxed), logged_in, stats.failed.load(Ordering::Relaxed));
+    println!("Average login time: {} ms", stats.login_time_ms.load(Ordering::Relaxed) / logged_in.max(1));
+    println!("Packets: {} sent ({:.1}/s), {} received ({:.1}/s)",
+             stats.packets_sent.load(Ordering::Relaxed), stats.packets_sent.load(Ordering::Relaxed) as f64 / elapsed,
+             stats.packets_received.load(Ordering::Relaxed), stats.packets_received.load(Ordering::Relaxed) as f64 / elapsed);
+}
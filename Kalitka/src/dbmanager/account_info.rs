@@ -0,0 +1,65 @@
+// Database Manager
+
+use sea_orm::entity::prelude::*;
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
+#[sea_orm(table_name = "account_info")]
+pub struct Model {
+    #[sea_orm(primary_key, autoincrement = false)]
+    pub uid: u32,
+    #[sea_orm(unique)]
+    pub account_uid: String,
+    // See `hash_token`; tokens themselves are never stored
+    pub account_token_hash: String,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter)]
+pub enum Relation {
+}
+
+impl RelationTrait for Relation {
+    fn def(&self) -> RelationDef {
+        match self {
+            _ => panic!("Unknown relation type!"),
+        }
+    }
+}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+impl Model {
+    const HASH_SCHEME: &'static str = "sha256";
+    const SALT_SIZE: usize = 16;
+
+    // Salted SHA-256 of the token, stored as "sha256$<salt>$<digest>" with both parts in base64
+    pub fn hash_token(token: &str) -> String {
+        let salt: [u8; Self::SALT_SIZE] = rand::random();
+
+        return format!("{}${}${}", Self::HASH_SCHEME, base64::encode(&salt), base64::encode(&Self::digest(&salt, token)));
+    }
+
+    pub fn token_matches(&self, token: &str) -> bool {
+        let parts: Vec<&str> = self.account_token_hash.split('$').collect();
+
+        let (salt, digest) = match parts.as_slice() {
+            [scheme, salt, digest] if *scheme == Self::HASH_SCHEME => match (base64::decode(salt), base64::decode(digest)) {
+                (Ok(salt), Ok(digest)) => (salt, digest),
+                _ => return false,
+            },
+            _ => return false,
+        };
+
+        let expected = Self::digest(&salt, token);
+
+        return digest.len() == expected.len() && openssl::memcmp::eq(&digest, &expected);
+    }
+
+    fn digest(salt: &[u8], token: &str) -> [u8; 32] {
+        let mut hasher = openssl::sha::Sha256::new();
+
+        hasher.update(salt);
+        hasher.update(token.as_bytes());
+
+        return hasher.finish();
+    }
+}
@@ -0,0 +1,106 @@
+// Database Manager
+
+use sea_orm::{entity::*, error::*, query::*, DbConn, Database, Schema, ConnectionTrait};
+use sea_orm::entity::prelude::*;
+
+pub use super::account_info::Model as AccountInfo;
+use super::account_info::Entity as AccountInfoEntity;
+
+/*
+  This is used to convert async operations into sync ones
+ */
+trait Block {
+    fn wait(self) -> <Self as futures::Future>::Output
+        where Self: Sized, Self: futures::Future
+    {
+        futures::executor::block_on(self)
+    }
+}
+
+impl<F,T> Block for F
+    where F: futures::Future<Output = T>
+{}
+
+/*
+  Database manager itself.
+  Accounts are kept in the same database RustySamovar uses, so UIDs handed out here are the ones it knows players by.
+ */
+
+#[derive(Debug)]
+pub struct DatabaseManager {
+    db: DbConn,
+}
+
+impl DatabaseManager {
+    // UID that the very first account gets; matches the one preexisting player data is stored under
+    const FIRST_UID: u32 = 1337;
+
+    pub fn new(conn_string: &str) -> Self {
+        let dm = DatabaseManager {
+            db: Database::connect(conn_string).wait().unwrap(),
+        };
+
+        dm.create_tables();
+
+        return dm;
+    }
+
+    fn create_tables(&self) {
+        let builder = self.db.get_database_backend();
+        let schema = Schema::new(builder);
+
+        let mut statement = schema.create_table_from_entity(AccountInfoEntity);
+        statement.if_not_exists();
+
+        self.db.execute(builder.build(&statement)).wait().unwrap();
+    }
+
+    pub fn get_account(&self, account_uid: &str) -> Result<Option<AccountInfo>, DbErr> {
+        return AccountInfoEntity::find()
+            .filter(super::account_info::Column::AccountUid.eq(account_uid))
+            .one(&self.db).wait();
+    }
+
+    /*
+      Creates an account logging in as the given UID, or as the next free one if none is given.
+      Game server makes player data for UIDs it doesn't know yet, so any free UID will do.
+     */
+    pub fn create_account(&self, account_uid: &str, account_token: &str, uid: Option<u32>) -> Result<AccountInfo, DbErr> {
+        let uid = match uid {
+            Some(uid) => uid,
+            None => self.next_free_uid()?,
+        };
+
+        let account = super::account_info::ActiveModel {
+            uid: ActiveValue::Set(uid),
+            account_uid: ActiveValue::Set(account_uid.to_string()),
+            account_token_hash: ActiveValue::Set(AccountInfo::hash_token(account_token)),
+        };
+
+        AccountInfoEntity::insert(account).exec(&self.db).wait()?;
+
+        match AccountInfoEntity::find_by_id(uid).one(&self.db).wait()? {
+            Some(account) => Ok(account),
+            None => Err(DbErr::Custom(format!("Failed to find inserted account {}", uid))),
+        }
+    }
+
+    pub fn remove_account(&self, account_uid: &str) -> Result<bool, DbErr> {
+        let result = AccountInfoEntity::delete_many()
+            .filter(super::account_info::Column::AccountUid.eq(account_uid))
+            .exec(&self.db).wait()?;
+
+        return Ok(result.rows_affected > 0);
+    }
+
+    pub fn next_free_uid(&self) -> Result<u32, DbErr> {
+        let last_account = AccountInfoEntity::find()
+            .order_by_desc(super::account_info::Column::Uid)
+            .one(&self.db).wait()?;
+
+        match last_account {
+            Some(account) => return Ok(account.uid + 1),
+            None => return Ok(Self::FIRST_UID),
+        };
+    }
+}
@@ -0,0 +1,5 @@
+pub mod database_manager;
+
+pub use self::database_manager::DatabaseManager;
+
+mod account_info;
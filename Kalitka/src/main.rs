@@ -5,12 +5,15 @@ extern crate tracing_subscriber;
 #[macro_use]
 extern crate num_derive;
 
+use std::process;
 use std::thread;
 
 mod server;
 mod utils;
+mod dbmanager;
 
 use server::NetworkServer;
+use rs_nodeconf::NodeConfig;
 
 fn main() {
     //pretty_env_logger::init();
@@ -22,6 +25,14 @@ fn main() {
         .with_test_writer()
         .init();
 
-    let mut ns = NetworkServer::new("0.0.0.0", 4242).unwrap();
+    let nc = match NodeConfig::load() {
+        Ok(nc) => nc,
+        Err(e) => {
+            println!("{}", e);
+            process::exit(2);
+        },
+    };
+
+    let mut ns = NetworkServer::new(nc).unwrap();
     ns.run().expect("Failed to serve!");
 }
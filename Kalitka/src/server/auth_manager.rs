@@ -1,10 +1,11 @@
 use openssl::hash::MessageDigest;
-use openssl::pkey::PKey;
-use openssl::rsa::Padding;
+use openssl::pkey::{PKey, Private, Public};
+use openssl::rsa::{Padding, Rsa};
 use openssl::sign::Signer;
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc, Mutex};
 use std::collections::HashMap;
 use std::convert::TryInto;
+use std::fmt;
 
 use prost::Message;
 
@@ -15,39 +16,111 @@ use packet_processor_macro::*;
 use packet_processor::*;
 use rs_nodeconf::NodeConfig;
 
+use crate::dbmanager::DatabaseManager;
+use crate::server::UserRegistry;
+
+/*
+  Handles logins. Looking accounts up and exchanging seeds takes a while, so it's done outside of the user registry lock,
+  which is only taken to record the outcome.
+ */
 #[packet_processor(GetPlayerTokenReq)]
 pub struct AuthManager {
-    conv_to_user: HashMap<u32, u32>,
-    user_to_conv: HashMap<u32, u32>,
+    users: Arc<Mutex<UserRegistry>>,
     //packets_to_send_tx: mpsc::Sender<IpcMessage>,
     packets_to_send_tx: PushSocket,
+    db: DatabaseManager,
+    keys: HashMap<u8, SeedKeys>,
 }
 
-impl AuthManager {
-    pub const SPOOFED_PLAYER_UID: u32 = 1337;
+// Keys used to exchange seeds with newer clients, prepared once at startup
+struct SeedKeys {
+    encrypt_key: Rsa<Public>,
+    decrypt_key: Rsa<Private>,
+    signing_key: PKey<Private>,
+}
+
+#[derive(Debug, Clone)]
+pub struct AuthManagerError {
+    reason: String,
+}
+
+impl AuthManagerError {
+    pub fn new(reason: &str) -> AuthManagerError {
+        return AuthManagerError {reason: reason.to_string()};
+    }
+}
+
+impl fmt::Display for AuthManagerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "AuthManagerError: {}", self.reason)
+    }
+}
 
-    pub fn new(node_config: &NodeConfig) -> AuthManager {
+impl AuthManager {
+    pub fn new(node_config: &NodeConfig, db: DatabaseManager, users: Arc<Mutex<UserRegistry>>) -> Result<AuthManager, AuthManagerError> {
         let mut am = AuthManager {
-            conv_to_user: HashMap::new(),
-            user_to_conv: HashMap::new(),
+            users: users,
             packet_callbacks: HashMap::new(),
-            packets_to_send_tx: node_config.connect_out_queue().unwrap(),
+            packets_to_send_tx: match node_config.connect_out_queue() {
+                Ok(socket) => socket,
+                Err(e) => return Err(AuthManagerError::new(format!("Failed to connect to the out queue: {}", e).as_str())),
+            },
+            db: db,
+            keys: AuthManager::load_keys(&node_config.keys_dir)?,
         };
 
         am.register();
 
-        return am;
+        return Ok(am);
+    }
+
+    fn load_keys(keys_dir: &str) -> Result<HashMap<u8, SeedKeys>, AuthManagerError> {
+        let mut keys = HashMap::new();
+
+        for (key_id, key_pair) in mhycrypt::load_rsa_keys("RSAConfig", keys_dir).iter() {
+            let public_components = key_pair.encrypt_key.n().to_owned()
+                .and_then(|n| key_pair.encrypt_key.e().to_owned().map(|e| (n, e)));
+
+            let encrypt_key = match public_components.and_then(|(n, e)| Rsa::from_public_components(n, e)) {
+                Ok(key) => key,
+                Err(e) => return Err(AuthManagerError::new(format!("Bad encryption key {}: {}", key_id, e).as_str())),
+            };
+
+            let signing_key = match PKey::from_rsa(key_pair.signing_key.clone()) {
+                Ok(key) => key,
+                Err(e) => return Err(AuthManagerError::new(format!("Bad signing key {}: {}", key_id, e).as_str())),
+            };
+
+            keys.insert(*key_id, SeedKeys {
+                encrypt_key: encrypt_key,
+                decrypt_key: key_pair.signing_key.clone(),
+                signing_key: signing_key,
+            });
+        }
+
+        println!("Loaded {} seed exchange keys", keys.len());
+
+        return Ok(keys);
     }
 
     pub fn process_get_player_token(&mut self, conv: u32, metadata: &proto::PacketHead, req: &proto::GetPlayerTokenReq, rsp: &mut proto::GetPlayerTokenRsp) {
-        let seed: u64 = 0xBABECAFEF00D; // TODO: use real value!
-        let client_hardcoded_seed: u64 = 0x12345678;
-        let uid = self.get_uid_by_account_id(req.account_uid.parse().unwrap());
+        // Every session gets its own key stream
+        let seed: u64 = rand::random();
 
         rsp.account_type = req.account_type;
         rsp.account_uid = req.account_uid.clone();
         rsp.token = req.account_token.clone();
-        rsp.secret_key_seed = seed; // TODO: temporary workaround!
+
+        let uid = match self.get_uid_by_account(&req.account_uid, &req.account_token) {
+            Ok(uid) => uid,
+            Err(retcode) => {
+                println!("Rejecting login of account {}: {:?}", req.account_uid, retcode);
+                rsp.retcode = retcode as i32;
+                return;
+            },
+        };
+
+        rsp.secret_key_seed = seed;
         rsp.uid = uid;
 
         if req.key_id > 0 { // TODO: detect client version properly!
@@ -68,70 +141,121 @@ impl AuthManager {
             // tool. While still possible, it's tiresome, and won't allow patched client to connect to official server without
             // switching back and forth between two versions of global-metadata.dat file.
 
-            let key_id = req.key_id as u8;
-
-            let rsa_key_collection = mhycrypt::load_rsa_keys("RSAConfig", "keys");
-            let keys = match rsa_key_collection.get(&key_id) {
-                Some(keys) => keys,
-                None => panic!("Unknown key ID {}!", key_id),
+            match self.exchange_seeds(req, seed, rsp) {
+                Ok(_) => {},
+                Err(retcode) => {
+                    println!("Key exchange with account {} failed: {:?}", req.account_uid, retcode);
+                    rsp.retcode = retcode as i32;
+                    return;
+                },
             };
+        }
 
-            // Decrypt received client seed
-
-            let client_seed_encrypted = base64::decode(&req.client_rand_key).unwrap();
+        // Response is sent once we're done here, so the session finds the user registered by the time it gets it
+        if !self.users.lock().unwrap().add_session(conv, uid, seed) {
+            println!("Client with conv {} left before logging in as {}", conv, uid);
+        }
+    }
 
-            let mut dec_buf: Vec<u8> = vec![0; 256];
+    fn exchange_seeds(&self, req: &proto::GetPlayerTokenReq, seed: u64, rsp: &mut proto::GetPlayerTokenRsp) -> Result<(), proto::Retcode> {
+        let key_id = req.key_id as u8;
 
-            let client_seed = match keys.signing_key.private_decrypt(&client_seed_encrypted, &mut dec_buf, Padding::PKCS1) {
-                Ok(seed_size) => {
-                    // Note: from_be_bytes here, because client seems to swap order of bytes for the seed
-                    u64::from_be_bytes(dec_buf[0..seed_size].try_into().unwrap())
-                },
-                Err(e) => { // TODO: must panic here!
-                    println!("Error decrypting client seed: {}", e);
-                    client_hardcoded_seed // TODO: temporary workaround!
-                },
-            };
+        let keys = match self.keys.get(&key_id) {
+            Some(keys) => keys,
+            None => {
+                println!("Unknown key ID {}!", key_id);
+                return Err(proto::Retcode::RetSvrError);
+            },
+        };
 
-            // Encrypt server seed which we'll use in negotiating with the client
+        // Decrypt received client seed
 
-            let mut enc_buf: Vec<u8> = vec![0; 256];
+        let client_seed_encrypted = match base64::decode(&req.client_rand_key) {
+            Ok(seed) => seed,
+            Err(e) => {
+                println!("Malformed client seed: {}", e);
+                return Err(proto::Retcode::RetTokenError);
+            },
+        };
 
-            // Note: to_be_bytes here, because client seems to swap order of bytes for the seed
-            let seed_bytes = (seed ^ client_seed).to_be_bytes();
+        let mut dec_buf: Vec<u8> = vec![0; 256];
 
-            let len = keys.encrypt_key.public_encrypt(&seed_bytes, &mut enc_buf, Padding::PKCS1).unwrap();
+        let client_seed = match keys.decrypt_key.private_decrypt(&client_seed_encrypted, &mut dec_buf, Padding::PKCS1) {
+            // Note: from_be_bytes here, because client seems to swap order of bytes for the seed
+            Ok(seed_size) => match dec_buf[0..seed_size].try_into() {
+                Ok(seed_bytes) => u64::from_be_bytes(seed_bytes),
+                Err(_) => {
+                    println!("Wrong client seed size: {}", seed_size);
+                    return Err(proto::Retcode::RetTokenError);
+                },
+            },
+            Err(e) => {
+                println!("Error decrypting client seed: {}", e);
+                return Err(proto::Retcode::RetTokenError);
+            },
+        };
 
-            // Sign it
-            let keypair = PKey::from_rsa(keys.signing_key.clone()).unwrap();
-            let mut signer = Signer::new(MessageDigest::sha256(), &keypair).unwrap();
-            let signature = signer.sign_oneshot_to_vec(&seed_bytes).unwrap();
+        // Encrypt server seed which we'll use in negotiating with the client
 
-            rsp.key_id = key_id as u32;
-            rsp.server_rand_key = base64::encode(&enc_buf);
-            rsp.sign = base64::encode(&signature);
-        }
+        let mut enc_buf: Vec<u8> = vec![0; 256];
 
-        self.conv_to_user.insert(conv, uid);
-        self.user_to_conv.insert(uid, conv);
-    }
+        // Note: to_be_bytes here, because client seems to swap order of bytes for the seed
+        let seed_bytes = (seed ^ client_seed).to_be_bytes();
 
-    fn get_uid_by_account_id(&self, account_uid: u32) -> u32 {
-        // TODO!
-        return AuthManager::SPOOFED_PLAYER_UID;
-    }
+        let len = match keys.encrypt_key.public_encrypt(&seed_bytes, &mut enc_buf, Padding::PKCS1) {
+            Ok(len) => len,
+            Err(e) => {
+                println!("Failed to encrypt server seed with key {}: {}", key_id, e);
+                return Err(proto::Retcode::RetSvrError);
+            },
+        };
 
-    pub fn resolve_conv(&self, conv: u32) -> Option<u32> {
-        match self.conv_to_user.get(&conv) {
-            Some(uid) => return Some(*uid),
-            None => return None,
+        // Sign it
+        let signature = match Signer::new(MessageDigest::sha256(), &keys.signing_key).and_then(|mut signer| signer.sign_oneshot_to_vec(&seed_bytes)) {
+            Ok(signature) => signature,
+            Err(e) => {
+                println!("Failed to sign server seed with key {}: {}", key_id, e);
+                return Err(proto::Retcode::RetSvrError);
+            },
         };
+
+        rsp.key_id = key_id as u32;
+        rsp.server_rand_key = base64::encode(&enc_buf[..len]);
+        rsp.sign = base64::encode(&signature);
+
+        return Ok(());
     }
 
-    pub fn resolve_uid(&self, uid: u32) -> Option<u32> {
-        match self.user_to_conv.get(&uid) {
-            Some(conv) => return Some(*conv),
-            None => return None,
+    fn get_uid_by_account(&self, account_uid: &str, account_token: &str) -> Result<u32, proto::Retcode> {
+        if account_uid.is_empty() || account_token.is_empty() {
+            return Err(proto::Retcode::RetTokenError);
+        }
+
+        match self.db.get_account(account_uid) {
+            Ok(Some(account)) => {
+                if account.token_matches(account_token) {
+                    return Ok(account.uid);
+                } else {
+                    return Err(proto::Retcode::RetTokenError);
+                }
+            },
+            Ok(None) => {
+                // First login registers the account, game server makes the player once it sees the new UID
+                match self.db.create_account(account_uid, account_token, None) {
+                    Ok(account) => {
+                        println!("Registered account {} as UID {}", account_uid, account.uid);
+                        return Ok(account.uid);
+                    },
+                    Err(e) => {
+                        println!("Failed to register account {}: {}", account_uid, e);
+                        return Err(proto::Retcode::RetSvrError);
+                    },
+                };
+            },
+            Err(e) => {
+                println!("Failed to look up account {}: {}", account_uid, e);
+                return Err(proto::Retcode::RetSvrError);
+            },
         };
     }
 }
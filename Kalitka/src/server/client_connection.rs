@@ -1,37 +1,119 @@
+use std::fmt;
 use std::io;
 use std::io::Read;
 use std::fs;
 use std::net::SocketAddr;
-use std::net::UdpSocket;
 use std::io::Write;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 use std::convert::TryInto;
+use std::sync::Arc;
+
+use prost::Message;
 
 use rs_utils::TimeManager;
 
+use crate::utils::HandshakePacket;
+use crate::utils::DataPacket;
+use crate::utils::PacketIdTable;
+use crate::server::GatewayStats;
+
 extern crate kcp;
 extern crate mhycrypt;
 
 use kcp::Kcp;
 
+#[cfg(feature = "raw_packet_dump")]
+use rs_capture::{CaptureWriter, CaptureRecord, Direction};
+
+#[derive(Debug)]
+pub enum ClientConnectionError {
+    KcpError(kcp::Error),
+}
+
+impl fmt::Display for ClientConnectionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientConnectionError::KcpError(e) => write!(f, "KCP error: {:?}", e),
+        }
+    }
+}
+
+impl From<kcp::Error> for ClientConnectionError {
+    fn from(e: kcp::Error) -> ClientConnectionError {
+        return ClientConnectionError::KcpError(e);
+    }
+}
+
 pub struct ClientConnection {
     conv: u32,
     token: u32,
     ikcp: Kcp<Source>,
     established_time: SystemTime,
+    last_received_time: SystemTime,
     key: [u8; 0x1000],
     pending_seed: Option<u64>,
+    error_count: u32,
+    packet_ids: Option<Arc<PacketIdTable>>,
+    #[cfg(feature = "raw_packet_dump")]
+    capture: Option<CaptureWriter>,
 }
 
 pub struct Source
 {
     address: Option<SocketAddr>,
-    socket: UdpSocket,
+    socket: Arc<tokio::net::UdpSocket>,
+    stats: Arc<GatewayStats>,
+    highest_sn: Option<u32>,
+}
+
+impl Source {
+    // miHoYo's KCP header has a token after conv, so it's 4 bytes longer than the stock one
+    const KCP_HEADER_SIZE: usize = 28;
+    const KCP_CMD_PUSH: u8 = 81;
+
+    /*
+      KCP crate doesn't tell us when it resends anything, so we look at what goes out.
+      Data segment with a sequence number we've already sent before is a retransmission.
+     */
+    fn count_retransmits(&mut self, data: &[u8]) {
+        let mut offset = 0;
+
+        while offset + Source::KCP_HEADER_SIZE <= data.len() {
+            let segment = &data[offset..];
+
+            let cmd = segment[8];
+            let sn = u32::from_le_bytes(segment[16..20].try_into().unwrap());
+            let len = u32::from_le_bytes(segment[24..28].try_into().unwrap()) as usize;
+
+            if cmd == Source::KCP_CMD_PUSH {
+                match self.highest_sn {
+                    Some(highest_sn) if sn <= highest_sn => GatewayStats::count(&self.stats.kcp_retransmits),
+                    _ => self.highest_sn = Some(sn),
+                };
+            }
+
+            offset += Source::KCP_HEADER_SIZE + len;
+        }
+    }
 }
 
 impl Write for Source {
     fn write(&mut self, data: &[u8]) -> io::Result<usize> {
-        return self.socket.send_to(data, self.address.expect("Unknown destination address!"));
+        // Socket is nonblocking; a full send buffer is no different from a datagram lost on the way, KCP will resend it
+        let size = match self.socket.try_send_to(data, self.address.expect("Unknown destination address!")) {
+            Ok(size) => size,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                GatewayStats::count(&self.stats.datagrams_dropped);
+                return Ok(data.len());
+            },
+            Err(e) => return Err(e),
+        };
+
+        GatewayStats::count(&self.stats.datagrams_sent);
+        GatewayStats::add(&self.stats.bytes_sent, size as u64);
+        self.count_retransmits(data);
+
+        return Ok(size);
     }
 
     fn flush(&mut self) -> io::Result<()> {
@@ -40,10 +122,17 @@ impl Write for Source {
 }
 
 impl ClientConnection {
-    pub fn new(socket: UdpSocket, conv: u32, token: u32) -> ClientConnection {
+    // Client pings us every few seconds, so a peer silent for that long is surely gone
+    const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+    #[cfg(feature = "raw_packet_dump")]
+    const CAPTURE_DIR: &'static str = "./captures";
+
+    pub fn new(socket: Arc<tokio::net::UdpSocket>, conv: u32, token: u32, master_key: &[u8; 0x1000], stats: Arc<GatewayStats>) -> ClientConnection {
         let s = Source {
             address: None,
             socket: socket,
+            stats: stats,
+            highest_sn: None,
         };
 
         return ClientConnection {
@@ -51,8 +140,13 @@ impl ClientConnection {
             token: token,
             ikcp: Kcp::new(conv, token, s),
             established_time: SystemTime::now(),
-            key: ClientConnection::read_key("master").try_into().expect("Incorrect master key"),
+            last_received_time: SystemTime::now(),
+            key: master_key.clone(),
             pending_seed: None,
+            error_count: 0,
+            packet_ids: None,
+            #[cfg(feature = "raw_packet_dump")]
+            capture: ClientConnection::open_capture(conv),
         };
     }
 
@@ -60,7 +154,62 @@ impl ClientConnection {
         self.ikcp.output.0.address = Some(new_source);
     }
 
-    pub fn process_udp_packet(&mut self, data: &[u8]) -> Vec<Vec<u8>> {
+    pub fn source(&self) -> Option<SocketAddr> {
+        return self.ikcp.output.0.address;
+    }
+
+    /*
+      Checks that the datagram carries at least one whole packet encrypted with this session's key, without feeding anything into KCP.
+      Only unfragmented data segments can be checked this way; datagrams without those don't pass.
+     */
+    pub fn carries_valid_packet(&self, data: &[u8]) -> bool {
+        let key = self.current_key();
+        let mut offset = 0;
+
+        while offset + Source::KCP_HEADER_SIZE <= data.len() {
+            let segment = &data[offset..];
+
+            let conv = u32::from_le_bytes(segment[0..4].try_into().unwrap());
+            let token = u32::from_le_bytes(segment[4..8].try_into().unwrap());
+            let cmd = segment[8];
+            let frg = segment[9];
+            let len = u32::from_le_bytes(segment[24..28].try_into().unwrap()) as usize;
+
+            if conv != self.conv || token != self.token || Source::KCP_HEADER_SIZE + len > segment.len() {
+                return false;
+            }
+
+            if cmd == Source::KCP_CMD_PUSH && frg == 0 {
+                let mut payload = segment[Source::KCP_HEADER_SIZE..Source::KCP_HEADER_SIZE + len].to_vec();
+                mhycrypt::mhy_xor(&mut payload, &key);
+
+                match DataPacket::new_from_bytes(&payload) {
+                    Ok(packet) => if proto::PacketHead::decode(&packet.metadata[..]).is_ok() {
+                        return true;
+                    },
+                    Err(_) => {},
+                };
+            }
+
+            offset += Source::KCP_HEADER_SIZE + len;
+        }
+
+        return false;
+    }
+
+    // Key the next incoming packet is going to be decrypted with
+    fn current_key(&self) -> [u8; 0x1000] {
+        let mut key = self.key.clone();
+
+        match self.pending_seed {
+            Some(seed) => mhycrypt::mhy_generate_key(&mut key, seed, false),
+            None => {},
+        };
+
+        return key;
+    }
+
+    pub fn process_udp_packet(&mut self, data: &[u8]) -> Result<Vec<Vec<u8>>, ClientConnectionError> {
         match self.pending_seed {
             None => {},
             Some(seed) => {
@@ -69,37 +218,73 @@ impl ClientConnection {
             },
         }
 
+        self.last_received_time = SystemTime::now();
+
         let mut packets: Vec<Vec<u8>> = Vec::new();
-        self.ikcp.input(data).unwrap();
-        self.ikcp.update(self.elapsed_time_millis()).unwrap();
-        self.ikcp.flush().unwrap();
+        self.ikcp.input(data)?;
+        self.ikcp.update(self.elapsed_time_millis())?;
+        self.ikcp.flush()?;
         loop {
             let mut buf = [0u8; 0x20000];
             match self.ikcp.recv(&mut buf) {
                 Err(_) => break,
                 Ok(size) => {
-                    #[cfg(feature = "raw_packet_dump")]
-                    {
-                        use pretty_hex::*;
-                        let cfg = HexConfig {title: true, width: 16, group: 0, ascii: true, ..HexConfig::default() };
-                        println!("{:?}", buf[..size].to_vec().hex_conf(cfg));
-                    }
                     mhycrypt::mhy_xor(&mut buf[..size], &self.key);
                     let data = buf[..size].to_owned();
                     packets.push(data);
                 },
             }
         }
-        self.ikcp.update(self.elapsed_time_millis()).unwrap();
-        return packets;
+        self.ikcp.update(self.elapsed_time_millis())?;
+        return Ok(packets);
+    }
+
+    pub fn update(&mut self) {
+        match self.ikcp.update(self.elapsed_time_millis()) {
+            Ok(_) => {},
+            Err(e) => println!("Failed to update KCP state for conv {}: {:?}", self.conv, e),
+        };
+    }
+
+    pub fn is_dead(&self) -> bool {
+        if self.ikcp.is_dead_link() {
+            return true;
+        }
+
+        let idle_time = Duration::from_millis(TimeManager::duration_since(self.last_received_time));
+
+        return idle_time > ClientConnection::IDLE_TIMEOUT;
+    }
+
+    pub fn send_disconnect(&mut self, reason: u32) {
+        let packet = HandshakePacket::new_disconnect(self.conv, self.token, reason);
+
+        let source = &self.ikcp.output.0;
+
+        match source.address {
+            Some(address) => match source.socket.try_send_to(&packet.to_bytes(), address) {
+                Ok(_) => {},
+                Err(e) => println!("Failed to send DISCONNECT to conv {}: {}", self.conv, e),
+            },
+            None => {}, // Never heard of the client, no one to tell
+        };
+    }
+
+    // Packet ID table of the client's version; unknown until it sends GetPlayerTokenReq
+    pub fn packet_ids(&self) -> Option<Arc<PacketIdTable>> {
+        return self.packet_ids.clone();
+    }
+
+    pub fn set_packet_ids(&mut self, packet_ids: Arc<PacketIdTable>) {
+        self.packet_ids = Some(packet_ids);
     }
 
     pub fn update_key(&mut self, seed: u64) {
         self.pending_seed = Some(seed);
     }
 
-    fn read_key(key_name: &str) -> Vec<u8> {
-        let filename = format!("./{}/{}.key", "keys", key_name);
+    pub fn read_key(keys_dir: &str, key_name: &str) -> Vec<u8> {
+        let filename = format!("{}/{}.key", keys_dir, key_name);
         let mut f = fs::File::open(&filename).expect(&format!("File '{}' not found", filename));
         let metadata = fs::metadata(&filename).expect("unable to read metadata");
         let mut buffer = vec![0; metadata.len() as usize];
@@ -111,11 +296,65 @@ impl ClientConnection {
         return TimeManager::duration_since(self.established_time).try_into().unwrap();
     }
 
-    pub fn send_udp_packet(&mut self, data: &[u8]) {
+    pub fn send_udp_packet(&mut self, data: &[u8]) -> Result<(), ClientConnectionError> {
+        self.queue_udp_packet(data)?;
+        return self.flush();
+    }
+
+    // Packet only hits the wire on the next flush, so several of them can share datagrams
+    pub fn queue_udp_packet(&mut self, data: &[u8]) -> Result<(), ClientConnectionError> {
         let mut buf = data.to_owned();
         mhycrypt::mhy_xor(&mut buf, &self.key);
-        self.ikcp.send(&buf).expect("Failed to send data!");
-        self.ikcp.flush().unwrap();
-        self.ikcp.update(self.elapsed_time_millis()).unwrap();
+        self.ikcp.send(&buf)?;
+        return Ok(());
+    }
+
+    pub fn flush(&mut self) -> Result<(), ClientConnectionError> {
+        self.ikcp.flush()?;
+        self.ikcp.update(self.elapsed_time_millis())?;
+        return Ok(());
+    }
+
+    #[cfg(feature = "raw_packet_dump")]
+    fn open_capture(conv: u32) -> Option<CaptureWriter> {
+        match CaptureWriter::for_session(ClientConnection::CAPTURE_DIR, conv) {
+            Ok(writer) => Some(writer),
+            Err(e) => {
+                println!("Failed to create capture file for conv {}: {}", conv, e);
+                None
+            },
+        }
+    }
+
+    #[cfg(feature = "raw_packet_dump")]
+    pub fn capture(&mut self, direction: Direction, user_id: u32, packet_id: &proto::PacketId, metadata: &[u8], data: &[u8]) {
+        let writer = match &mut self.capture {
+            Some(writer) => writer,
+            None => return,
+        };
+
+        let record = CaptureRecord {
+            timestamp: TimeManager::timestamp(),
+            direction: direction,
+            conv: self.conv,
+            user_id: user_id,
+            packet_id: packet_id.clone(),
+            metadata: metadata.to_vec(),
+            data: data.to_vec(),
+        };
+
+        match writer.write(&record) {
+            Ok(_) => {},
+            Err(e) => {
+                println!("Failed to write capture for conv {}, stopping it: {}", self.conv, e);
+                self.capture = None;
+            },
+        };
+    }
+
+    // Returns the number of errors produced by this session so far
+    pub fn register_error(&mut self) -> u32 {
+        self.error_count += 1;
+        return self.error_count;
     }
 }
@@ -0,0 +1,452 @@
+use std::fmt;
+use std::io::Cursor;
+use std::net::SocketAddr;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use tokio::sync::mpsc::UnboundedReceiver;
+
+use prost::Message;
+
+use rs_ipc::{IpcMessage, IpcEvent, ControlMessage};
+use rs_metrics::Metrics;
+use rs_nodeconf::NodeConfig;
+use rs_utils::TimeManager;
+
+use crate::utils::{HandshakePacket, DataPacket, DataDecError};
+use crate::utils::ProtocolVersions;
+use crate::server::{ClientConnection, ClientConnectionError};
+use crate::server::{AuthManager, UserRegistry};
+use crate::server::GatewayStats;
+
+#[cfg(feature = "raw_packet_dump")]
+use rs_capture::Direction;
+
+// Everything that can happen to a session, delivered to its task in order
+pub enum SessionEvent {
+    Datagram(SocketAddr, Vec<u8>),
+    Outgoing(std::time::Instant, IpcMessage), // Along with the time it was pulled from the out queue
+    Kick(u32),
+    Disconnected,
+}
+
+// Errors caused by a single client; those never bring the whole gateway down
+#[derive(Debug)]
+pub enum SessionError {
+    Kcp(ClientConnectionError),
+    MalformedPacket(DataDecError),
+    MalformedHead(prost::DecodeError),
+    MalformedBody(proto::PacketId, prost::DecodeError),
+}
+
+impl SessionError {
+    fn count(&self, stats: &GatewayStats) {
+        match self {
+            SessionError::Kcp(_) => GatewayStats::count(&stats.kcp_errors),
+            SessionError::MalformedPacket(_) => GatewayStats::count(&stats.malformed_packets),
+            SessionError::MalformedHead(_) => GatewayStats::count(&stats.malformed_heads),
+            SessionError::MalformedBody(_, _) => GatewayStats::count(&stats.malformed_packets),
+        };
+    }
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SessionError::Kcp(e) => write!(f, "{}", e),
+            SessionError::MalformedPacket(e) => write!(f, "Malformed data packet: {}", e),
+            SessionError::MalformedHead(e) => write!(f, "Malformed packet header: {}", e),
+            SessionError::MalformedBody(packet_id, e) => write!(f, "Malformed {:?} body: {}", packet_id, e),
+        }
+    }
+}
+
+/*
+  Task that owns a single client's connection.
+  KCP processing, decryption and packet parsing happen here, so sessions don't wait for each other.
+ */
+pub struct ClientSession {
+    conv: u32,
+    client: ClientConnection,
+    user_id: Option<u32>,
+    closing: bool,
+    outbox: Vec<IpcMessage>,
+    flush_deadline: Option<Instant>,
+    batch_window: Duration,
+    union_notifies: bool,
+    last_address_change: Option<Instant>,
+    address_change_interval: Duration,
+    events_rx: UnboundedReceiver<SessionEvent>,
+    packets_to_process_tx: mpsc::Sender<(std::time::Instant, IpcEvent)>,
+    auth_manager: Arc<Mutex<AuthManager>>,
+    users: Arc<Mutex<UserRegistry>>,
+    protocol_versions: Arc<ProtocolVersions>,
+    stats: Arc<GatewayStats>,
+    metrics: Arc<Metrics>,
+}
+
+impl ClientSession {
+    const KCP_UPDATE_INTERVAL_MS: u64 = 20;
+    const MAX_SESSION_ERRORS: u32 = 16;
+    // Bigger notifies aren't worth wrapping, they fill the datagram on their own
+    const UNION_MAX_BODY_SIZE: usize = 256;
+
+    pub fn new(client: ClientConnection, conv: u32, events_rx: UnboundedReceiver<SessionEvent>, packets_to_process_tx: mpsc::Sender<(std::time::Instant, IpcEvent)>,
+               auth_manager: Arc<Mutex<AuthManager>>, users: Arc<Mutex<UserRegistry>>, protocol_versions: Arc<ProtocolVersions>, stats: Arc<GatewayStats>, metrics: Arc<Metrics>,
+               node_config: &NodeConfig) -> ClientSession {
+        return ClientSession {
+            conv: conv,
+            client: client,
+            user_id: None,
+            closing: false,
+            outbox: vec![],
+            flush_deadline: None,
+            batch_window: Duration::from_millis(node_config.batch_window_ms),
+            union_notifies: node_config.union_small_notifies,
+            last_address_change: None,
+            address_change_interval: Duration::from_secs(node_config.address_change_interval_secs),
+            events_rx: events_rx,
+            packets_to_process_tx: packets_to_process_tx,
+            auth_manager: auth_manager,
+            users: users,
+            protocol_versions: protocol_versions,
+            stats: stats,
+            metrics: metrics,
+        };
+    }
+
+    pub async fn run(mut self) {
+        // KCP needs to be ticked even if nobody talks to us
+        let mut ticker = tokio::time::interval(Duration::from_millis(ClientSession::KCP_UPDATE_INTERVAL_MS));
+
+        while !self.closing {
+            let flush_deadline = self.flush_deadline.unwrap_or_else(Instant::now);
+
+            tokio::select! {
+                event = self.events_rx.recv() => match event {
+                    Some(SessionEvent::Datagram(source_address, bytes)) => self.process_datagram(source_address, &bytes),
+                    Some(SessionEvent::Outgoing(pulled_at, message)) => {
+                        self.metrics.observe("kalitka_internal_queue_seconds", &[("queue", "out")], pulled_at.elapsed());
+                        self.queue_game_packet(message);
+                    },
+                    Some(SessionEvent::Kick(reason)) => {
+                        self.client.send_disconnect(reason);
+                        self.closing = true;
+                    },
+                    Some(SessionEvent::Disconnected) => self.closing = true,
+                    None => self.closing = true, // Gateway is going away
+                },
+                _ = tokio::time::sleep_until(flush_deadline), if self.flush_deadline.is_some() => self.flush_outbox(),
+                _ = ticker.tick() => {
+                    self.client.update();
+
+                    if self.client.is_dead() {
+                        println!("Client with conv {} stopped responding, closing the session", self.conv);
+                        self.client.send_disconnect(HandshakePacket::DISCONNECT_REASON_TIMEOUT);
+                        self.closing = true;
+                    }
+                },
+            }
+        }
+    }
+
+    /*
+      Gateway has checked conv and token already, but those travel in plain text.
+      So datagram coming from a new address only moves the session there if it carries a packet that decrypts fine with the session's key.
+      Until then it doesn't touch the session at all: neither KCP state, nor idle timer, nor error count.
+      Before login the key is the master one every client has, so it's logged in sessions that are really protected.
+     */
+    fn process_datagram(&mut self, source_address: SocketAddr, bytes: &[u8]) {
+        if self.client.source() != Some(source_address) {
+            if !self.may_change_address() {
+                println!("Session with conv {} changed address too recently, ignoring datagram from {}", self.conv, source_address);
+                GatewayStats::count(&self.stats.rejected_address_changes);
+                return;
+            }
+
+            if !self.client.carries_valid_packet(bytes) {
+                println!("Datagram for conv {} from {} carries no packet that decrypts, keeping the old address", self.conv, source_address);
+                GatewayStats::count(&self.stats.rejected_address_changes);
+                return;
+            }
+
+            println!("Session with conv {} (uid {:?}) moved from {:?} to {}", self.conv, self.user_id, self.client.source(), source_address);
+            GatewayStats::count(&self.stats.address_changes);
+            self.client.update_source(source_address);
+            self.last_address_change = Some(Instant::now());
+        }
+
+        let packets = match self.client.process_udp_packet(bytes) {
+            Ok(packets) => packets,
+            Err(e) => {
+                self.session_error(SessionError::Kcp(e));
+                return;
+            },
+        };
+
+        for packet in packets.iter() {
+            match self.process_game_packet(packet) {
+                Ok(_) => {},
+                Err(e) => self.session_error(e),
+            };
+
+            if self.closing {
+                return;
+            }
+        }
+    }
+
+    fn may_change_address(&self) -> bool {
+        match self.last_address_change {
+            Some(changed) => return changed.elapsed() >= self.address_change_interval,
+            None => return true,
+        };
+    }
+
+    fn session_error(&mut self, error: SessionError) {
+        println!("Error in session with conv {} (uid {:?}): {}", self.conv, self.user_id, error);
+        error.count(&self.stats);
+
+        if self.client.register_error() >= ClientSession::MAX_SESSION_ERRORS {
+            println!("Too many errors in session with conv {}, dropping it", self.conv);
+            GatewayStats::count(&self.stats.dropped_sessions);
+            self.client.send_disconnect(HandshakePacket::DISCONNECT_REASON_BAD_DATA);
+            self.closing = true;
+        }
+    }
+
+    fn process_game_packet(&mut self, packet: &[u8]) -> Result<(), SessionError> {
+        let data = DataPacket::new_from_bytes(packet).map_err(SessionError::MalformedPacket)?;
+
+        let head = proto::PacketHead::decode(&mut Cursor::new(&data.metadata)).map_err(SessionError::MalformedHead)?;
+
+        let table = match self.client.packet_ids() {
+            Some(table) => table,
+            None => match self.protocol_versions.detect(data.packet_id) {
+                Some(table) => {
+                    println!("Client with conv {} speaks version {}", self.conv, table.version());
+                    self.client.set_packet_ids(table.clone());
+                    table
+                },
+                None => {
+                    println!("Packet ID {} from conv {} isn't GetPlayerTokenReq in any known version, skipping", data.packet_id, self.conv);
+                    return Ok(());
+                },
+            },
+        };
+
+        let packet_id = match table.to_internal(data.packet_id) {
+            Some(packet_id) => packet_id,
+            None => {
+                println!("Skipping unknown packet ID {}", data.packet_id);
+                return Ok(());
+            }
+        };
+
+        let user_id = match packet_id {
+            proto::PacketId::GetPlayerTokenReq => {
+                // Processor itself doesn't expect malformed requests
+                proto::GetPlayerTokenReq::decode(&mut Cursor::new(&data.data)).map_err(|e| SessionError::MalformedBody(packet_id.clone(), e))?;
+
+                #[cfg(feature = "raw_packet_dump")]
+                self.client.capture(Direction::ClientToServer, 0, &packet_id, &data.metadata, &data.data);
+
+                self.count_packet("in", &packet_id);
+
+                /*
+                  Login hits the database and does RSA, so it runs off the session tasks.
+                  Its outcome arrives later, as GetPlayerTokenRsp through the out queue.
+                 */
+                let conv = self.conv;
+                let auth_manager = self.auth_manager.clone();
+                tokio::task::spawn_blocking(move || auth_manager.lock().unwrap().process(conv, packet_id, data.metadata, data.data));
+                return Ok(());
+            },
+            _ => match self.user_id {
+                None => {
+                    println!("Unknown user with conv {}! Skipping", self.conv);
+                    return Ok(());
+                },
+                Some(user_id) => user_id,
+            },
+        };
+
+        if packet_id == proto::PacketId::UnionCmdNotify {
+            let union = proto::UnionCmdNotify::decode(&mut Cursor::new(&data.data)).map_err(|e| SessionError::MalformedBody(packet_id.clone(), e))?;
+            for u_cmd in union.cmd_list.into_iter() {
+                match table.to_internal(u_cmd.message_id as u16) {
+                    Some(packet_id) => self.send_packet_to_process(user_id, packet_id, &data.metadata, &u_cmd.body),
+                    None => println!("Skipping unknown packet ID {} in union", u_cmd.message_id),
+                };
+            }
+        } else {
+            self.send_packet_to_process(user_id, packet_id, &data.metadata, &data.data);
+        }
+
+        return Ok(());
+    }
+
+    fn send_packet_to_process(&mut self, user_id: u32, packet_id: proto::PacketId, metadata: &[u8], data: &[u8])
+    {
+        println!("Got packet {:?}", packet_id);
+
+        #[cfg(feature = "raw_packet_dump")]
+        self.client.capture(Direction::ClientToServer, user_id, &packet_id, metadata, data);
+
+        self.count_packet("in", &packet_id);
+
+        self.publish(IpcEvent::Packet(IpcMessage(packet_id, user_id, metadata.to_vec(), data.to_vec())));
+    }
+
+    fn publish(&mut self, event: IpcEvent) {
+        match self.packets_to_process_tx.send( (std::time::Instant::now(), event) ) {
+            Ok(_) => {},
+            Err(_) => println!("Processing queue is gone, dropping message from conv {}", self.conv),
+        };
+    }
+
+    fn queue_game_packet(&mut self, message: IpcMessage) {
+        self.outbox.push(message);
+
+        if self.batch_window.as_millis() == 0 {
+            self.flush_outbox();
+        } else if self.flush_deadline.is_none() {
+            self.flush_deadline = Some(Instant::now() + self.batch_window);
+        }
+    }
+
+    // Sends everything queued within the batching window with a single KCP flush
+    fn flush_outbox(&mut self) {
+        self.flush_deadline = None;
+
+        if self.outbox.is_empty() {
+            return;
+        }
+
+        let outbox = std::mem::take(&mut self.outbox);
+        let mut notifies: Vec<(u16, Vec<u8>, Vec<u8>)> = vec![];
+
+        for IpcMessage(packet_id, _, metadata, data) in outbox.into_iter() {
+            if packet_id == proto::PacketId::GetPlayerTokenRsp {
+                // Login outcome is known by now, so is the user
+                let (user_id, seed) = {
+                    let users = self.users.lock().unwrap();
+
+                    (users.resolve_conv(self.conv), users.get_seed(self.conv))
+                };
+
+                self.user_id = user_id;
+
+                match seed {
+                    Some(seed) => self.client.update_key(seed),
+                    None => {}, // Login was rejected, keep using the initial key
+                };
+
+                // Goes through the same queue as the packets, so game server learns about the player before seeing any of theirs
+                match user_id {
+                    Some(user_id) => self.publish(IpcEvent::Control(ControlMessage::SessionOpened(user_id))),
+                    None => {},
+                };
+            }
+
+            #[cfg(feature = "raw_packet_dump")]
+            self.client.capture(Direction::ServerToClient, self.user_id.unwrap_or(0), &packet_id, &metadata, &data);
+
+            self.count_packet("out", &packet_id);
+
+            let wire_id = match self.to_wire(&packet_id) {
+                Some(wire_id) => wire_id,
+                None => {
+                    println!("Packet {:?} doesn't exist in client's version, dropping", packet_id);
+                    continue;
+                },
+            };
+
+            if self.union_notifies && self.is_small_notify(&packet_id, &data) {
+                notifies.push((wire_id, metadata, data));
+                continue;
+            }
+
+            // Keep the order packets were sent in
+            self.queue_union(&mut notifies);
+            self.queue_data_packet(wire_id, metadata, data);
+        }
+
+        self.queue_union(&mut notifies);
+
+        match self.client.flush() {
+            Ok(_) => GatewayStats::count(&self.stats.kcp_flushes),
+            Err(e) => {
+                println!("Failed to flush packets to conv {}: {}", self.conv, e);
+                GatewayStats::count(&self.stats.kcp_errors);
+            },
+        };
+    }
+
+    fn count_packet(&self, direction: &str, packet_id: &proto::PacketId) {
+        self.metrics.inc("kalitka_packets_total", &[("direction", direction), ("packet", self.protocol_versions.packet_name(packet_id))]);
+    }
+
+    fn is_small_notify(&self, packet_id: &proto::PacketId, data: &[u8]) -> bool {
+        return data.len() <= ClientSession::UNION_MAX_BODY_SIZE &&
+               *packet_id != proto::PacketId::UnionCmdNotify &&
+               self.protocol_versions.is_notify(packet_id);
+    }
+
+    fn queue_union(&mut self, notifies: &mut Vec<(u16, Vec<u8>, Vec<u8>)>) {
+        let union_id = self.to_wire(&proto::PacketId::UnionCmdNotify);
+
+        // Not worth it for a single packet
+        if notifies.len() < 2 || union_id.is_none() {
+            for (wire_id, metadata, data) in notifies.drain(..) {
+                self.queue_data_packet(wire_id, metadata, data);
+            }
+            return;
+        }
+
+        GatewayStats::count(&self.stats.unions_sent);
+        GatewayStats::add(&self.stats.packets_in_unions, notifies.len() as u64);
+
+        let union = proto::UnionCmdNotify {
+            cmd_list: notifies.drain(..).map(|(wire_id, _, data)| proto::UnionCmd {
+                message_id: wire_id as u32,
+                body: data,
+            }).collect(),
+        };
+
+        let head = proto::PacketHead {
+            sent_ms: TimeManager::timestamp(),
+            ..proto::PacketHead::default()
+        };
+
+        let mut metadata: Vec<u8> = vec!();
+        head.encode(&mut metadata).unwrap();
+
+        let mut data: Vec<u8> = vec!();
+        union.encode(&mut data).unwrap();
+
+        self.queue_data_packet(union_id.unwrap(), metadata, data);
+    }
+
+    fn queue_data_packet(&mut self, wire_id: u16, metadata: Vec<u8>, data: Vec<u8>) {
+        let bytes = DataPacket::new(wire_id, metadata, data).to_bytes();
+
+        match self.client.queue_udp_packet(&bytes) {
+            Ok(_) => GatewayStats::count(&self.stats.packets_sent),
+            Err(e) => {
+                println!("Failed to send packet {} to conv {}: {}", wire_id, self.conv, e);
+                GatewayStats::count(&self.stats.kcp_errors);
+            },
+        };
+    }
+
+    // Game server speaks canonical IDs, client expects the ones of its own version
+    fn to_wire(&self, packet_id: &proto::PacketId) -> Option<u16> {
+        match self.client.packet_ids() {
+            Some(packet_ids) => return packet_ids.to_wire(packet_id),
+            None => return Some(packet_id.clone() as u16),
+        };
+    }
+}
@@ -0,0 +1,132 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use rs_metrics::Metrics;
+
+/*
+  Counters of everything the gateway had to throw away.
+  Shared between the receiving loop and the packet relaying thread.
+ */
+#[derive(Default)]
+pub struct GatewayStats {
+    pub malformed_handshakes: AtomicU64,
+    pub bad_tokens: AtomicU64,
+    pub unknown_convs: AtomicU64,
+    pub kcp_errors: AtomicU64,
+    pub malformed_packets: AtomicU64,
+    pub malformed_heads: AtomicU64,
+    pub unknown_uids: AtomicU64,
+    pub dropped_sessions: AtomicU64,
+    pub banned_datagrams: AtomicU64,
+    pub throttled_datagrams: AtomicU64,
+    pub throttled_handshakes: AtomicU64,
+    pub session_limit_hits: AtomicU64,
+    pub bans: AtomicU64,
+    pub address_changes: AtomicU64,
+    pub rejected_address_changes: AtomicU64,
+    pub packets_sent: AtomicU64,
+    pub packets_in_unions: AtomicU64,
+    pub unions_sent: AtomicU64,
+    pub kcp_flushes: AtomicU64,
+    pub datagrams_sent: AtomicU64,
+    pub datagrams_dropped: AtomicU64,
+    pub bytes_sent: AtomicU64,
+    pub kcp_retransmits: AtomicU64,
+}
+
+impl GatewayStats {
+    pub fn new() -> GatewayStats {
+        return GatewayStats::default();
+    }
+
+    pub fn count(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add(counter: &AtomicU64, value: u64) {
+        counter.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn get(counter: &AtomicU64) -> u64 {
+        return counter.load(Ordering::Relaxed);
+    }
+
+    // Copies the counters into the metrics registry, called every time metrics are scraped
+    pub fn export(&self, metrics: &Metrics) {
+        let events = [
+            ("malformed_handshake", &self.malformed_handshakes),
+            ("bad_token", &self.bad_tokens),
+            ("unknown_conv", &self.unknown_convs),
+            ("unknown_uid", &self.unknown_uids),
+            ("dropped_session", &self.dropped_sessions),
+            ("banned_datagram", &self.banned_datagrams),
+            ("throttled_datagram", &self.throttled_datagrams),
+            ("throttled_handshake", &self.throttled_handshakes),
+            ("session_limit_hit", &self.session_limit_hits),
+            ("ban", &self.bans),
+            ("address_change", &self.address_changes),
+            ("rejected_address_change", &self.rejected_address_changes),
+        ];
+
+        for (event, counter) in events.iter() {
+            metrics.set_counter("kalitka_gateway_events_total", &[("event", event)], GatewayStats::get(counter));
+        }
+
+        let failures = [
+            ("kcp", &self.kcp_errors),
+            ("packet", &self.malformed_packets),
+            ("head", &self.malformed_heads),
+        ];
+
+        for (kind, counter) in failures.iter() {
+            metrics.set_counter("kalitka_decode_failures_total", &[("kind", kind)], GatewayStats::get(counter));
+        }
+
+        metrics.set_counter("kalitka_kcp_retransmits_total", &[], GatewayStats::get(&self.kcp_retransmits));
+        metrics.set_counter("kalitka_kcp_flushes_total", &[], GatewayStats::get(&self.kcp_flushes));
+        metrics.set_counter("kalitka_unions_sent_total", &[], GatewayStats::get(&self.unions_sent));
+        metrics.set_counter("kalitka_datagrams_sent_total", &[], GatewayStats::get(&self.datagrams_sent));
+        metrics.set_counter("kalitka_datagrams_dropped_total", &[], GatewayStats::get(&self.datagrams_dropped));
+        metrics.set_counter("kalitka_bytes_sent_total", &[], GatewayStats::get(&self.bytes_sent));
+    }
+}
+
+impl fmt::Display for GatewayStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "malformed handshakes: {}, bad tokens: {}, unknown convs: {}, KCP errors: {}, malformed packets: {}, malformed heads: {}, unknown uids: {}, dropped sessions: {}, datagrams from banned sources: {}, throttled datagrams: {}, throttled handshakes: {}, session limit hits: {}, bans: {}, address changes: {}, rejected address changes: {}",
+               GatewayStats::get(&self.malformed_handshakes),
+               GatewayStats::get(&self.bad_tokens),
+               GatewayStats::get(&self.unknown_convs),
+               GatewayStats::get(&self.kcp_errors),
+               GatewayStats::get(&self.malformed_packets),
+               GatewayStats::get(&self.malformed_heads),
+               GatewayStats::get(&self.unknown_uids),
+               GatewayStats::get(&self.dropped_sessions),
+               GatewayStats::get(&self.banned_datagrams),
+               GatewayStats::get(&self.throttled_datagrams),
+               GatewayStats::get(&self.throttled_handshakes),
+               GatewayStats::get(&self.session_limit_hits),
+               GatewayStats::get(&self.bans),
+               GatewayStats::get(&self.address_changes),
+               GatewayStats::get(&self.rejected_address_changes),
+        )
+    }
+}
+
+// Outbound traffic, to see how well batching does
+pub struct TrafficStats<'a>(pub &'a GatewayStats);
+
+impl fmt::Display for TrafficStats<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "packets: {} ({} of them in {} unions), KCP flushes: {}, datagrams: {} ({} dropped), bytes: {}, retransmits: {}",
+               GatewayStats::get(&self.0.packets_sent),
+               GatewayStats::get(&self.0.packets_in_unions),
+               GatewayStats::get(&self.0.unions_sent),
+               GatewayStats::get(&self.0.kcp_flushes),
+               GatewayStats::get(&self.0.datagrams_sent),
+               GatewayStats::get(&self.0.datagrams_dropped),
+               GatewayStats::get(&self.0.bytes_sent),
+               GatewayStats::get(&self.0.kcp_retransmits),
+        )
+    }
+}
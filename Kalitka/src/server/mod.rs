@@ -2,8 +2,18 @@ mod network_server;
 mod auth_manager;
 //mod login_manager;
 mod client_connection;
+mod client_session;
+mod gateway_stats;
+mod rate_limiter;
+mod node_router;
+mod user_registry;
 
 pub use self::network_server::NetworkServer;
-pub use self::auth_manager::AuthManager;
+pub use self::auth_manager::{AuthManager, AuthManagerError};
 //pub use self::login_manager::LoginManager;
-pub use self::client_connection::ClientConnection;
+pub use self::client_connection::{ClientConnection, ClientConnectionError};
+pub use self::client_session::{ClientSession, SessionEvent, SessionError};
+pub use self::gateway_stats::{GatewayStats, TrafficStats};
+pub use self::rate_limiter::{RateLimiter, Verdict};
+pub use self::node_router::NodeRouter;
+pub use self::user_registry::UserRegistry;
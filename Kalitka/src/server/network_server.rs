@@ -1,43 +1,64 @@
 use std::fmt;
 use std::net::UdpSocket;
-use std::net::SocketAddr;
+use std::net::{SocketAddr, IpAddr};
 use std::collections::HashMap;
-use std::io::Cursor;
+use std::convert::TryInto;
 use std::thread;
+use std::time::{Duration, Instant};
 use std::sync::mpsc;
 use std::sync::{Arc, RwLock, Mutex};
 
-use num_derive::FromPrimitive;
-use num_traits::FromPrimitive;
-use num_derive::ToPrimitive;
-use num_traits::ToPrimitive;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender, UnboundedReceiver};
 
-use crate::utils::HandshakePacket;
-use crate::utils::DataPacket;
+use crate::utils::{HandshakePacket, HandshakeDecError};
+use crate::utils::ConvAllocator;
+use crate::utils::ProtocolVersions;
 use crate::server::ClientConnection;
-use crate::server::AuthManager;
+use crate::server::{ClientSession, SessionEvent};
+use crate::server::{AuthManager, UserRegistry};
+use crate::server::{GatewayStats, TrafficStats};
+use crate::server::{RateLimiter, Verdict};
+use crate::server::NodeRouter;
+use crate::dbmanager::DatabaseManager;
 
-use rs_ipc::{IpcMessage, PullSocket, PushSocket};
+use rs_ipc::{IpcEvent, ControlMessage, PullSocket, PubSocket};
+use rs_metrics::{Metrics, MetricsServer};
 
-use proto::PacketHead;
-use proto::GetPlayerTokenRsp;
-
-use prost::Message;
-
-use rs_ipc::{SubSocket, PubSocket};
-
-use packet_processor::{PacketProcessor, EasilyUnpackable};
 use rs_nodeconf::NodeConfig;
+use rs_utils::{ShutdownSignal, ReloadSignal};
 
 extern crate kcp;
 
 // -------------
 
+type Sessions = Arc<RwLock<HashMap<u32, UnboundedSender<SessionEvent>>>>;
+
+/*
+  Receiving loop only does the cheap part: handshakes, token checks and rate limiting.
+  Everything else is handed over to session tasks, which run on all the cores available.
+ */
 pub struct NetworkServer {
     socket: UdpSocket,
-    clients: Arc<Mutex<HashMap<u32,ClientConnection>>>,
+    // Set up once the async runtime is running, all the traffic goes through it
+    async_socket: Option<Arc<tokio::net::UdpSocket>>,
+    sessions: Sessions,
+    conv_allocator: ConvAllocator,
     node_config: NodeConfig,
-    packets_to_process_tx: PubSocket,
+    packets_to_process_tx: mpsc::Sender<(Instant, IpcEvent)>,
+    packets_to_process_rx: Option<mpsc::Receiver<(Instant, IpcEvent)>>,
+    publisher: Option<thread::JoinHandle<()>>,
+    closed_sessions_tx: UnboundedSender<u32>,
+    closed_sessions_rx: Option<UnboundedReceiver<u32>>,
+    reload_requests_tx: UnboundedSender<()>,
+    reload_requests_rx: Option<UnboundedReceiver<()>>,
+    auth_manager: Option<Arc<Mutex<AuthManager>>>,
+    users: Arc<Mutex<UserRegistry>>,
+    stats: Arc<GatewayStats>,
+    metrics: Arc<Metrics>,
+    protocol_versions: Arc<ProtocolVersions>,
+    rate_limiter: RateLimiter,
+    router: Arc<Mutex<NodeRouter>>,
+    master_key: [u8; 0x1000],
 }
 
 #[derive(Debug, Clone)]
@@ -58,19 +79,44 @@ impl fmt::Display for NetworkServerError {
 }
 
 impl NetworkServer {
-    pub fn new(host: &str, port: i16) -> Result<NetworkServer, NetworkServerError> {
-        let node_config = NodeConfig::new();
+    const KCP_CONV_TOKEN_SIZE: usize = 8;
+    const HOUSEKEEPING_INTERVAL: Duration = Duration::from_millis(100);
+    const STATS_INTERVAL: Duration = Duration::from_secs(60);
+    const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+    pub fn new(node_config: NodeConfig) -> Result<NetworkServer, NetworkServerError> {
+        let rate_limiter = RateLimiter::new(&node_config);
+        let router = NodeRouter::new(&node_config);
 
-        let mut packets_to_process_tx = node_config.bind_in_queue().unwrap();
+        let (packets_to_process_tx, packets_to_process_rx) = mpsc::channel();
+        let (closed_sessions_tx, closed_sessions_rx) = unbounded_channel();
+        let (reload_requests_tx, reload_requests_rx) = unbounded_channel();
 
         let gs = NetworkServer {
-            socket: match UdpSocket::bind(format!("{}:{}", host, port).to_string()) {
+            socket: match UdpSocket::bind(format!("{}:{}", node_config.gateway_bind_addr, node_config.gateway_port)) {
                 Ok(socket) => socket,
                 Err(e) => return Err(NetworkServerError::new(format!("Failed to bind socket: {}", e).as_str())),
             },
-            clients: Arc::new(Mutex::new(HashMap::new())),
-            node_config: node_config,
+            async_socket: None,
+            sessions: Arc::new(RwLock::new(HashMap::new())),
+            conv_allocator: ConvAllocator::new(),
             packets_to_process_tx: packets_to_process_tx,
+            packets_to_process_rx: Some(packets_to_process_rx),
+            publisher: None,
+            closed_sessions_tx: closed_sessions_tx,
+            closed_sessions_rx: Some(closed_sessions_rx),
+            reload_requests_tx: reload_requests_tx,
+            reload_requests_rx: Some(reload_requests_rx),
+            auth_manager: None,
+            users: Arc::new(Mutex::new(UserRegistry::new())),
+            stats: Arc::new(GatewayStats::new()),
+            metrics: Arc::new(Metrics::new()),
+            protocol_versions: Arc::new(ProtocolVersions::load(&node_config.packet_ids_dir)),
+            rate_limiter: rate_limiter,
+            router: Arc::new(Mutex::new(router)),
+            // Every session starts with the same key, no need to hit the disk each time
+            master_key: ClientConnection::read_key(&node_config.keys_dir, "master").try_into().expect("Incorrect master key"),
+            node_config: node_config,
         };
 
         print!("Connection established\n");
@@ -81,56 +127,361 @@ impl NetworkServer {
     pub fn run(&mut self) -> Result<i16, NetworkServerError> {
         print!("Starting server\n");
 
-        let mut packets_to_send_rx = self.node_config.bind_out_queue().unwrap();
+        let runtime = match tokio::runtime::Builder::new_multi_thread().enable_all().build() {
+            Ok(runtime) => runtime,
+            Err(e) => return Err(NetworkServerError::new(format!("Failed to start async runtime: {}", e).as_str())),
+        };
+
+        return runtime.block_on(self.serve());
+    }
+
+    async fn serve(&mut self) -> Result<i16, NetworkServerError> {
+        let db = DatabaseManager::new(&self.node_config.database_url);
+        let auth_manager = AuthManager::new(&self.node_config, db, self.users.clone())
+            .map_err(|e| NetworkServerError::new(format!("Failed to set up logins: {}", e).as_str()))?;
+        let auth_manager = Arc::new(Mutex::new(auth_manager));
+        self.auth_manager = Some(auth_manager.clone());
+
+        self.start_publisher_thread()?;
+        self.start_relay_thread()?;
+        self.start_metrics_server()?;
+
+        /*
+          Clones of a socket share its nonblocking flag, so there's no keeping a blocking handle around once tokio has it.
+          Everyone sends through the tokio socket instead: sessions with try_send_to, as KCP output can't wait.
+         */
+        let std_socket = self.socket.try_clone()
+            .map_err(|e| NetworkServerError::new(format!("Failed to clone socket: {}", e).as_str()))?;
+        std_socket.set_nonblocking(true)
+            .map_err(|e| NetworkServerError::new(format!("Failed to set up socket: {}", e).as_str()))?;
+        let socket = Arc::new(tokio::net::UdpSocket::from_std(std_socket)
+            .map_err(|e| NetworkServerError::new(format!("Failed to set up socket: {}", e).as_str()))?);
+        self.async_socket = Some(socket.clone());
+
+        let mut closed_sessions_rx = self.closed_sessions_rx.take().unwrap();
+        let mut reload_requests_rx = self.reload_requests_rx.take().unwrap();
+
+        let shutdown = ShutdownSignal::install();
+        let reload = ReloadSignal::install();
+
+        let mut buffer = [0u8; 65536];
+        let mut last_stats = Instant::now();
+
+        while !shutdown.is_requested() {
+            match tokio::time::timeout(NetworkServer::HOUSEKEEPING_INTERVAL, socket.recv_from(&mut buffer)).await {
+                Ok(Ok( (bytes_number, source_address) )) => self.process_udp_packet(&socket, source_address, &buffer[..bytes_number]),
+                Ok(Err(e)) => println!("Failed to receive data: {}", e),
+                Err(_) => {}, // Nothing came in, time to look after the sessions
+            };
+
+            while let Ok(conv) = closed_sessions_rx.try_recv() {
+                self.close_session(conv);
+            }
+
+            // Reload asked for by the game server stays local, otherwise nodes would keep asking each other forever
+            while let Ok(()) = reload_requests_rx.try_recv() {
+                self.reload_config();
+            }
+
+            if reload.take() && self.reload_config() {
+                self.publish(ControlMessage::ReloadConfig);
+            }
+
+            self.rate_limiter.cleanup();
+            self.expire_nodes();
+
+            if last_stats.elapsed() >= NetworkServer::STATS_INTERVAL {
+                println!("Gateway error stats: {}", self.stats);
+                println!("Gateway traffic stats: {}", TrafficStats(&self.stats));
+                last_stats = Instant::now();
+            }
+        }
+
+        self.shutdown(&mut closed_sessions_rx).await;
+
+        return Ok(0);
+    }
+
+    // Kicks everyone out and waits for their sessions to end, so that game server gets to save the players
+    async fn shutdown(&mut self, closed_sessions_rx: &mut UnboundedReceiver<u32>) {
+        let convs: Vec<u32> = self.sessions.read().unwrap().keys().cloned().collect();
+
+        println!("Shutting down, disconnecting {} clients", convs.len());
+
+        for conv in convs {
+            self.notify_session(conv, SessionEvent::Kick(HandshakePacket::DISCONNECT_REASON_SERVER_KICK));
+        }
+
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(self.node_config.shutdown_timeout_secs);
+
+        while !self.sessions.read().unwrap().is_empty() {
+            match tokio::time::timeout_at(deadline, closed_sessions_rx.recv()).await {
+                Ok(Some(conv)) => self.close_session(conv),
+                Ok(None) => break,
+                Err(_) => {
+                    // Game server still has to hear they're gone, otherwise it keeps the players online
+                    let convs: Vec<u32> = self.sessions.read().unwrap().keys().cloned().collect();
+                    println!("{} sessions failed to close in time, closing them anyway", convs.len());
+
+                    for conv in convs {
+                        self.close_session(conv);
+                    }
+                },
+            };
+        }
+
+        self.publish(ControlMessage::ShuttingDown);
 
-        let clients = self.clients.clone();
-        let mut auth_manager = Arc::new(Mutex::new(AuthManager::new(&self.node_config)));
-        let am = auth_manager.clone();
+        // Publisher exits once the last sender is gone, but only after sending out everything queued
+        let (packets_to_process_tx, _) = mpsc::channel();
+        drop(std::mem::replace(&mut self.packets_to_process_tx, packets_to_process_tx));
 
-        let packet_relaying_thread = thread::spawn(move || {
+        /*
+          Sessions that didn't close in time still hold their senders, so the publisher may never see the last one go.
+          It gets another timeout to send out what's queued, then the gateway exits regardless.
+         */
+        let deadline = Instant::now() + Duration::from_secs(self.node_config.shutdown_timeout_secs);
+
+        match self.publisher.take() {
+            Some(publisher) => {
+                while !publisher.is_finished() && Instant::now() < deadline {
+                    tokio::time::sleep(NetworkServer::SHUTDOWN_POLL_INTERVAL).await;
+                }
+
+                if publisher.is_finished() {
+                    let _ = publisher.join();
+                } else {
+                    println!("Publisher failed to finish in time, some messages may be lost");
+                }
+            },
+            None => {},
+        };
+
+        println!("Gateway stopped");
+    }
+
+    // PubSocket is blocking, so it gets a thread of its own instead of stalling session tasks
+    fn start_publisher_thread(&mut self) -> Result<(), NetworkServerError> {
+        let mut packets_to_process_tx: PubSocket = self.node_config.bind_in_queue()
+            .map_err(|e| NetworkServerError::new(format!("Failed to bind in queue: {}", e).as_str()))?;
+        let packets_to_process_rx = self.packets_to_process_rx.take().unwrap();
+        let metrics = self.metrics.clone();
+        let router = self.router.clone();
+        let sessions = self.sessions.clone();
+        let users = self.users.clone();
+
+        self.publisher = Some(thread::spawn(move || {
+            for (queued_at, event) in packets_to_process_rx.iter() {
+                metrics.observe("kalitka_internal_queue_seconds", &[("queue", "in")], queued_at.elapsed());
+
+                // Everything about a player goes to their node only, the rest is for everyone
+                let result = match event {
+                    IpcEvent::Packet(message) => {
+                        let node = router.lock().unwrap().route(message.1);
+
+                        match node {
+                            Some(node) => packets_to_process_tx.send_to(node, message),
+                            None => {
+                                println!("No game node is up for {:?} from user {}, disconnecting", message.0, message.1);
+                                NetworkServer::kick_users(&[message.1], &sessions, &users);
+                                Ok(())
+                            },
+                        }
+                    },
+                    IpcEvent::Control(ControlMessage::SessionOpened(user_id)) => {
+                        let node = router.lock().unwrap().assign(user_id);
+
+                        match node {
+                            Some(node) => {
+                                println!("User {} is served by game node {}", user_id, node);
+                                packets_to_process_tx.send_control_to(node, &ControlMessage::SessionOpened(user_id))
+                            },
+                            None => {
+                                // Client gets to retry once some node is up
+                                println!("No game node is up for user {}, disconnecting", user_id);
+                                NetworkServer::kick_users(&[user_id], &sessions, &users);
+                                Ok(())
+                            },
+                        }
+                    },
+                    IpcEvent::Control(ControlMessage::SessionClosed(user_id)) => match router.lock().unwrap().release(user_id) {
+                        Some(node) => packets_to_process_tx.send_control_to(node, &ControlMessage::SessionClosed(user_id)),
+                        None => Ok(()), // Never made it to a game node
+                    },
+                    IpcEvent::Control(message) => packets_to_process_tx.send_control(&message),
+                };
+
+                match result {
+                    Ok(_) => {},
+                    Err(e) => println!("Failed to publish message: {}", e),
+                };
+            }
+        }));
+
+        return Ok(());
+    }
+
+    // Routes game server's replies to the sessions they belong to
+    fn start_relay_thread(&mut self) -> Result<(), NetworkServerError> {
+        let mut packets_to_send_rx: PullSocket = self.node_config.bind_out_queue()
+            .map_err(|e| NetworkServerError::new(format!("Failed to bind out queue: {}", e).as_str()))?;
+        let sessions = self.sessions.clone();
+        let users = self.users.clone();
+        let stats = self.stats.clone();
+        let reload_requests_tx = self.reload_requests_tx.clone();
+        let router = self.router.clone();
+        let metrics = self.metrics.clone();
+
+        thread::spawn(move || {
             loop {
-                let IpcMessage(packet_id, user_id, metadata, data) = packets_to_send_rx.recv().unwrap();
+                let message = match packets_to_send_rx.recv_event_timed() {
+                    Ok((IpcEvent::Packet(message), latency)) => {
+                        match latency {
+                            Some(latency) => metrics.observe("kalitka_ipc_queue_seconds", &[], latency),
+                            None => {},
+                        };
+
+                        message
+                    },
+                    Ok((IpcEvent::Control(message), _)) => {
+                        NetworkServer::process_control_message(message, &sessions, &users, &router, &reload_requests_tx);
+                        continue;
+                    },
+                    Err(e) => {
+                        println!("Failed to receive packet to send: {}", e);
+                        continue;
+                    },
+                };
+
+                let pulled_at = Instant::now();
+                let packet_id = message.0.clone();
+                let user_id = message.1;
 
                 let conv = match packet_id {
                     proto::PacketId::GetPlayerTokenRsp => user_id, // Mapping is not performed on those
-                    _ => am.lock().unwrap().resolve_uid(user_id).unwrap_or_else(|| panic!("Unknown user ID {}!", user_id)),
+                    _ => match users.lock().unwrap().resolve_uid(user_id) {
+                        Some(conv) => conv,
+                        None => {
+                            println!("Unknown user ID {} for outgoing {:?}, dropping", user_id, packet_id);
+                            GatewayStats::count(&stats.unknown_uids);
+                            continue;
+                        },
+                    },
                 };
 
-                let data_packet = DataPacket::new(packet_id.clone() as u16, metadata, data.clone());
-
-                match clients.lock().unwrap().get_mut(&conv) {
-                    Some(client) => {
-                        let bytes = data_packet.to_bytes();
-                        client.send_udp_packet(&bytes);
+                let session = sessions.read().unwrap().get(&conv).cloned();
 
-                        if packet_id == proto::PacketId::GetPlayerTokenRsp {
-                            // TODO: a bit hacky!
-                            let token_rsp: GetPlayerTokenRsp = EasilyUnpackable::from(&data);
-                            client.update_key(token_rsp.secret_key_seed);
-                        }
-                    },
-                    None => panic!("Unknown client conv: {}", conv),
+                let delivered = match session {
+                    Some(session) => session.send(SessionEvent::Outgoing(pulled_at, message)).is_ok(),
+                    None => false,
                 };
+
+                if !delivered {
+                    println!("Unknown client conv {} for outgoing {:?}, dropping", conv, packet_id);
+                    GatewayStats::count(&stats.unknown_convs);
+                }
             }
         });
 
-        let mut buffer = [0u8; 65536];
+        return Ok(());
+    }
 
-        loop {
-            match self.socket.recv_from(&mut buffer) {
-                Ok( (bytes_number, source_address) ) => self.process_udp_packet(source_address, &buffer[..bytes_number], &mut auth_manager),
-                Err(e) => panic!("Failed to receive data: {}", e),
+    fn process_control_message(message: ControlMessage, sessions: &Sessions, users: &Arc<Mutex<UserRegistry>>, router: &Arc<Mutex<NodeRouter>>,
+                               reload_requests_tx: &UnboundedSender<()>) {
+        let kick = |conv: u32| {
+            match sessions.read().unwrap().get(&conv) {
+                Some(session) => {
+                    let _ = session.send(SessionEvent::Kick(HandshakePacket::DISCONNECT_REASON_SERVER_KICK));
+                },
+                None => {}, // Already gone
+            };
+        };
+
+        match message {
+            ControlMessage::KickUid(user_id) => match users.lock().unwrap().resolve_uid(user_id) {
+                Some(conv) => {
+                    println!("Game server asked to kick user {}", user_id);
+                    kick(conv);
+                },
+                None => println!("Game server asked to kick user {}, who isn't connected", user_id),
+            },
+            ControlMessage::NodeStarted(node) => {
+                let user_ids = router.lock().unwrap().node_started(node);
+
+                if user_ids.is_empty() {
+                    println!("Game node {} is up", node);
+                } else {
+                    // Node came back without stopping first, so it crashed and its players' state is gone with it
+                    println!("Game node {} restarted, disconnecting its {} clients", node, user_ids.len());
+                }
+
+                NetworkServer::kick_users(&user_ids, sessions, users);
+            },
+            ControlMessage::NodeAlive(node) => {
+                // Nodes that were running before the gateway started are first heard from this way
+                if router.lock().unwrap().node_alive(node) {
+                    println!("Game node {} is up", node);
+                }
+            },
+            ControlMessage::NodeStopping(node) => {
+                // Node saves its players on the way down, they get to another one once they reconnect
+                let user_ids = router.lock().unwrap().node_stopping(node);
+
+                println!("Game node {} is going down, disconnecting its {} clients", node, user_ids.len());
+
+                NetworkServer::kick_users(&user_ids, sessions, users);
+            },
+            ControlMessage::ReloadConfig => {
+                let _ = reload_requests_tx.send(());
+            },
+            ControlMessage::SessionOpened(_) | ControlMessage::SessionClosed(_) | ControlMessage::ShuttingDown => {
+                println!("Unexpected {:?} from the game server, ignoring", message);
+            },
+        };
+    }
+
+    fn start_metrics_server(&mut self) -> Result<(), NetworkServerError> {
+        self.metrics.describe("kalitka_active_sessions", "Sessions currently open on the gateway");
+        self.metrics.describe("kalitka_packets_total", "Game packets passed through the gateway");
+        self.metrics.describe("kalitka_decode_failures_total", "Datagrams and packets that failed to decode");
+        self.metrics.describe("kalitka_gateway_events_total", "Datagrams, sessions and sources the gateway had to reject");
+        self.metrics.describe("kalitka_kcp_retransmits_total", "KCP data segments sent more than once");
+        self.metrics.describe("kalitka_kcp_flushes_total", "KCP flushes of batched outgoing packets");
+        self.metrics.describe("kalitka_unions_sent_total", "UnionCmdNotify packets built from small notifies");
+        self.metrics.describe("kalitka_datagrams_sent_total", "UDP datagrams sent to clients");
+        self.metrics.describe("kalitka_datagrams_dropped_total", "UDP datagrams dropped because the socket's send buffer was full");
+        self.metrics.describe("kalitka_bytes_sent_total", "UDP payload bytes sent to clients");
+        self.metrics.describe("kalitka_internal_queue_seconds", "Time packets wait in the gateway's own queues: before being published to game nodes (in) or before their session picks them up (out); time spent in transit between nodes is not included");
+        self.metrics.describe("kalitka_ipc_queue_seconds", "Time replies of game nodes spend in the IPC queue, from being sent until the gateway receives them; relies on the clocks of the nodes being in sync");
+        self.metrics.describe("kalitka_node_players", "Logged in players per game node");
+
+        let sessions = self.sessions.clone();
+        let stats = self.stats.clone();
+        let router = self.router.clone();
+
+        self.metrics.add_collector(move |metrics| {
+            metrics.set_gauge("kalitka_active_sessions", &[], sessions.read().unwrap().len() as f64);
+
+            for (node, players) in router.lock().unwrap().load() {
+                let node = node.to_string();
+                metrics.set_gauge("kalitka_node_players", &[("node", node.as_str())], players as f64);
             }
-        }
 
-        //packet_relaying_thread.join().unwrap();
+            stats.export(metrics);
+        });
 
-        return Ok(0);
+        return MetricsServer::start(self.metrics.clone(), &self.node_config.metrics_addr, self.node_config.gateway_metrics_port)
+            .map_err(|e| NetworkServerError::new(format!("Failed to start metrics server: {}", e).as_str()));
     }
 
-    fn process_udp_packet(&mut self, source_address: SocketAddr, packet_bytes: &[u8], auth_manager: &mut Arc<Mutex<AuthManager>>) {
+    fn process_udp_packet(&mut self, socket: &tokio::net::UdpSocket, source_address: SocketAddr, packet_bytes: &[u8]) {
         //print!("Received packet! Len = {}\n", packet_bytes.len());
 
+        if self.rate_limiter.is_banned(&source_address.ip()) {
+            GatewayStats::count(&self.stats.banned_datagrams);
+            return;
+        }
+
         let hs_packet = HandshakePacket::new(packet_bytes);
 
         match hs_packet {
@@ -138,99 +489,210 @@ impl NetworkServer {
                 //print!("Received handshake packet: {:#?}\n", hs_packet);
                 if hs_packet.is_connect() {
                     //print!("Sending reply to CONNECT\n");
-                    // TODO: assign conv and token!
-                    let conv = 0x96969696u32;
-                    let token = 0x42424242u32;
+                    match self.rate_limiter.check_handshake(source_address.ip()) {
+                        Verdict::Allow => {},
+                        Verdict::Throttle => {
+                            GatewayStats::count(&self.stats.throttled_handshakes);
+                            return;
+                        },
+                        Verdict::Ban => {
+                            println!("Too many handshakes from {}", source_address);
+                            self.ban(source_address.ip());
+                            return;
+                        },
+                    };
+
+                    if !self.rate_limiter.check_session_count(self.sessions.read().unwrap().len()) {
+                        println!("Session limit reached, rejecting CONNECT from {}", source_address);
+                        GatewayStats::count(&self.stats.session_limit_hits);
+                        return;
+                    }
+
+                    let (conv, token) = self.conv_allocator.allocate();
+
+                    self.open_session(conv, token, source_address);
 
                     let reply = HandshakePacket::new_conv(conv, token);
 
-                    let mut client = ClientConnection::new(self.socket.try_clone().unwrap(), conv, token);
-                    client.update_source(source_address);
+                    match socket.try_send_to(&reply.to_bytes(), source_address) {
+                        Ok(_) => {},
+                        Err(e) => println!("Failed to reply to CONNECT from {}: {}", source_address, e),
+                    };
+                } else if hs_packet.is_disconnect() {
+                    let conv = hs_packet.conv();
+
+                    if !self.conv_allocator.verify(conv, hs_packet.token()) {
+                        println!("Token mismatch in DISCONNECT for conv {} from {}, skipping", conv, source_address);
+                        return;
+                    }
 
-                    self.clients.lock().unwrap().insert(conv, client);
+                    println!("Client with conv {} disconnected, reason {}", conv, hs_packet.reason());
 
-                    self.socket.send_to(&reply.to_bytes(), source_address).expect("Failed to send data!");
+                    self.notify_session(conv, SessionEvent::Disconnected);
                 }
             },
+            // Anything of a different size is a KCP packet
+            Err(HandshakeDecError::SizeMismatch(_)) => self.process_kcp_packet(source_address, packet_bytes),
             Err(e) => {
-                //print!("Error constructing handshake: {:#?}", e);
-                let conv = kcp::get_conv(packet_bytes);
+                println!("Malformed handshake from {}: {}", source_address, e);
+                GatewayStats::count(&self.stats.malformed_handshakes);
+            },
+        };
+    }
 
-                let packets = match self.clients.lock().unwrap().get_mut(&conv) {
-                    Some(client) => {
-                        client.update_source(source_address);
+    fn process_kcp_packet(&mut self, source_address: SocketAddr, packet_bytes: &[u8]) {
+        if packet_bytes.len() < NetworkServer::KCP_CONV_TOKEN_SIZE {
+            println!("Packet from {} is too small to be a KCP one, skipping", source_address);
+            GatewayStats::count(&self.stats.kcp_errors);
+            return;
+        }
 
-                        client.process_udp_packet(packet_bytes)
-                    }
-                    None => panic!("Unknown client conv: {}", conv),
-                };
+        let conv = kcp::get_conv(packet_bytes);
+        let token = NetworkServer::get_token(packet_bytes);
 
-                for packet in packets.iter() {
-                    self.process_game_packet(conv, packet, auth_manager);
-                }
+        if !self.conv_allocator.verify(conv, token) {
+            println!("Unknown conv {} or token mismatch from {}, skipping", conv, source_address);
+            GatewayStats::count(&self.stats.bad_tokens);
+            return;
+        }
+
+        match self.rate_limiter.check_datagram(conv) {
+            Verdict::Allow => {},
+            Verdict::Throttle => {
+                GatewayStats::count(&self.stats.throttled_datagrams);
+                return;
+            },
+            Verdict::Ban => {
+                println!("Session with conv {} is flooding us from {}, dropping it", conv, source_address);
+                self.ban(source_address.ip());
+                GatewayStats::count(&self.stats.dropped_sessions);
+                self.notify_session(conv, SessionEvent::Kick(HandshakePacket::DISCONNECT_REASON_BAD_DATA));
+                return;
             },
         };
+
+        self.notify_session(conv, SessionEvent::Datagram(source_address, packet_bytes.to_vec()));
     }
 
-    fn process_game_packet(&mut self, conv: u32, packet: &[u8], auth_manager: &mut Arc<Mutex<AuthManager>>) {
-        let data = match DataPacket::new_from_bytes(packet) {
-            Ok(data) => data,
-            Err(e) => panic!("Malformed data packet: {:#?}!", e),
-        };
+    // Players of nodes that went silent are kicked, they get to another node once they reconnect
+    fn expire_nodes(&mut self) {
+        let expired = self.router.lock().unwrap().expire_nodes();
 
-        let head = match PacketHead::decode(&mut Cursor::new(&data.metadata)) {
-            Ok(head) => head,
-            Err(e) => panic!("Malformed packet header: {:#?}!", e),
-        };
+        for (node, user_ids) in expired {
+            println!("Game node {} stopped responding, disconnecting its {} clients", node, user_ids.len());
 
-        let packet_id: proto::PacketId = match FromPrimitive::from_u16(data.packet_id) {
-            Some(packet_id) => packet_id,
-            None => {
-                println!("Skipping unknown packet ID {}", data.packet_id);
-                return;
-            }
-        };
+            NetworkServer::kick_users(&user_ids, &self.sessions, &self.users);
+        }
+    }
 
-        let user_id = match packet_id {
-            proto::PacketId::GetPlayerTokenReq => {
-                auth_manager.lock().unwrap().process(conv, packet_id, data.metadata, data.data);
-                return;
-            },
-            _ => match auth_manager.lock().unwrap().resolve_conv(conv) {
-                None => {
-                    println!("Unknown user with conv {}! Skipping", conv);
-                    return;
+    // Disconnects clients of the given users, if they're still around
+    fn kick_users(user_ids: &[u32], sessions: &Sessions, users: &Arc<Mutex<UserRegistry>>) {
+        for user_id in user_ids {
+            let conv = users.lock().unwrap().resolve_uid(*user_id);
+
+            let session = match conv {
+                Some(conv) => sessions.read().unwrap().get(&conv).cloned(),
+                None => None,
+            };
+
+            match session {
+                Some(session) => {
+                    let _ = session.send(SessionEvent::Kick(HandshakePacket::DISCONNECT_REASON_SERVER_KICK));
                 },
-                Some(user_id) => user_id,
-            },
+                None => {}, // Already gone
+            };
+        }
+    }
+
+    fn notify_session(&self, conv: u32, event: SessionEvent) {
+        let delivered = match self.sessions.read().unwrap().get(&conv) {
+            Some(session) => session.send(event).is_ok(),
+            None => false,
         };
 
-        if packet_id == proto::PacketId::UnionCmdNotify {
-            let union: proto::UnionCmdNotify = EasilyUnpackable::from(&data.data);
-            for u_cmd in union.cmd_list.into_iter() {
-                self.send_packet_to_process(user_id, u_cmd.message_id as u16, &data.metadata, &u_cmd.body);
-            }
-        } else {
-            self.send_packet_to_process(user_id, data.packet_id, &data.metadata, &data.data);
+        if !delivered {
+            println!("Unknown client conv {}, skipping", conv);
+            GatewayStats::count(&self.stats.unknown_convs);
         }
     }
 
-    fn send_packet_to_process(&mut self, user_id: u32, packet_id: u16, metadata: &[u8], data: &[u8])
-    {
-        /*let sender: &mut PubSocket = match &self.packets_to_process_tx {
-            Some(mut sender) => &mut sender,
-            None => panic!("Processing queue wasn't set up!"),
-        };*/
-                
-        let packet_id: proto::PacketId = match FromPrimitive::from_u16(packet_id) {
-            Some(packet_id) => packet_id,
-            None => {
-                println!("Skipping unknown packet ID {}", packet_id);
-                return;
+    fn ban(&mut self, address: IpAddr) {
+        self.rate_limiter.ban(address);
+        GatewayStats::count(&self.stats.bans);
+    }
+
+    fn open_session(&mut self, conv: u32, token: u32, source_address: SocketAddr) {
+        let socket = self.async_socket.clone().unwrap();
+
+        let mut client = ClientConnection::new(socket, conv, token, &self.master_key, self.stats.clone());
+        client.update_source(source_address);
+
+        let (events_tx, events_rx) = unbounded_channel();
+
+        let session = ClientSession::new(client, conv, events_rx, self.packets_to_process_tx.clone(),
+                                         self.auth_manager.clone().unwrap(), self.users.clone(), self.protocol_versions.clone(), self.stats.clone(), self.metrics.clone(),
+                                         &self.node_config);
+
+        self.sessions.write().unwrap().insert(conv, events_tx);
+        self.users.lock().unwrap().open_session(conv);
+
+        // Whatever way the session ends, the gateway has to clean up after it
+        let handle = tokio::spawn(session.run());
+        let closed_sessions_tx = self.closed_sessions_tx.clone();
+
+        tokio::spawn(async move {
+            match handle.await {
+                Ok(_) => {},
+                Err(e) => println!("Session with conv {} crashed: {}", conv, e),
+            };
+
+            let _ = closed_sessions_tx.send(conv);
+        });
+    }
+
+    fn close_session(&mut self, conv: u32) {
+        self.sessions.write().unwrap().remove(&conv);
+        self.conv_allocator.free(conv);
+        self.rate_limiter.forget_session(conv);
+
+        let user_id = match self.users.lock().unwrap().remove_session(conv) {
+            Some(user_id) => user_id,
+            None => return, // Client haven't logged in yet or is already playing from a newer session
+        };
+
+        // Tell the game server that the player is gone for good
+        self.publish(ControlMessage::SessionClosed(user_id));
+    }
+
+    fn publish(&self, message: ControlMessage) {
+        let _ = self.packets_to_process_tx.send((Instant::now(), IpcEvent::Control(message)));
+    }
+
+    /*
+      Only the limits and per-session settings can be changed on the fly, the latter apply to new sessions only.
+      Addresses, ports and paths are already in use, changing those requires a restart.
+     */
+    fn reload_config(&mut self) -> bool {
+        let node_config = match NodeConfig::load() {
+            Ok(node_config) => node_config,
+            Err(e) => {
+                println!("Failed to reload config, keeping the old one: {}", e);
+                return false;
             },
         };
 
-        println!("Got packet {:?}", packet_id);
-        self.packets_to_process_tx.send( IpcMessage(packet_id, user_id, metadata.to_vec(), data.to_vec()) );
+        self.rate_limiter.reconfigure(&node_config);
+        self.router.lock().unwrap().reconfigure(&node_config);
+        self.node_config = node_config;
+
+        println!("Config reloaded");
+
+        return true;
+    }
+
+    fn get_token(packet_bytes: &[u8]) -> u32 {
+        // Token immediately follows conv in the KCP header
+        // unwrap() here is valid as the size was checked by the caller
+        return u32::from_le_bytes(packet_bytes[4..8].try_into().unwrap());
     }
 }
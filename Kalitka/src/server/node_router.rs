@@ -0,0 +1,273 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use rs_nodeconf::NodeConfig;
+
+/*
+  Decides which game node serves which player.
+  Player sticks to the node picked at login until the session ends, so that changes in the set of live nodes
+  only affect new sessions; players of a node going down are kicked and land elsewhere once they reconnect.
+  There's no moving a live player between nodes: handoff always means the client reconnecting and logging in again.
+
+  Only nodes heard from get players: every configured node is considered down until it reports starting or sends
+  a heartbeat, and then it's expected to keep sending them. One that falls silent for node_timeout_secs, or announces
+  itself as started while still having players, is treated as crashed and handled the same way as one stopping.
+ */
+pub struct NodeRouter {
+    node_config: NodeConfig,
+    assignments: HashMap<u32, u8>,
+    last_seen: HashMap<u8, Instant>,
+}
+
+impl NodeRouter {
+    pub fn new(node_config: &NodeConfig) -> NodeRouter {
+        return NodeRouter {
+            node_config: node_config.clone(),
+            assignments: HashMap::new(),
+            last_seen: HashMap::new(),
+        };
+    }
+
+    // Node list may change, players already assigned stay where they are
+    pub fn reconfigure(&mut self, node_config: &NodeConfig) {
+        self.node_config = node_config.clone();
+    }
+
+    // Returns None if there's no live node to put the user on
+    pub fn assign(&mut self, user_id: u32) -> Option<u8> {
+        let down = self.down();
+
+        if self.node_config.game_nodes.iter().all(|node| down.contains(node)) {
+            return None;
+        }
+
+        let node = self.node_config.pick_game_node(user_id, &down);
+
+        self.assignments.insert(user_id, node);
+
+        return Some(node);
+    }
+
+    pub fn route(&mut self, user_id: u32) -> Option<u8> {
+        match self.assignments.get(&user_id) {
+            Some(node) => return Some(*node),
+            None => return self.assign(user_id),
+        };
+    }
+
+    pub fn release(&mut self, user_id: u32) -> Option<u8> {
+        return self.assignments.remove(&user_id);
+    }
+
+    /*
+      Returns the users that have to leave the node: if it was live and never said it was stopping,
+      it must have restarted, and whoever was assigned to it is gone from there.
+     */
+    pub fn node_started(&mut self, node: u8) -> Vec<u32> {
+        let restarted = self.last_seen.insert(node, Instant::now()).is_some();
+
+        match restarted {
+            true => return self.assigned_to(node),
+            false => return Vec::new(),
+        };
+    }
+
+    // Returns true if the node was considered down and is now up
+    pub fn node_alive(&mut self, node: u8) -> bool {
+        return self.last_seen.insert(node, Instant::now()).is_none();
+    }
+
+    // Returns the users that have to leave the node
+    pub fn node_stopping(&mut self, node: u8) -> Vec<u32> {
+        self.last_seen.remove(&node);
+
+        return self.assigned_to(node);
+    }
+
+    // Marks nodes that stopped sending heartbeats as down, returns them along with the users that have to leave
+    pub fn expire_nodes(&mut self) -> Vec<(u8, Vec<u32>)> {
+        let timeout = Duration::from_secs(self.node_config.node_timeout_secs);
+
+        let expired: Vec<u8> = self.last_seen.iter()
+            .filter(|(_, seen)| seen.elapsed() >= timeout)
+            .map(|(node, _)| *node)
+            .collect();
+
+        return expired.into_iter()
+            .map(|node| (node, self.node_stopping(node)))
+            .collect();
+    }
+
+    // Number of players per node, live ones included even if empty
+    pub fn load(&self) -> HashMap<u8, usize> {
+        let down = self.down();
+
+        let mut load: HashMap<u8, usize> = self.node_config.game_nodes.iter()
+            .filter(|node| !down.contains(node))
+            .map(|node| (*node, 0))
+            .collect();
+
+        for node in self.assignments.values() {
+            *load.entry(*node).or_insert(0) += 1;
+        }
+
+        return load;
+    }
+
+    fn down(&self) -> HashSet<u8> {
+        return self.node_config.game_nodes.iter()
+            .filter(|node| !self.last_seen.contains_key(node))
+            .cloned()
+            .collect();
+    }
+
+    fn assigned_to(&self, node: u8) -> Vec<u32> {
+        return self.assignments.iter()
+            .filter(|(_, assigned)| **assigned == node)
+            .map(|(user_id, _)| *user_id)
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn router(game_nodes: &[u8], node_timeout_secs: u64) -> NodeRouter {
+        let mut config = NodeConfig::new();
+        config.game_nodes = game_nodes.to_vec();
+        config.node_timeout_secs = node_timeout_secs;
+
+        return NodeRouter::new(&config);
+    }
+
+    fn sorted(mut user_ids: Vec<u32>) -> Vec<u32> {
+        user_ids.sort();
+
+        return user_ids;
+    }
+
+    #[test]
+    fn gives_no_players_to_silent_nodes() {
+        let mut router = router(&[0, 1], 10);
+
+        assert_eq!(router.assign(1), None);
+        assert_eq!(router.route(1), None);
+        assert!(router.load().is_empty());
+
+        assert!(router.node_alive(1));
+        assert!(!router.node_alive(1));
+
+        for user_id in 0..100 {
+            assert_eq!(router.route(user_id), Some(1));
+        }
+
+        assert_eq!(router.load().get(&0), None);
+        assert_eq!(router.load().get(&1), Some(&100));
+    }
+
+    #[test]
+    fn keeps_players_on_first_start() {
+        let mut router = router(&[0, 1], 10);
+
+        assert!(router.node_started(0).is_empty());
+        assert!(router.node_started(1).is_empty());
+        assert_eq!(router.load().get(&0), Some(&0));
+    }
+
+    #[test]
+    fn kicks_players_of_restarted_node() {
+        let mut router = router(&[0, 1], 10);
+
+        router.node_started(0);
+        router.node_started(1);
+
+        let on_zero: Vec<u32> = (0..100).filter(|user_id| router.route(*user_id) == Some(0)).collect();
+
+        assert!(!on_zero.is_empty());
+        assert_eq!(sorted(router.node_started(0)), on_zero);
+    }
+
+    #[test]
+    fn kicks_players_of_stopping_node() {
+        let mut router = router(&[0, 1, 2], 10);
+
+        for node in 0..3 {
+            router.node_started(node);
+        }
+
+        let before: Vec<Option<u8>> = (0..100).map(|user_id| router.route(user_id)).collect();
+        let on_two: Vec<u32> = (0..100).filter(|user_id| before[*user_id as usize] == Some(2)).collect();
+
+        assert!(!on_two.is_empty());
+        assert_eq!(sorted(router.node_stopping(2)), on_two);
+
+        // Everyone logs in again, only players of the stopped node change places
+        for user_id in 0..100 {
+            router.release(user_id);
+
+            match before[user_id as usize] {
+                Some(2) => assert_ne!(router.route(user_id), Some(2)),
+                node => assert_eq!(router.route(user_id), node),
+            };
+        }
+
+        assert_eq!(router.load().get(&2), None);
+    }
+
+    #[test]
+    fn returns_nothing_for_empty_stopping_node() {
+        let mut router = router(&[0, 1], 10);
+
+        assert!(router.node_stopping(1).is_empty());
+
+        router.node_started(0);
+        router.route(1);
+
+        assert!(router.node_stopping(1).is_empty());
+    }
+
+    #[test]
+    fn expires_silent_nodes() {
+        let mut router = router(&[0, 1], 0);
+
+        router.node_started(0);
+
+        let on_zero: Vec<u32> = (0..10).filter(|user_id| router.route(*user_id) == Some(0)).collect();
+
+        assert_eq!(on_zero.len(), 10);
+
+        let expired = router.expire_nodes();
+
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].0, 0);
+        assert_eq!(sorted(expired[0].1.clone()), on_zero);
+
+        // Node 1 never started, so there's nothing to expire
+        assert!(router.expire_nodes().is_empty());
+        assert_eq!(router.assign(100), None);
+    }
+
+    #[test]
+    fn keeps_nodes_sending_heartbeats() {
+        let mut router = router(&[0, 1], 10);
+
+        router.node_started(0);
+        router.route(1);
+
+        assert!(router.expire_nodes().is_empty());
+        assert_eq!(router.route(1), Some(0));
+    }
+
+    #[test]
+    fn forgets_released_players() {
+        let mut router = router(&[0], 10);
+
+        router.node_started(0);
+        router.route(1);
+
+        assert_eq!(router.release(1), Some(0));
+        assert_eq!(router.release(1), None);
+        assert!(router.node_stopping(0).is_empty());
+    }
+}
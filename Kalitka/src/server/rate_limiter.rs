@@ -0,0 +1,154 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use rs_nodeconf::NodeConfig;
+
+// Fixed-window hit counter
+struct Window {
+    started: Instant,
+    count: u32,
+}
+
+impl Window {
+    fn new() -> Window {
+        return Window {
+            started: Instant::now(),
+            count: 0,
+        };
+    }
+
+    // Returns the number of hits within current window, including this one
+    fn hit(&mut self, period: Duration) -> u32 {
+        if self.started.elapsed() >= period {
+            self.started = Instant::now();
+            self.count = 0;
+        }
+
+        self.count = self.count.saturating_add(1);
+        return self.count;
+    }
+
+    fn is_stale(&self, period: Duration) -> bool {
+        return self.started.elapsed() >= period;
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    Allow,
+    Throttle,
+    Ban,
+}
+
+/*
+  Keeps strangers from flooding the gateway.
+  Handshakes are limited per source IP, datagrams per session; whoever goes way over the limit gets banned for a while.
+ */
+pub struct RateLimiter {
+    handshakes: HashMap<IpAddr, Window>,
+    datagrams: HashMap<u32, Window>,
+    bans: HashMap<IpAddr, Instant>,
+    last_cleanup: Instant,
+    handshakes_per_ip: u32,
+    datagrams_per_session: u32,
+    max_sessions: usize,
+    ban_duration: Duration,
+}
+
+impl RateLimiter {
+    const HANDSHAKE_PERIOD: Duration = Duration::from_secs(60);
+    const DATAGRAM_PERIOD: Duration = Duration::from_secs(1);
+    const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+    // Session sending that many times more datagrams than allowed is surely malicious
+    const DATAGRAM_BAN_FACTOR: u32 = 4;
+    /*
+      Source IP of a handshake is easily spoofed, so going slightly over the limit only gets the extra ones dropped.
+      Otherwise anyone could get a legitimate address banned by sending a handful of handshakes on its behalf.
+     */
+    const HANDSHAKE_BAN_FACTOR: u32 = 4;
+
+    pub fn new(node_config: &NodeConfig) -> RateLimiter {
+        return RateLimiter {
+            handshakes: HashMap::new(),
+            datagrams: HashMap::new(),
+            bans: HashMap::new(),
+            last_cleanup: Instant::now(),
+            handshakes_per_ip: node_config.handshakes_per_ip_per_minute,
+            datagrams_per_session: node_config.datagrams_per_session_per_second,
+            max_sessions: node_config.max_sessions,
+            ban_duration: Duration::from_secs(node_config.ban_duration_secs),
+        };
+    }
+
+    // Counters and bans are kept, only the limits change
+    pub fn reconfigure(&mut self, node_config: &NodeConfig) {
+        self.handshakes_per_ip = node_config.handshakes_per_ip_per_minute;
+        self.datagrams_per_session = node_config.datagrams_per_session_per_second;
+        self.max_sessions = node_config.max_sessions;
+        self.ban_duration = Duration::from_secs(node_config.ban_duration_secs);
+    }
+
+    pub fn is_banned(&mut self, address: &IpAddr) -> bool {
+        match self.bans.get(address) {
+            Some(until) if *until > Instant::now() => return true,
+            Some(_) => {
+                self.bans.remove(address);
+                return false;
+            },
+            None => return false,
+        };
+    }
+
+    pub fn ban(&mut self, address: IpAddr) {
+        println!("Banning {} for {} seconds", address, self.ban_duration.as_secs());
+        self.bans.insert(address, Instant::now() + self.ban_duration);
+        self.handshakes.remove(&address);
+    }
+
+    pub fn check_handshake(&mut self, address: IpAddr) -> Verdict {
+        let count = self.handshakes.entry(address).or_insert_with(Window::new).hit(RateLimiter::HANDSHAKE_PERIOD);
+
+        if count > self.handshakes_per_ip.saturating_mul(RateLimiter::HANDSHAKE_BAN_FACTOR) {
+            return Verdict::Ban;
+        } else if count > self.handshakes_per_ip {
+            return Verdict::Throttle;
+        }
+
+        return Verdict::Allow;
+    }
+
+    pub fn check_session_count(&self, session_count: usize) -> bool {
+        return session_count < self.max_sessions;
+    }
+
+    pub fn check_datagram(&mut self, conv: u32) -> Verdict {
+        let count = self.datagrams.entry(conv).or_insert_with(Window::new).hit(RateLimiter::DATAGRAM_PERIOD);
+
+        if count > self.datagrams_per_session.saturating_mul(RateLimiter::DATAGRAM_BAN_FACTOR) {
+            return Verdict::Ban;
+        } else if count > self.datagrams_per_session {
+            return Verdict::Throttle;
+        }
+
+        return Verdict::Allow;
+    }
+
+    pub fn forget_session(&mut self, conv: u32) {
+        self.datagrams.remove(&conv);
+    }
+
+    // Drops the state nobody needs anymore, so that spoofed addresses don't eat all the memory
+    pub fn cleanup(&mut self) {
+        if self.last_cleanup.elapsed() < RateLimiter::CLEANUP_INTERVAL {
+            return;
+        }
+
+        let now = Instant::now();
+
+        self.handshakes.retain(|_, window| !window.is_stale(RateLimiter::HANDSHAKE_PERIOD));
+        self.bans.retain(|_, until| *until > now);
+
+        self.last_cleanup = now;
+    }
+}
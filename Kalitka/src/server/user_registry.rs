@@ -0,0 +1,131 @@
+use std::collections::{HashMap, HashSet};
+
+/*
+  Who is logged in through which conv, and with what key.
+  Everything the gateway touches on every packet lives here, behind a lock of its own,
+  so that logins doing their database and crypto work don't hold up other sessions.
+ */
+pub struct UserRegistry {
+    open_convs: HashSet<u32>,
+    conv_to_user: HashMap<u32, u32>,
+    user_to_conv: HashMap<u32, u32>,
+    conv_to_seed: HashMap<u32, u64>,
+}
+
+impl Default for UserRegistry {
+    fn default() -> Self {
+        UserRegistry::new()
+    }
+}
+
+impl UserRegistry {
+    pub fn new() -> UserRegistry {
+        return UserRegistry {
+            open_convs: HashSet::new(),
+            conv_to_user: HashMap::new(),
+            user_to_conv: HashMap::new(),
+            conv_to_seed: HashMap::new(),
+        };
+    }
+
+    pub fn open_session(&mut self, conv: u32) {
+        self.open_convs.insert(conv);
+    }
+
+    // Login may finish after its client is gone; returns false then, as there's nobody to register
+    pub fn add_session(&mut self, conv: u32, uid: u32, seed: u64) -> bool {
+        if !self.open_convs.contains(&conv) {
+            return false;
+        }
+
+        self.conv_to_user.insert(conv, uid);
+        self.user_to_conv.insert(uid, conv);
+        self.conv_to_seed.insert(conv, seed);
+
+        return true;
+    }
+
+    /*
+      Returns UID of the player who is gone with this conv.
+      If the player has already logged in again with another conv, nobody is gone and None is returned.
+     */
+    pub fn remove_session(&mut self, conv: u32) -> Option<u32> {
+        self.open_convs.remove(&conv);
+        self.conv_to_seed.remove(&conv);
+
+        let uid = self.conv_to_user.remove(&conv)?;
+
+        if self.user_to_conv.get(&uid) != Some(&conv) {
+            return None;
+        }
+
+        self.user_to_conv.remove(&uid);
+
+        return Some(uid);
+    }
+
+    pub fn get_seed(&self, conv: u32) -> Option<u64> {
+        return self.conv_to_seed.get(&conv).cloned();
+    }
+
+    pub fn resolve_conv(&self, conv: u32) -> Option<u32> {
+        match self.conv_to_user.get(&conv) {
+            Some(uid) => return Some(*uid),
+            None => return None,
+        };
+    }
+
+    pub fn resolve_uid(&self, uid: u32) -> Option<u32> {
+        match self.user_to_conv.get(&uid) {
+            Some(conv) => return Some(*conv),
+            None => return None,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registers_users_of_open_sessions() {
+        let mut users = UserRegistry::new();
+
+        users.open_session(10);
+
+        assert!(users.add_session(10, 1337, 42));
+        assert_eq!(users.resolve_conv(10), Some(1337));
+        assert_eq!(users.resolve_uid(1337), Some(10));
+        assert_eq!(users.get_seed(10), Some(42));
+
+        assert_eq!(users.remove_session(10), Some(1337));
+        assert_eq!(users.resolve_uid(1337), None);
+        assert_eq!(users.get_seed(10), None);
+    }
+
+    #[test]
+    fn ignores_logins_finishing_after_client_left() {
+        let mut users = UserRegistry::new();
+
+        users.open_session(10);
+        assert_eq!(users.remove_session(10), None);
+
+        assert!(!users.add_session(10, 1337, 42));
+        assert_eq!(users.resolve_conv(10), None);
+        assert_eq!(users.resolve_uid(1337), None);
+    }
+
+    #[test]
+    fn keeps_newer_session_of_same_user() {
+        let mut users = UserRegistry::new();
+
+        users.open_session(10);
+        users.open_session(20);
+        users.add_session(10, 1337, 1);
+        users.add_session(20, 1337, 2);
+
+        assert_eq!(users.remove_session(10), None);
+        assert_eq!(users.resolve_uid(1337), Some(20));
+        assert_eq!(users.remove_session(20), Some(1337));
+    }
+}
@@ -0,0 +1,49 @@
+use std::collections::HashMap;
+
+use rand::Rng;
+
+/*
+  Hands out conv / token pairs for KCP sessions.
+  Both values are random so that neither can be guessed from the other sessions' ones.
+ */
+pub struct ConvAllocator {
+    allocated: HashMap<u32, u32>,
+}
+
+impl ConvAllocator {
+    pub fn new() -> ConvAllocator {
+        return ConvAllocator {
+            allocated: HashMap::new(),
+        };
+    }
+
+    pub fn allocate(&mut self) -> (u32, u32) {
+        let mut rng = rand::thread_rng();
+
+        let conv = loop {
+            let conv: u32 = rng.gen();
+
+            // Zero conv is reserved by KCP
+            if conv != 0 && !self.allocated.contains_key(&conv) {
+                break conv;
+            }
+        };
+
+        let token: u32 = rng.gen();
+
+        self.allocated.insert(conv, token);
+
+        return (conv, token);
+    }
+
+    pub fn verify(&self, conv: u32, token: u32) -> bool {
+        match self.allocated.get(&conv) {
+            Some(allocated_token) => return *allocated_token == token,
+            None => return false,
+        };
+    }
+
+    pub fn free(&mut self, conv: u32) {
+        self.allocated.remove(&conv);
+    }
+}
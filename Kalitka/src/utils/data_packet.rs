@@ -2,8 +2,20 @@ use std::fmt;
 use std::convert::TryInto;
 
 #[derive(Debug)]
-pub struct DataDecError {
-    reason: String,
+pub enum DataDecError {
+    TooSmall(usize),
+    UnknownMagic(u16, u16),
+    SizeMismatch { expected: usize, actual: usize },
+}
+
+impl fmt::Display for DataDecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DataDecError::TooSmall(size) => write!(f, "Size is too small: {}", size),
+            DataDecError::UnknownMagic(start, end) => write!(f, "Unknown magic: 0x{:x} 0x{:x}", start, end),
+            DataDecError::SizeMismatch { expected, actual } => write!(f, "Wrong packet size: expected {}, got {}", expected, actual),
+        }
+    }
 }
 
 #[repr(packed)]
@@ -40,7 +52,7 @@ impl DataPacket {
 
     pub fn new_from_bytes(raw_data: &[u8]) -> Result<DataPacket, DataDecError> {
         if raw_data.len() < std::mem::size_of::<DataPacketSmallest>() {
-            return Err(DataDecError {reason: "Size is too small!".to_string()});
+            return Err(DataDecError::TooSmall(raw_data.len()));
         }
 
         // unwrap() here are valid as we're cutting exactly 4 bytes of data
@@ -55,7 +67,7 @@ impl DataPacket {
             let expected_size = std::mem::size_of::<DataPacketSmallest>() + metadata_size + data_size;
 
             if raw_data.len() != expected_size {
-                return Err(DataDecError {reason: format!("Wrong packet size: expected {}, got {} (header {}, payload {})!", expected_size, raw_data.len(), std::mem::size_of::<DataPacketSmallest>(), metadata_size + data_size)});
+                return Err(DataDecError::SizeMismatch { expected: expected_size, actual: raw_data.len() });
             }
 
             return Ok(DataPacket {
@@ -64,7 +76,7 @@ impl DataPacket {
                 data: raw_data[metadata_size+10..metadata_size+data_size+10].to_owned(),
             });
         } else {
-            return Err(DataDecError {reason: format!("Unknown magic: 0x{:x} 0x{:x}", start_magic, end_magic),});
+            return Err(DataDecError::UnknownMagic(start_magic, end_magic));
         }
     }
 
@@ -2,8 +2,18 @@ use std::fmt;
 use std::convert::TryInto;
 
 #[derive(Debug)]
-pub struct HandshakeDecError {
-    reason: String,
+pub enum HandshakeDecError {
+    SizeMismatch(usize),
+    UnknownMagic(u32, u32),
+}
+
+impl fmt::Display for HandshakeDecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HandshakeDecError::SizeMismatch(size) => write!(f, "Size mismatch: {}", size),
+            HandshakeDecError::UnknownMagic(start, end) => write!(f, "Unknown magic: 0x{:x} 0x{:x}", start, end),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -27,9 +37,13 @@ impl HandshakePacket {
 
     const HS_CONNECTION_DATA: u32 = 1234567890;
 
+    pub const DISCONNECT_REASON_TIMEOUT: u32 = 2;
+    pub const DISCONNECT_REASON_BAD_DATA: u32 = 3;
+    pub const DISCONNECT_REASON_SERVER_KICK: u32 = 5;
+
     pub fn new(raw_data: &[u8]) -> Result<HandshakePacket, HandshakeDecError> {
         if raw_data.len() != std::mem::size_of::<HandshakePacket>() {
-            return Err(HandshakeDecError {reason: "Size mismatch!".to_string()});
+            return Err(HandshakeDecError::SizeMismatch(raw_data.len()));
         }
 
         // unwrap() here are valid as we're cutting exactly 4 bytes of data
@@ -51,7 +65,7 @@ impl HandshakePacket {
                 end_magic: end_magic,
             });
         } else {
-            return Err(HandshakeDecError {reason: format!("Unknown magic: 0x{:x} 0x{:x}", start_magic, end_magic),});
+            return Err(HandshakeDecError::UnknownMagic(start_magic, end_magic));
         }
     }
 
@@ -61,6 +75,34 @@ impl HandshakePacket {
                (self.data == HandshakePacket::HS_CONNECTION_DATA);
     }
 
+    pub fn is_disconnect(&self) -> bool {
+        return (self.start_magic == HandshakePacket::HS_MAGIC_DISCONNECT_START) &&
+               (self.end_magic == HandshakePacket::HS_MAGIC_DISCONNECT_END);
+    }
+
+    pub fn conv(&self) -> u32 {
+        return self.param1;
+    }
+
+    pub fn token(&self) -> u32 {
+        return self.param2;
+    }
+
+    // Only meaningful for DISCONNECT packets
+    pub fn reason(&self) -> u32 {
+        return self.data;
+    }
+
+    pub fn new_connect() -> HandshakePacket {
+        HandshakePacket {
+            start_magic: HandshakePacket::HS_MAGIC_CONNECT_START,
+            param1: 0,
+            param2: 0,
+            data: HandshakePacket::HS_CONNECTION_DATA,
+            end_magic: HandshakePacket::HS_MAGIC_CONNECT_END,
+        }
+    }
+
     pub fn new_conv(conv: u32, token: u32) -> HandshakePacket {
         HandshakePacket {
             start_magic: HandshakePacket::HS_MAGIC_SEND_CONV_START,
@@ -71,6 +113,16 @@ impl HandshakePacket {
         }
     }
 
+    pub fn new_disconnect(conv: u32, token: u32, reason: u32) -> HandshakePacket {
+        HandshakePacket {
+            start_magic: HandshakePacket::HS_MAGIC_DISCONNECT_START,
+            param1: conv,
+            param2: token,
+            data: reason,
+            end_magic: HandshakePacket::HS_MAGIC_DISCONNECT_END,
+        }
+    }
+
     pub fn to_bytes(&self) -> Vec<u8> {
         let mut ret = Vec::with_capacity(std::mem::size_of::<HandshakePacket>());
 
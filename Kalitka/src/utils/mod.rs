@@ -1,5 +1,7 @@
 mod handshake_packet;
 mod data_packet;
+mod conv_allocator;
+mod packet_id_table;
 /*mod id_manager;
 mod time_manager;
 mod avatar_builder;*/
@@ -7,8 +9,10 @@ mod avatar_builder;*/
 #[macro_use]
 mod remapper;
 */
-pub use self::handshake_packet::HandshakePacket;
-pub use self::data_packet::DataPacket;
+pub use self::handshake_packet::{HandshakePacket, HandshakeDecError};
+pub use self::data_packet::{DataPacket, DataDecError};
+pub use self::conv_allocator::ConvAllocator;
+pub use self::packet_id_table::{PacketIdTable, ProtocolVersions};
 /*pub use self::id_manager::IdManager;
 pub use self::time_manager::TimeManager;
 pub use self::remapper::Remapper;
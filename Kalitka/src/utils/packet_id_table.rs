@@ -0,0 +1,151 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use num_traits::FromPrimitive;
+
+/*
+  Maps packet IDs used on the wire by a particular client version to the ones compiled into `proto`.
+  Tables are JSON files named after the version, with packet names as keys and command IDs as values.
+ */
+pub struct PacketIdTable {
+    version: String,
+    wire_to_internal: HashMap<u16, proto::PacketId>,
+    internal_to_wire: HashMap<proto::PacketId, u16>,
+}
+
+impl PacketIdTable {
+    // Table for the version `proto` was built for
+    pub fn native() -> PacketIdTable {
+        return PacketIdTable::from_pairs("native", PacketIdTable::compiled_packet_ids());
+    }
+
+    pub fn load(version: &str, filename: &Path, names: &HashMap<String, proto::PacketId>) -> Result<PacketIdTable, String> {
+        let contents = fs::read_to_string(filename).map_err(|e| format!("Failed to read {}: {}", filename.display(), e))?;
+        let ids: HashMap<String, u16> = serde_json::from_str(&contents).map_err(|e| format!("Error while reading json {}: {}", filename.display(), e))?;
+
+        let pairs = ids.into_iter()
+            .filter_map(|(name, wire_id)| match names.get(&name) {
+                Some(packet_id) => Some((wire_id, packet_id.clone())),
+                None => {
+                    println!("Packet {} of version {} is unknown to this build, skipping", name, version);
+                    None
+                },
+            })
+            .collect();
+
+        return Ok(PacketIdTable::from_pairs(version, pairs));
+    }
+
+    fn from_pairs(version: &str, pairs: Vec<(u16, proto::PacketId)>) -> PacketIdTable {
+        return PacketIdTable {
+            version: version.to_string(),
+            internal_to_wire: pairs.iter().map(|(wire_id, packet_id)| (packet_id.clone(), *wire_id)).collect(),
+            wire_to_internal: pairs.into_iter().collect(),
+        };
+    }
+
+    fn compiled_packet_ids() -> Vec<(u16, proto::PacketId)> {
+        return (0..=u16::MAX)
+            .filter_map(|id| FromPrimitive::from_u16(id).map(|packet_id| (id, packet_id)))
+            .collect();
+    }
+
+    pub fn version(&self) -> &str {
+        return &self.version;
+    }
+
+    pub fn to_internal(&self, wire_id: u16) -> Option<proto::PacketId> {
+        return self.wire_to_internal.get(&wire_id).cloned();
+    }
+
+    pub fn to_wire(&self, packet_id: &proto::PacketId) -> Option<u16> {
+        return self.internal_to_wire.get(packet_id).cloned();
+    }
+}
+
+pub struct ProtocolVersions {
+    tables: Vec<Arc<PacketIdTable>>,
+    // Worked out once, as formatting names for every packet relayed adds up
+    packet_names: HashMap<proto::PacketId, String>,
+    notifies: HashSet<proto::PacketId>,
+}
+
+impl ProtocolVersions {
+    pub fn load(directory: &str) -> ProtocolVersions {
+        let names: HashMap<String, proto::PacketId> = PacketIdTable::compiled_packet_ids().into_iter()
+            .map(|(_, packet_id)| (format!("{:?}", packet_id), packet_id))
+            .collect();
+
+        let mut tables = vec![];
+
+        match fs::read_dir(directory) {
+            Ok(entries) => for entry in entries.flatten() {
+                let path = entry.path();
+
+                if path.extension().map(|e| e != "json").unwrap_or(true) {
+                    continue;
+                }
+
+                let version = match path.file_stem() {
+                    Some(stem) => stem.to_string_lossy().to_string(),
+                    None => continue,
+                };
+
+                match PacketIdTable::load(&version, &path, &names) {
+                    Ok(table) => {
+                        println!("Loaded packet IDs for version {}", version);
+                        tables.push(Arc::new(table));
+                    },
+                    Err(e) => println!("{}", e),
+                };
+            },
+            Err(e) => println!("Can't read packet ID tables from {}: {}", directory, e),
+        };
+
+        if tables.is_empty() {
+            println!("No packet ID tables loaded, only native client version is supported");
+        }
+
+        // Native version is always served; its name sorts below any numbered version, so loaded tables win in case of ambiguity
+        tables.push(Arc::new(PacketIdTable::native()));
+
+        // Newest versions go first, so they win in case of ambiguity
+        tables.sort_by(|a, b| ProtocolVersions::version_key(b.version()).cmp(&ProtocolVersions::version_key(a.version())));
+
+        return ProtocolVersions {
+            tables: tables,
+            packet_names: names.iter().map(|(name, packet_id)| (packet_id.clone(), name.clone())).collect(),
+            notifies: names.iter().filter(|(name, _)| name.ends_with("Notify")).map(|(_, packet_id)| packet_id.clone()).collect(),
+        };
+    }
+
+    fn version_key(version: &str) -> Vec<u32> {
+        return version.split('.').map(|part| part.parse().unwrap_or(0)).collect();
+    }
+
+    pub fn packet_name(&self, packet_id: &proto::PacketId) -> &str {
+        match self.packet_names.get(packet_id) {
+            Some(name) => return name,
+            None => return "Unknown",
+        };
+    }
+
+    pub fn is_notify(&self, packet_id: &proto::PacketId) -> bool {
+        return self.notifies.contains(packet_id);
+    }
+
+    // Client's version is figured out by the ID of the very first packet it sends, which is GetPlayerTokenReq
+    pub fn detect(&self, wire_id: u16) -> Option<Arc<PacketIdTable>> {
+        let candidates: Vec<&Arc<PacketIdTable>> = self.tables.iter()
+            .filter(|table| table.to_internal(wire_id) == Some(proto::PacketId::GetPlayerTokenReq))
+            .collect();
+
+        if candidates.len() > 1 {
+            println!("Packet ID {} is GetPlayerTokenReq in {} versions, assuming {}", wire_id, candidates.len(), candidates[0].version());
+        }
+
+        return candidates.first().map(|table| (*table).clone());
+    }
+}
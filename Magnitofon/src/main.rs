@@ -0,0 +1,78 @@
+mod replay;
+
+use std::env;
+use std::process;
+use std::time::Duration;
+
+use rs_capture::CaptureReader;
+use rs_nodeconf::NodeConfig;
+
+use replay::Replayer;
+
+fn usage() -> ! {
+    println!("Usage: Magnitofon <capture file> [--uid <uid>] [--quiet-ms <ms>] [--ids-only]");
+    process::exit(2);
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+
+    let mut filename: Option<String> = None;
+    let mut user_id: Option<u32> = None;
+    let mut quiet_period = Duration::from_millis(500);
+    let mut compare_bodies = true;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--uid" => user_id = Some(args.next().and_then(|v| v.parse().ok()).unwrap_or_else(|| usage())),
+            "--quiet-ms" => quiet_period = Duration::from_millis(args.next().and_then(|v| v.parse().ok()).unwrap_or_else(|| usage())),
+            "--ids-only" => compare_bodies = false,
+            _ if filename.is_none() => filename = Some(arg),
+            _ => usage(),
+        };
+    }
+
+    let filename = filename.unwrap_or_else(|| usage());
+
+    let records = match CaptureReader::open(&filename).and_then(|mut reader| reader.read_all()) {
+        Ok(records) => records,
+        Err(e) => {
+            println!("Failed to read capture {}: {}", filename, e);
+            process::exit(2);
+        },
+    };
+
+    let nc = match NodeConfig::load() {
+        Ok(nc) => nc,
+        Err(e) => {
+            println!("{}", e);
+            process::exit(2);
+        },
+    };
+
+    let mut replayer = match Replayer::new(&nc, quiet_period, compare_bodies) {
+        Ok(replayer) => replayer,
+        Err(e) => {
+            println!("{}", e);
+            process::exit(2);
+        },
+    };
+
+    let report = match replayer.replay(records, user_id) {
+        Ok(report) => report,
+        Err(e) => {
+            println!("{}", e);
+            process::exit(2);
+        },
+    };
+
+    for mismatch in report.mismatches.iter() {
+        println!("{}", mismatch);
+    }
+
+    println!("Replayed {} steps, {} mismatches", report.steps, report.mismatches.len());
+
+    if !report.mismatches.is_empty() {
+        process::exit(1);
+    }
+}
@@ -0,0 +1,3 @@
+mod replayer;
+
+pub use self::replayer::{Replayer, ReplayError, ReplayReport};
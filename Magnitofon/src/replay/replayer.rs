@@ -0,0 +1,218 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use rs_capture::{CaptureRecord, Direction, BodyDecoders};
+use rs_ipc::{IpcMessage, ControlMessage, PubSocket};
+use rs_nodeconf::NodeConfig;
+
+#[derive(Debug, Clone)]
+pub struct ReplayError {
+    reason: String,
+}
+
+impl ReplayError {
+    pub fn new(reason: &str) -> ReplayError {
+        return ReplayError {reason: reason.to_string()};
+    }
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ReplayError: {}", self.reason)
+    }
+}
+
+pub struct ReplayReport {
+    pub steps: usize,
+    pub mismatches: Vec<String>,
+}
+
+// Single client packet along with everything server replied to it in the recorded session
+struct ReplayStep {
+    request: CaptureRecord,
+    expected: Vec<CaptureRecord>,
+}
+
+/*
+  Pretends to be Kalitka: binds the same queues, publishes recorded client packets and listens to what the game server says back.
+  Replies to a packet are considered complete once the server stays silent for a while.
+ */
+pub struct Replayer {
+    packets_to_process_tx: PubSocket,
+    packets_to_send_rx: mpsc::Receiver<IpcMessage>,
+    quiet_period: Duration,
+    compare_bodies: bool,
+    decoders: BodyDecoders,
+    node_config: NodeConfig,
+}
+
+impl Replayer {
+    // Game server needs some time to (re)connect to the queues, messages published before that are lost
+    const CONNECT_DELAY: Duration = Duration::from_secs(2);
+
+    pub fn new(node_config: &NodeConfig, quiet_period: Duration, compare_bodies: bool) -> Result<Replayer, ReplayError> {
+        let packets_to_process_tx = node_config.bind_in_queue()
+            .map_err(|e| ReplayError::new(&format!("Failed to bind in queue: {}", e)))?;
+
+        let mut packets_to_send_rx = node_config.bind_out_queue()
+            .map_err(|e| ReplayError::new(&format!("Failed to bind out queue: {}", e)))?;
+
+        let (tx, rx) = mpsc::channel();
+
+        thread::spawn(move || {
+            loop {
+                let message = match packets_to_send_rx.recv() {
+                    Ok(message) => message,
+                    Err(e) => {
+                        println!("Failed to receive response: {}", e);
+                        break;
+                    },
+                };
+
+                if tx.send(message).is_err() {
+                    break; // Replay is over
+                }
+            }
+        });
+
+        thread::sleep(Replayer::CONNECT_DELAY);
+
+        return Ok(Replayer {
+            packets_to_process_tx: packets_to_process_tx,
+            packets_to_send_rx: rx,
+            quiet_period: quiet_period,
+            compare_bodies: compare_bodies,
+            decoders: BodyDecoders::server(),
+            node_config: node_config.clone(),
+        });
+    }
+
+    // If `user_id` is given, session is replayed on behalf of that user instead of the recorded one
+    pub fn replay(&mut self, records: Vec<CaptureRecord>, user_id: Option<u32>) -> Result<ReplayReport, ReplayError> {
+        let steps = Replayer::split_into_steps(records);
+
+        let user_id = match (user_id, steps.first()) {
+            (Some(user_id), _) => user_id,
+            (None, Some(step)) => step.request.user_id,
+            (None, None) => return Err(ReplayError::new("Capture contains no client packets to replay")),
+        };
+
+        let mut report = ReplayReport {
+            steps: steps.len(),
+            mismatches: vec![],
+        };
+
+        // Player goes to the same game node the gateway would send them to
+        let node = self.node_config.pick_game_node(user_id, &HashSet::new());
+
+        println!("Replaying as user {} on game node {}", user_id, node);
+
+        // Game server ignores players the gateway hasn't told it about
+        self.send_control(node, ControlMessage::SessionOpened(user_id))?;
+
+        for (i, step) in steps.iter().enumerate() {
+            println!("Step {}: replaying {:?}", i, step.request.packet_id);
+
+            let message = IpcMessage(step.request.packet_id.clone(), user_id, step.request.metadata.clone(), step.request.data.clone());
+            self.send(node, message)?;
+
+            let actual = self.collect_responses(user_id);
+
+            for mismatch in self.compare(&step.expected, &actual) {
+                report.mismatches.push(format!("Step {} ({:?}): {}", i, step.request.packet_id, mismatch));
+            }
+        }
+
+        // Let the game server forget about the player, just as the gateway does when the session ends
+        self.send_control(node, ControlMessage::SessionClosed(user_id))?;
+
+        return Ok(report);
+    }
+
+    fn send(&mut self, node: u8, message: IpcMessage) -> Result<(), ReplayError> {
+        return self.packets_to_process_tx.send_to(node, message)
+            .map_err(|e| ReplayError::new(&format!("Failed to publish packet: {}", e)));
+    }
+
+    fn send_control(&mut self, node: u8, message: ControlMessage) -> Result<(), ReplayError> {
+        return self.packets_to_process_tx.send_control_to(node, &message)
+            .map_err(|e| ReplayError::new(&format!("Failed to publish {:?}: {}", message, e)));
+    }
+
+    fn split_into_steps(records: Vec<CaptureRecord>) -> Vec<ReplayStep> {
+        let mut steps: Vec<ReplayStep> = vec![];
+
+        for record in records.into_iter() {
+            // Token exchange never reaches the game server
+            if record.packet_id == proto::PacketId::GetPlayerTokenReq || record.packet_id == proto::PacketId::GetPlayerTokenRsp {
+                continue;
+            }
+
+            match record.direction {
+                Direction::ClientToServer => steps.push(ReplayStep {
+                    request: record,
+                    expected: vec![],
+                }),
+                Direction::ServerToClient => match steps.last_mut() {
+                    Some(step) => step.expected.push(record),
+                    None => {}, // Nothing was asked yet
+                },
+            };
+        }
+
+        return steps;
+    }
+
+    fn collect_responses(&self, user_id: u32) -> Vec<IpcMessage> {
+        let mut responses = vec![];
+
+        loop {
+            match self.packets_to_send_rx.recv_timeout(self.quiet_period) {
+                Ok(message) => {
+                    if message.1 == user_id {
+                        responses.push(message);
+                    } else {
+                        println!("Ignoring {:?} sent to user {}", message.0, message.1);
+                    }
+                },
+                Err(_) => break,
+            };
+        }
+
+        return responses;
+    }
+
+    fn compare(&self, expected: &[CaptureRecord], actual: &[IpcMessage]) -> Vec<String> {
+        let mut mismatches = vec![];
+
+        for i in 0..std::cmp::max(expected.len(), actual.len()) {
+            match (expected.get(i), actual.get(i)) {
+                (Some(expected), Some(actual)) => {
+                    if expected.packet_id != actual.0 {
+                        mismatches.push(format!("response #{}: expected {:?}, got {:?}", i, expected.packet_id, actual.0));
+                    } else if self.compare_bodies && expected.data != actual.3 {
+                        mismatches.push(format!("response #{} {:?} differs:\n  expected: {}\n  got:      {}", i, actual.0,
+                                                self.describe(&expected.packet_id, &expected.data),
+                                                self.describe(&actual.0, &actual.3)));
+                    }
+                },
+                (Some(expected), None) => mismatches.push(format!("response #{}: expected {:?}, got nothing", i, expected.packet_id)),
+                (None, Some(actual)) => mismatches.push(format!("response #{}: unexpected {:?}", i, actual.0)),
+                (None, None) => {},
+            };
+        }
+
+        return mismatches;
+    }
+
+    fn describe(&self, packet_id: &proto::PacketId, data: &[u8]) -> String {
+        match self.decoders.decode(packet_id, data) {
+            Some(Ok(json)) => json.to_string(),
+            Some(Err(e)) => format!("<{}>", e),
+            None => format!("<{} bytes>", data.len()),
+        }
+    }
+}
@@ -9,10 +9,12 @@ use packet_processor::*;
 
 use crate::collection;
 
-use sea_orm::{entity::*, error::*, query::*, DbConn, FromQueryResult, Database};
+use sea_orm::{entity::*, error::*, query::*, DbConn, FromQueryResult, Database, ConnectionTrait, Schema};
 use sea_orm::entity::prelude::*;
 use crate::JsonManager;
 use crate::utils::IdManager;
+use rs_utils::TimeManager;
+use chrono::NaiveDate;
 
 pub use super::player_info::Model as PlayerInfo;
 use super::player_info::Entity as PlayerInfoEntity;
@@ -189,6 +191,58 @@ impl DatabaseManager {
         };
     }
 
+    /*
+      Fresh in-memory SQLite database with every table created from the entity definitions.
+      Meant for tests and tools; fixture rows are added with `insert`.
+     */
+    pub fn in_memory(jm: Arc<JsonManager>) -> Self {
+        let db = Database::connect("sqlite::memory:").wait().unwrap();
+        let backend = db.get_database_backend();
+        let schema = Schema::new(backend);
+
+        let tables = vec![
+            schema.create_table_from_entity(PlayerInfoEntity),
+            schema.create_table_from_entity(AvatarInfoEntity),
+            schema.create_table_from_entity(AvatarWeaponEntity),
+            schema.create_table_from_entity(AvatarReliquaryEntity),
+            schema.create_table_from_entity(SceneInfoEntity),
+            schema.create_table_from_entity(TeamInfoEntity),
+            schema.create_table_from_entity(AvatarTeamInfoEntity),
+            schema.create_table_from_entity(TeamSelectionInfoEntity),
+            schema.create_table_from_entity(PlayerPropEntity),
+            schema.create_table_from_entity(AvatarPropEntity),
+            schema.create_table_from_entity(AvatarFightPropEntity),
+            schema.create_table_from_entity(OpenStateEntity),
+            schema.create_table_from_entity(MaterialInfoEntity),
+            schema.create_table_from_entity(ReliquaryInfoEntity),
+            schema.create_table_from_entity(EquipInfoEntity),
+            schema.create_table_from_entity(ItemInfoEntity),
+            schema.create_table_from_entity(WeaponAffixInfoEntity),
+            schema.create_table_from_entity(ReliquaryPropEntity),
+            schema.create_table_from_entity(FurnitureInfoEntity),
+            schema.create_table_from_entity(TransPointEntity),
+        ];
+
+        for table in tables {
+            db.execute(backend.build(&table)).wait().unwrap();
+        }
+
+        return DatabaseManager {
+            db: db,
+            jm: jm.clone(),
+        };
+    }
+
+    // Puts a single fixture row into the database, e.g. `dm.insert(PlayerInfo {...}.into_active_model())`
+    pub fn insert<A, E>(&self, row: A) -> Result<E::Model, DbErr>
+        where
+            A: ActiveModelTrait<Entity = E>,
+            E::Model: IntoActiveModel<A>,
+            E: EntityTrait,
+    {
+        return row.put(&self.db);
+    }
+
     pub fn get_player_info(&self, uid: u32) -> Option<PlayerInfo> {
         match PlayerInfoEntity::find_by_id(uid).one(&self.db).wait() {
             Err(_) => { println!("DB ERROR!"); None },
@@ -1078,6 +1132,136 @@ impl DatabaseManager {
         let point: TransPoint = point.put(&self.db).unwrap();
     }
 
+    /*
+      Player data for a UID nobody played as yet: the traveler holding their initial weapon, alone in the first team,
+      standing where everyone starts. Gateway hands such UIDs out to accounts logging in for the first time.
+     */
+    pub fn create_player(&self, uid: u32) -> Result<(), DbErr> {
+        let avatar_id = IdManager::get_avatar_id_by_char_id(Self::STARTER_CHARACTER_ID);
+
+        let avatar = match self.jm.avatars.get(&avatar_id) {
+            Some(avatar) => avatar,
+            None => return Err(DbErr::Custom(format!("No data for starter avatar {}", avatar_id))),
+        };
+
+        self.insert(PlayerInfo {
+            uid: uid,
+            nick_name: "Traveler".to_string(),
+            signature: "".to_string(),
+            birthday: NaiveDate::from_ymd(2000, 1, 1),
+            namecard_id: Self::STARTER_NAMECARD_ID,
+            finish_achievement_num: 0,
+            tower_floor_index: 1,
+            tower_level_index: 1,
+            avatar_id: avatar_id,
+        }.into_active_model())?;
+
+        let player_props = vec![
+            (proto::PropType::PropPlayerLevel, 1),
+            (proto::PropType::PropPlayerExp, 0),
+            (proto::PropType::PropPlayerWorldLevel, 0),
+            (proto::PropType::PropMaxStamina, 10000),
+            (proto::PropType::PropCurPersistStamina, 10000),
+            (proto::PropType::PropIsSpringAutoUse, 1),
+        ];
+
+        PlayerPropEntity::insert_many(player_props.into_iter().map(|(prop, value)| PlayerProp {
+            uid: uid,
+            prop_id: prop as u32,
+            prop_value: value,
+        }.into_active_model())).exec(&self.db).wait()?;
+
+        self.insert(OpenState {
+            uid: uid,
+            state_id: proto::OpenStateType::OpenStatePaimon as u32,
+            value: 1,
+        }.into_active_model())?;
+
+        self.insert(SceneInfo {
+            uid: uid,
+            scene_id: Self::SPOOFED_SCENE_ID,
+            scene_token: Self::SPOOFED_SCENE_TOKEN,
+            pos_x: -3400.0,
+            pos_y: 233.0,
+            pos_z: -3427.6,
+        }.into_active_model())?;
+
+        let guid = self.get_new_guid(uid) as i64;
+
+        self.insert(AvatarInfo {
+            uid: uid,
+            character_id: Self::STARTER_CHARACTER_ID,
+            avatar_type: 1,
+            guid: guid,
+            born_time: (TimeManager::timestamp() / 1000) as u32,
+        }.into_active_model())?;
+
+        let avatar_props = vec![
+            (proto::PropType::PropLevel, 1),
+            (proto::PropType::PropExp, 0),
+            (proto::PropType::PropBreakLevel, 0),
+        ];
+
+        AvatarPropEntity::insert_many(avatar_props.into_iter().map(|(prop, value)| AvatarProp {
+            guid: guid,
+            prop_id: prop as u32,
+            prop_value: value,
+        }.into_active_model())).exec(&self.db).wait()?;
+
+        let fight_props = vec![
+            (proto::FightPropType::FightPropBaseHp, avatar.hp_base),
+            (proto::FightPropType::FightPropMaxHp, avatar.hp_base),
+            (proto::FightPropType::FightPropCurHp, avatar.hp_base),
+            (proto::FightPropType::FightPropBaseAttack, avatar.attack_base),
+            (proto::FightPropType::FightPropCurAttack, avatar.attack_base),
+            (proto::FightPropType::FightPropBaseDefense, avatar.defense_base),
+            (proto::FightPropType::FightPropCurDefense, avatar.defense_base),
+            (proto::FightPropType::FightPropCritical, avatar.critical),
+            (proto::FightPropType::FightPropCriticalHurt, avatar.critical_hurt),
+            (proto::FightPropType::FightPropChargeEfficiency, avatar.charge_efficiency),
+            (proto::FightPropType::FightPropMaxWindEnergy, Self::STARTER_ENERGY),
+            (proto::FightPropType::FightPropCurWindEnergy, Self::STARTER_ENERGY),
+        ];
+
+        AvatarFightPropEntity::insert_many(fight_props.into_iter().map(|(prop, value)| AvatarFightProp {
+            guid: guid,
+            prop_id: prop as u32,
+            value: value,
+        }.into_active_model())).exec(&self.db).wait()?;
+
+        match self.add_equip(uid, avatar.initial_weapon) {
+            Some(weapon) => self.insert(AvatarWeapon {
+                avatar_guid: guid,
+                weapon_guid: weapon.guid as i64,
+            }.into_active_model())?,
+            None => return Err(DbErr::Custom(format!("Failed to give weapon {} to user {}", avatar.initial_weapon, uid))),
+        };
+
+        TeamInfoEntity::insert_many((1..=4).map(|id| TeamInfo {
+            uid: uid,
+            id: id,
+            name: format!("Team {}", id),
+        }.into_active_model())).exec(&self.db).wait()?;
+
+        self.insert(AvatarTeamInfo {
+            uid: uid,
+            team_id: 1,
+            guid: guid,
+        }.into_active_model())?;
+
+        self.insert(TeamSelectionInfo {
+            uid: uid,
+            avatar: guid,
+            team: 1,
+        }.into_active_model())?;
+
+        return Ok(());
+    }
+
+    const STARTER_CHARACTER_ID: u32 = 7;
+    const STARTER_NAMECARD_ID: u32 = 210001;
+    const STARTER_ENERGY: f32 = 60.0;
+
     pub const SPOOFED_AVATAR_ID: u32 = 1;
     pub const SPOOFED_WEAPON_ID: u32 = 2;
     const SPOOFED_SCENE_ID: u32 = 3; // TODO: that's a different kind of ID!
@@ -1,9 +1,9 @@
-use std::sync::{mpsc::{self, Sender, Receiver}, Arc, Mutex};
+use std::sync::{mpsc::{self, Sender, Receiver}, Arc, Mutex, MutexGuard};
 use std::thread;
 use std::collections::{HashMap, HashSet};
 use std::collections::hash_map::Entry::{Occupied, Vacant};
 
-use rs_ipc::{IpcMessage, PushSocket};
+use rs_ipc::{IpcMessage, PacketSink};
 
 use prost::Message;
 
@@ -32,7 +32,7 @@ struct Player {
     lua_manager: Arc<LuaManager>,
     json_manager: Arc<JsonManager>,
     db_manager: Arc<DatabaseManager>,
-    packets_to_send_tx: PushSocket,
+    packets_to_send_tx: Box<dyn PacketSink>,
 }
 
 impl Player {
@@ -151,29 +151,59 @@ impl Player {
         });
     }
 
+    pub fn save(&self) {
+        let mut scene_info = match self.db_manager.get_player_scene_info(self.player_id) {
+            Some(scene_info) => scene_info,
+            None => {
+                tracing::warn!("Scene info for player {} not found, nothing to save!", self.player_id);
+                return;
+            },
+        };
+
+        scene_info.scene_id = self.current_scene;
+        scene_info.pos_x = self.pos.x;
+        scene_info.pos_y = self.pos.y;
+        scene_info.pos_z = self.pos.z;
+
+        self.db_manager.update_player_scene_info(scene_info);
+    }
+
     // Gatherable stuff is described in GatherExcelConfigData
 }
 
+/*
+  Every player gets an outbound sink of its own, so the manager needs a way to open new ones
+ */
+pub type SinkFactory = Box<dyn Fn() -> Box<dyn PacketSink> + Send + Sync>;
+
 pub struct EntityManager {
-    packets_to_send_tx: PushSocket,
+    packets_to_send_tx: Box<dyn PacketSink>,
     players: Arc<Mutex<HashMap<u32, Player>>>,
     players_moved: Sender<u32>,
     lua_manager: Arc<LuaManager>,
     json_manager: Arc<JsonManager>,
     db_manager: Arc<DatabaseManager>,
+    sinks: SinkFactory,
 }
 
 impl EntityManager {
     pub fn new(lua_manager: Arc<LuaManager>, json_manager: Arc<JsonManager>, db_manager: Arc<DatabaseManager>, node_config: &NodeConfig) -> Self {
+        let node_config = node_config.clone();
+
+        return Self::with_sinks(lua_manager, json_manager, db_manager, Box::new(move || Box::new(node_config.connect_out_queue().unwrap())));
+    }
+
+    pub fn with_sinks(lua_manager: Arc<LuaManager>, json_manager: Arc<JsonManager>, db_manager: Arc<DatabaseManager>, sinks: SinkFactory) -> Self {
         let (tx, rx): (Sender<u32>, Receiver<u32>) = mpsc::channel();
 
         let mut es = Self {
-            packets_to_send_tx: node_config.connect_out_queue().unwrap(),
+            packets_to_send_tx: sinks(),
             players_moved: tx,
             players: Arc::new(Mutex::new(HashMap::new())),
             lua_manager: lua_manager,
             json_manager: json_manager,
             db_manager: db_manager,
+            sinks: sinks,
         };
 
         es.run(rx);
@@ -189,91 +219,119 @@ impl EntityManager {
             loop {
                 let player_id = rx.recv().unwrap();
 
-                match players.lock() {
-                    Ok(mut players) => {
-                        let mut player = &mut players.get_mut(&player_id).unwrap();
-                        let scene = lua_manager.get_scene_by_id(player.current_scene).unwrap();
-                        let block = scene.get_block_by_pos(&player.pos);
-
-                        match block {
-                            Ok(block) =>
-                                if player.current_block != block.block_id {
-                                    println!("Player {:?} moved to the block {:?}", player.player_id, block.block_id);
-                                    player.current_block = block.block_id;
-                                },
-                            Err(_) => {
-                                // TODO?
-                                player.current_block = 0;
-                            },
-                        };
-
-                        player.position_changed();
+                let mut players = EntityManager::lock_players(&players);
+
+                let mut player = match players.get_mut(&player_id) {
+                    Some(player) => player,
+                    None => continue, // Player has already logged out
+                };
+                // Only some scenes are loaded, players elsewhere just don't get their surroundings tracked
+                let scene = match lua_manager.get_scene_by_id(player.current_scene) {
+                    Ok(scene) => scene,
+                    Err(e) => {
+                        println!("WARN: {}, not tracking player {}", e, player_id);
+                        continue;
+                    },
+                };
+                let block = scene.get_block_by_pos(&player.pos);
+
+                match block {
+                    Ok(block) =>
+                        if player.current_block != block.block_id {
+                            println!("Player {:?} moved to the block {:?}", player.player_id, block.block_id);
+                            player.current_block = block.block_id;
+                        },
+                    Err(_) => {
+                        // TODO?
+                        player.current_block = 0;
                     },
-                    Err(_) => panic!("Failed to grab player data!"),
                 };
+
+                player.position_changed();
             }
         });
     }
 
     pub fn player_moved(&self, user_id: u32, pos: Vector) {
-        match self.players.lock()
-        {
-            Ok(mut players) => match players.entry(user_id) {
-                Occupied(mut player) => {
-                    let mut player = player.get_mut();
-
-                    // HACK: if player moved too far away, then he's probably teleported just now; don't change position, we're in the process of teleportation
-                    if player.pos.sub(&pos).len() < 10.0 {
-                        player.pos = pos;
-                    } else {
-                        println!("WARN: Teleport detected, hack applied!");
-                    }
-                },
-                Vacant(entry) => {
-                    panic!("Moving of nonexistent player: {}", user_id);
-                },
+        match EntityManager::lock_players(&self.players).entry(user_id) {
+            Occupied(mut player) => {
+                let mut player = player.get_mut();
+
+                // HACK: if player moved too far away, then he's probably teleported just now; don't change position, we're in the process of teleportation
+                if player.pos.sub(&pos).len() < 10.0 {
+                    player.pos = pos;
+                } else {
+                    tracing::warn!("Teleport detected, hack applied!");
+                }
+            },
+            Vacant(_) => {
+                // Movement may still be in flight when the player logs out
+                tracing::warn!("Moving of nonexistent player: {}", user_id);
             },
-            Err(_) => panic!("Failed to grab player data!"),
         };
 
         self.players_moved.send(user_id).unwrap();
     }
 
     pub fn player_teleported(&self, user_id: u32, pos: Vector, scene_id: u32, token: u32, reason: &proto::EnterType) {
-        match self.players.lock()
-        {
-            Ok(mut players) => match players.entry(user_id) {
-                Occupied(mut player) => {
-                    let mut player = player.get_mut();
+        match EntityManager::lock_players(&self.players).entry(user_id) {
+            Occupied(mut player) => {
+                let mut player = player.get_mut();
 
-                    player.pos = pos;
+                player.pos = pos;
+
+                // TODO: check for scene_id change!
+                player.current_scene = scene_id;
+
+                player.enter_scene(reason, token);
+            },
+            Vacant(entry) => {
+                let mut player = Player {
+                    player_id: user_id,
+                    pos: pos,
+                    current_block: 0,
+                    current_scene: scene_id,
+                    entities: HashMap::new(),
+                    lua_manager: self.lua_manager.clone(),
+                    json_manager: self.json_manager.clone(),
+                    db_manager: self.db_manager.clone(),
+                    packets_to_send_tx: (self.sinks)(),
+                };
+
+                player.enter_scene(reason, token);
 
-                    // TODO: check for scene_id change!
-                    player.current_scene = scene_id;
-
-                    player.enter_scene(reason, token);
-                },
-                Vacant(entry) => {
-                    let mut player = Player {
-                        player_id: user_id,
-                        pos: pos,
-                        current_block: 0,
-                        current_scene: scene_id,
-                        entities: HashMap::new(),
-                        lua_manager: self.lua_manager.clone(),
-                        json_manager: self.json_manager.clone(),
-                        db_manager: self.db_manager.clone(),
-                        packets_to_send_tx: NodeConfig::new().connect_out_queue().unwrap(),//self.packets_to_send_tx.clone(),
-                    };
-
-                    player.enter_scene(reason, token);
-
-                    entry.insert(player);
-                },
+                entry.insert(player);
             },
-            Err(_) => panic!("Failed to grab player data!"),
         };
 
         self.players_moved.send(user_id).unwrap();
     }
-}
\ No newline at end of file
+
+    pub fn online_players(&self) -> Vec<u32> {
+        return EntityManager::lock_players(&self.players).keys().cloned().collect();
+    }
+
+    pub fn is_online(&self, user_id: u32) -> bool {
+        return EntityManager::lock_players(&self.players).contains_key(&user_id);
+    }
+
+    pub fn player_logged_out(&self, user_id: u32) {
+        let player = EntityManager::lock_players(&self.players).remove(&user_id);
+
+        match player {
+            Some(player) => player.save(),
+            None => tracing::warn!("Logout of nonexistent player: {}", user_id),
+        };
+    }
+
+    /*
+      Handler panicking with the lock held leaves it poisoned, but the map itself stays usable: at worst the player
+      in question is left half-updated. Refusing the lock instead would break logouts of everyone else for good.
+     */
+    fn lock_players(players: &Mutex<HashMap<u32, Player>>) -> MutexGuard<HashMap<u32, Player>> {
+        return players.lock().unwrap_or_else(|e| {
+            tracing::warn!("Player data lock was poisoned by a panic, recovering");
+            e.into_inner()
+        });
+    }
+}
@@ -1,5 +1,5 @@
 mod entity_manager;
 mod entities;
 
-pub use self::entity_manager::EntityManager;
+pub use self::entity_manager::{EntityManager, SinkFactory};
 pub use self::entities::{Entity, EntityTrait};
\ No newline at end of file
@@ -38,8 +38,13 @@ fn group_nonconsec_by<A, B, I>(v: I, key: fn (&B) -> A) -> BTreeMap<A, Vec<B>>
     result
 }
 
+enum JsonSource {
+    Directory(String),
+    Fixtures(HashMap<String, String>),
+}
+
 struct JsonReader {
-    base_path: String,
+    source: JsonSource,
 }
 
 pub struct JsonManager {
@@ -80,8 +85,19 @@ fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
 
 impl JsonManager {
     pub fn new(directory: &str) -> JsonManager {
-        let reader = JsonReader::new(directory);
+        return JsonManager::load(JsonReader::new(directory));
+    }
 
+    /*
+      Builds the manager from in-memory JSON documents instead of a data dump.
+      Documents are keyed by file name without extension (e.g. "ShopGoodsExcelConfigData" or "TeleportPoints");
+      everything not given is treated as an empty list.
+     */
+    pub fn from_fixtures(fixtures: &[(&str, &str)]) -> JsonManager {
+        return JsonManager::load(JsonReader::from_fixtures(fixtures));
+    }
+
+    fn load(reader: JsonReader) -> JsonManager {
         let asd: Vec<AvatarSkillDepot> = reader.read_json_list_game("AvatarSkillDepot");
         let mc: Vec<EntityCurve> = reader.read_json_list_game("MonsterCurve");
         let monsters: Vec<Monster> = reader.read_json_list_game("Monster");
@@ -186,17 +202,31 @@ impl JsonManager {
 impl JsonReader {
     pub fn new(directory: &str) -> JsonReader {
         return JsonReader {
-            base_path: directory.to_owned(),
+            source: JsonSource::Directory(directory.to_owned()),
+        };
+    }
+
+    pub fn from_fixtures(fixtures: &[(&str, &str)]) -> JsonReader {
+        return JsonReader {
+            source: JsonSource::Fixtures(fixtures.iter().map(|(name, json)| (name.to_string(), json.to_string())).collect()),
         };
     }
 
     fn read_json_list<T>(&self, name: &str, subpath: &str) -> Vec<T>
         where T: DeserializeOwned
     {
-        let path = format!("{}/{}/{}.json", self.base_path, subpath, name);
-
-        let json_file_path = Path::new(&path);
-        let json_file_str = read_to_string(json_file_path).unwrap_or_else(|_| panic!("File {} not found", path));
+        let json_file_str = match &self.source {
+            JsonSource::Directory(base_path) => {
+                let path = format!("{}/{}/{}.json", base_path, subpath, name);
+
+                let json_file_path = Path::new(&path);
+                read_to_string(json_file_path).unwrap_or_else(|_| panic!("File {} not found", path))
+            },
+            JsonSource::Fixtures(fixtures) => match fixtures.get(name) {
+                Some(json) => json.clone(),
+                None => "[]".to_owned(),
+            },
+        };
         let data: Vec<T> = serde_json::from_str(&json_file_str).expect(&format!("Error while reading json {}", name));
         return data;
     }
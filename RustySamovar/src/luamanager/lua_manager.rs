@@ -90,6 +90,14 @@ impl LuaManager {
         }
     }
 
+    // No scenes at all; for tests and tools that don't care about scene contents
+    pub fn empty() -> LuaManager {
+        LuaManager {
+            scenes_data: HashMap::new(),
+            last_entity_id: 1,
+        }
+    }
+
     pub fn get_scene_by_id(&self, scene_id: u32) -> Result<&InternalSceneData, String> {
         if self.scenes_data.contains_key(&scene_id) {
             return Ok(&self.scenes_data[&scene_id]);
@@ -5,6 +5,7 @@ extern crate tracing_subscriber;
 #[macro_use]
 extern crate num_derive;
 
+use std::process;
 use std::thread;
 
 use rs_nodeconf::NodeConfig;
@@ -35,8 +36,20 @@ fn main() {
         .with_test_writer()
         .init();
 
-    let nc = NodeConfig::new();
-    let mut gs = GameServer::new(&nc);
+    let nc = match NodeConfig::load().and_then(|nc| nc.validate_game_node().map(|_| nc)) {
+        Ok(nc) => nc,
+        Err(e) => {
+            println!("{}", e);
+            process::exit(2);
+        },
+    };
+    let mut gs = match GameServer::new(&nc) {
+        Ok(gs) => gs,
+        Err(e) => {
+            println!("{}", e);
+            process::exit(2);
+        },
+    };
 
     gs.run();
 }
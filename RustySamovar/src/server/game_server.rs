@@ -1,20 +1,26 @@
+use std::fmt;
 use std::sync::{mpsc, Mutex};
+use std::sync::mpsc::RecvTimeoutError;
 use std::thread;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::collections::hash_map::Entry::{Occupied, Vacant};
+use std::time::{Duration, Instant};
 
-use rs_ipc::{SubSocket, IpcMessage, PushSocket};
+use rs_ipc::{SubSocket, IpcMessage, IpcEvent, ControlMessage, PushSocket};
+use rs_metrics::{Metrics, MetricsServer};
 
 use crate::server::GameWorld;
-use packet_processor::PacketProcessor;
+use packet_processor::{PacketProcessor, MiddlewareChain, LoggingMiddleware, PanicIsolationMiddleware};
 
 use crate::{DatabaseManager, EntitySubsystem};
 use crate::JsonManager;
 use crate::LuaManager;
 use crate::server::LoginManager;
+use crate::server::{TracingMiddleware, TimingMiddleware, LoginGateMiddleware};
 use std::sync::Arc;
 use crate::entitymanager::EntityManager;
 use rs_nodeconf::NodeConfig;
+use rs_utils::{ShutdownSignal, ReloadSignal};
 use crate::subsystems::{InventorySubsystem, NpcSubsystem, ShopSubsystem};
 use crate::subsystems::misc::{PauseSubsystem, SceneSubsystem, SocialSubsystem, TeleportSubsystem};
 
@@ -35,24 +41,61 @@ impl<F,T> Block for F
 
 // -------------
 
+#[derive(Debug, Clone)]
+pub struct GameServerError {
+    reason: String,
+}
+
+impl GameServerError {
+    pub fn new(reason: &str) -> GameServerError {
+        return GameServerError {reason: reason.to_string()};
+    }
+}
+
+impl fmt::Display for GameServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "GameServerError: {}", self.reason)
+    }
+}
+
+// Who takes care of a packet; game world is per user, so it's looked up (or created) on the fly
+#[derive(Debug, Clone, Copy)]
+enum Handler {
+    Login,
+    World,
+    Processor(usize),
+}
+
 pub struct GameServer {
     //packets_to_process_rx: mpsc::Receiver<IpcMessage>,
-    packets_to_process_rx: SubSocket,
+    packets_to_process_rx: Option<SubSocket>,
     //packets_to_send_tx: mpsc::Sender<IpcMessage>,
     //packets_to_send_tx: PushSocket,
+    control_tx: PushSocket,
+    sessions: HashSet<u32>,
+    kicked: HashSet<u32>,
     worlds: HashMap<u32, GameWorld>,
     login_manager: LoginManager,
     database_manager: Arc<DatabaseManager>,
     json_manager: Arc<JsonManager>,
+    entity_manager: Arc<EntityManager>,
     processors: Vec<Box<PacketProcessor>>,
+    handlers: HashMap<proto::PacketId, Handler>,
+    middleware: Arc<MiddlewareChain>,
+    unhandled: HashSet<proto::PacketId>,
+    metrics: Arc<Metrics>,
+    shutdown_timeout: Duration,
+    node_config: NodeConfig,
 }
 
 impl GameServer {
+    const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
     //pub fn new(packets_to_process_rx: mpsc::Receiver<IpcMessage>, packets_to_send_tx: mpsc::Sender<IpcMessage>) -> GameServer {
-    pub fn new(node_config: &NodeConfig) -> GameServer {
-        let jm = Arc::new(JsonManager::new("./data/json"));
-        let db = Arc::new(DatabaseManager::new("sqlite://./database.db3", jm.clone()));
-        let lum = Arc::new(LuaManager::new("./data/lua", &jm.clone()));
+    pub fn new(node_config: &NodeConfig) -> Result<GameServer, GameServerError> {
+        let jm = Arc::new(JsonManager::new(&node_config.json_data_dir));
+        let db = Arc::new(DatabaseManager::new(&node_config.database_url, jm.clone()));
+        let lum = Arc::new(LuaManager::new(&node_config.lua_data_dir, &jm.clone()));
         let em = Arc::new(EntityManager::new(lum.clone(),jm.clone(), db.clone(), node_config));
         let lm = LoginManager::new(db.clone(), jm.clone(), em.clone(),node_config);
 
@@ -66,20 +109,104 @@ impl GameServer {
         let socs = SocialSubsystem::new(db.clone(), node_config);
         let ts = TeleportSubsystem::new(jm.clone(), db.clone(), em.clone(), node_config);
 
-        let mut packets_to_process_rx = node_config.connect_in_queue().unwrap();
-        packets_to_process_rx.subscribe_all();
+        let mut packets_to_process_rx = node_config.connect_in_queue()
+            .map_err(|e| GameServerError::new(format!("Failed to connect to in queue: {}", e).as_str()))?;
+        // Gateway sends us the players assigned to this node only
+        packets_to_process_rx.subscribe_node(node_config.game_node_id)
+            .map_err(|e| GameServerError::new(format!("Failed to subscribe to packets of node {}: {}", node_config.game_node_id, e).as_str()))?;
+        packets_to_process_rx.subscribe_control()
+            .map_err(|e| GameServerError::new(format!("Failed to subscribe to control messages: {}", e).as_str()))?;
+        let control_tx = node_config.connect_out_queue()
+            .map_err(|e| GameServerError::new(format!("Failed to connect to out queue: {}", e).as_str()))?;
         //let mut packets_to_send_tx = PushSocket::connect_tcp("127.0.0.1", 9014).unwrap();
 
-        let gs = GameServer {
-            packets_to_process_rx: packets_to_process_rx,
+        let metrics = Arc::new(Metrics::new());
+        metrics.describe("samovar_active_players", "Players that have a world loaded");
+        metrics.describe("samovar_packets_total", "Packets received from the gateway");
+        metrics.describe("samovar_handler_seconds", "Time spent handling a packet, per processor");
+        metrics.describe("samovar_unhandled_packets_total", "Packets no processor is registered for");
+        metrics.describe("samovar_ipc_queue_seconds", "Time packets from the gateway spend in the IPC queue, from being sent until this node receives them; relies on the clocks of the nodes being in sync");
+
+        // Panics are caught early, so that the rest of the middleware still gets to see the outcome
+        let middleware = MiddlewareChain::new()
+            .with(TracingMiddleware)
+            .with(PanicIsolationMiddleware)
+            .with(LoggingMiddleware)
+            .with(LoginGateMiddleware::new(em.clone()))
+            .with(TimingMiddleware::new(metrics.clone()));
+
+        match MetricsServer::start(metrics.clone(), &node_config.metrics_addr, node_config.game_node_metrics_port()) {
+            Ok(_) => {},
+            Err(e) => println!("Failed to start metrics server: {}", e),
+        };
+
+        let mut gs = GameServer {
+            packets_to_process_rx: Some(packets_to_process_rx),
+            control_tx: control_tx,
+            sessions: HashSet::new(),
+            kicked: HashSet::new(),
             worlds: HashMap::new(),
             login_manager: lm,
             database_manager: db.clone(),
             json_manager: jm.clone(),
+            entity_manager: em.clone(),
             processors: vec![Box::new(es), Box::new(nt), Box::new(ss), Box::new(scs), Box::new(ps), Box::new(socs), Box::new(ts)],
+            handlers: HashMap::new(),
+            middleware: Arc::new(middleware),
+            unhandled: HashSet::new(),
+            metrics: metrics,
+            shutdown_timeout: Duration::from_secs(node_config.shutdown_timeout_secs),
+            node_config: node_config.clone(),
         };
 
-        return gs;
+        gs.install_middleware();
+        gs.register_handlers()?;
+
+        return Ok(gs);
+    }
+
+    fn install_middleware(&mut self) {
+        self.login_manager.use_middleware(self.middleware.clone());
+
+        for processor in self.processors.iter_mut() {
+            processor.use_middleware(self.middleware.clone());
+        }
+    }
+
+    // Every packet has exactly one handler, two processors claiming the same packet is a bug
+    fn register_handlers(&mut self) -> Result<(), GameServerError> {
+        let mut registrations: Vec<(proto::PacketId, Handler, &'static str)> = vec![];
+
+        for packet_id in self.login_manager.supported() {
+            registrations.push((packet_id, Handler::Login, self.login_manager.name()));
+        }
+
+        // Worlds are created per user, so there's none to ask yet
+        for packet_id in GameWorld::supported_packets() {
+            registrations.push((packet_id, Handler::World, GameWorld::PROCESSOR_NAME));
+        }
+
+        for (i, processor) in self.processors.iter().enumerate() {
+            for packet_id in processor.supported() {
+                registrations.push((packet_id, Handler::Processor(i), processor.name()));
+            }
+        }
+
+        let mut owners: HashMap<proto::PacketId, &'static str> = HashMap::new();
+
+        for (packet_id, handler, name) in registrations.into_iter() {
+            match owners.get(&packet_id) {
+                Some(owner) => return Err(GameServerError::new(format!("{:?} is handled by both {} and {}", packet_id, owner, name).as_str())),
+                None => {},
+            };
+
+            owners.insert(packet_id.clone(), name);
+            self.handlers.insert(packet_id, handler);
+        }
+
+        println!("Registered handlers for {} packets", self.handlers.len());
+
+        return Ok(());
     }
 
     pub fn run(&mut self) {
@@ -90,33 +217,228 @@ impl GameServer {
             //}
         });
 
-        loop {
-            let IpcMessage(packet_id, user_id, metadata, data) = self.packets_to_process_rx.recv().unwrap();
+        let shutdown = ShutdownSignal::install();
+        let reload = ReloadSignal::install();
+        let packets_to_process_rx = self.start_receiver_thread();
+
+        println!("Game node {} is ready", self.node_config.game_node_id);
+        self.send_control(ControlMessage::NodeStarted(self.node_config.game_node_id));
+
+        // Gateway takes a node that goes silent for too long as crashed and moves its players away
+        let mut last_heartbeat = Instant::now();
+
+        while !shutdown.is_requested() {
+            match packets_to_process_rx.recv_timeout(GameServer::POLL_INTERVAL) {
+                Ok(event) => self.process_event(event),
+                Err(RecvTimeoutError::Timeout) => {},
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+
+            if reload.take() && self.reload_config() {
+                self.send_control(ControlMessage::ReloadConfig);
+            }
+
+            if last_heartbeat.elapsed() >= Duration::from_secs(self.node_config.node_heartbeat_interval_secs) {
+                self.send_control(ControlMessage::NodeAlive(self.node_config.game_node_id));
+                last_heartbeat = Instant::now();
+            }
+        }
+
+        self.shutdown(&packets_to_process_rx);
+    }
+
+    // SubSocket can't wait with a timeout, so it's read by a thread of its own
+    fn start_receiver_thread(&mut self) -> mpsc::Receiver<IpcEvent> {
+        let mut packets_to_process_rx = self.packets_to_process_rx.take().unwrap();
+        let (tx, rx) = mpsc::channel();
+        let metrics = self.metrics.clone();
+
+        thread::spawn(move || {
+            loop {
+                match packets_to_process_rx.recv_event_timed() {
+                    Ok((event, latency)) => {
+                        match latency {
+                            Some(latency) => metrics.observe("samovar_ipc_queue_seconds", &[], latency),
+                            None => {},
+                        };
+
+                        if tx.send(event).is_err() {
+                            return; // Game server is gone
+                        }
+                    },
+                    Err(e) => println!("Failed to receive packet to process: {}", e),
+                };
+            }
+        });
+
+        return rx;
+    }
+
+    fn process_event(&mut self, event: IpcEvent) {
+        match event {
+            IpcEvent::Packet(message) => self.process_message(message),
+            IpcEvent::Control(message) => self.process_control_message(message),
+        };
+    }
+
+    fn process_control_message(&mut self, message: ControlMessage) {
+        match message {
+            ControlMessage::SessionOpened(user_id) => {
+                self.sessions.insert(user_id);
+                self.kicked.remove(&user_id);
+            },
+            ControlMessage::SessionClosed(user_id) => {
+                self.sessions.remove(&user_id);
+                self.kicked.remove(&user_id);
+                self.player_logout(user_id);
+            },
+            ControlMessage::ReloadConfig => {
+                self.reload_config();
+            },
+            ControlMessage::ShuttingDown => {
+                // Gateway has closed all the sessions already, whoever is still here was missed somehow
+                println!("Gateway is shutting down");
+
+                for user_id in self.entity_manager.online_players() {
+                    self.player_logout(user_id);
+                }
+
+                self.sessions.clear();
+            },
+            ControlMessage::KickUid(_) | ControlMessage::NodeStarted(_) | ControlMessage::NodeStopping(_) | ControlMessage::NodeAlive(_) => {
+                println!("Unexpected {:?} from the gateway, ignoring", message);
+            },
+        };
+    }
+
+    fn process_message(&mut self, message: IpcMessage) {
+        let IpcMessage(packet_id, user_id, metadata, data) = message;
+
+        let packet = format!("{:?}", packet_id);
+        self.metrics.inc("samovar_packets_total", &[("packet", packet.as_str())]);
 
-            if (self.login_manager.is_supported(&packet_id)) {
-                self.login_manager.process(user_id, packet_id, metadata, data);
-            } else {
+        if !self.sessions.contains(&user_id) {
+            // Session was opened before we started, player's state is lost; client has to log in again
+            if self.kicked.insert(user_id) {
+                println!("Packet {:?} from user {} without a session, asking the gateway to kick them", packet_id, user_id);
+                self.send_control(ControlMessage::KickUid(user_id));
+            }
+
+            return;
+        }
+
+        let handler = match self.handlers.get(&packet_id) {
+            Some(handler) => *handler,
+            None => {
+                self.metrics.inc("samovar_unhandled_packets_total", &[("packet", packet.as_str())]);
+
+                // Client keeps sending lots of those, once is enough to know
+                if self.unhandled.insert(packet_id.clone()) {
+                    println!("No handler found for packet {:?}", packet_id);
+                }
+
+                return;
+            },
+        };
+
+        let processor: &mut PacketProcessor = match handler {
+            Handler::Login => &mut self.login_manager,
+            Handler::World => {
                 // TODO: each user_id will have a distinct world!
-                let world = match self.worlds.entry(user_id) {
+                match self.worlds.entry(user_id) {
                     Occupied(world) => world.into_mut(),
                     Vacant(entry) => {
-                        let world = GameWorld::new(self.database_manager.clone(),self.json_manager.clone()/*, self.packets_to_send_tx.clone()*/);
+                        let mut world = GameWorld::new(self.database_manager.clone(),self.json_manager.clone(), &self.node_config/*, self.packets_to_send_tx.clone()*/);
+                        world.use_middleware(self.middleware.clone());
                         entry.insert(world)
                     },
-                };
-
-                if world.is_supported(&packet_id) {
-                    world.process(user_id, packet_id.clone(), metadata.clone(), data.clone());
                 }
+            },
+            Handler::Processor(i) => self.processors[i].as_mut(),
+        };
 
-                for processor in self.processors.iter_mut() {
-                    if processor.is_supported(&packet_id) {
-                        processor.process(user_id, packet_id.clone(), metadata.clone(), data.clone());
-                    }
-                }
+        // Timing, logging and the rest is done by the middleware
+        processor.process(user_id, packet_id, metadata, data);
 
-                //println!("No handler found for packet {:#?}", packet_id);
+        self.metrics.set_gauge("samovar_active_players", &[], self.worlds.len() as f64);
+    }
+
+    /*
+      Gateway logs everyone out once told we're going down, so first we process whatever it sends us, for a limited time.
+      Players still online after that are saved right away.
+     */
+    fn shutdown(&mut self, packets_to_process_rx: &mpsc::Receiver<IpcEvent>) {
+        println!("Shutting down, draining incoming packets");
+
+        // Gateway disconnects our clients in response, their sessions are closed the usual way
+        self.send_control(ControlMessage::NodeStopping(self.node_config.game_node_id));
+
+        let deadline = Instant::now() + self.shutdown_timeout;
+
+        // Kicking everybody may take the gateway a while, so quiet periods don't mean it's done; only closed sessions do
+        while !self.sessions.is_empty() {
+            let now = Instant::now();
+
+            if now >= deadline {
+                println!("{} sessions weren't closed in time, saving them anyway", self.sessions.len());
+                break;
             }
+
+            match packets_to_process_rx.recv_timeout(deadline - now) {
+                Ok(event) => self.process_event(event),
+                Err(RecvTimeoutError::Timeout) => {},
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+        }
+
+        let players = self.entity_manager.online_players();
+
+        println!("Saving {} online players", players.len());
+
+        for user_id in players {
+            self.player_logout(user_id);
+        }
+
+        println!("Game server stopped");
+    }
+
+    fn send_control(&mut self, message: ControlMessage) {
+        match self.control_tx.send_control(&message) {
+            Ok(_) => {},
+            Err(e) => println!("Failed to send {:?}: {}", message, e),
+        };
+    }
+
+    /*
+      Managers have read their data at startup already, so only the settings consulted on the fly are affected:
+      shutdown timeout and whatever newly created worlds use.
+     */
+    fn reload_config(&mut self) -> bool {
+        let node_config = match NodeConfig::load() {
+            Ok(node_config) => node_config,
+            Err(e) => {
+                println!("Failed to reload config, keeping the old one: {}", e);
+                return false;
+            },
+        };
+
+        if node_config.game_node_id != self.node_config.game_node_id {
+            println!("Game node ID can't change without a restart, keeping the old config");
+            return false;
         }
+
+        self.shutdown_timeout = Duration::from_secs(node_config.shutdown_timeout_secs);
+        self.node_config = node_config;
+
+        println!("Config reloaded");
+
+        return true;
+    }
+
+    fn player_logout(&mut self, user_id: u32) {
+        println!("Player {} logged out", user_id);
+
+        self.entity_manager.player_logged_out(user_id);
+        self.worlds.remove(&user_id);
     }
 }
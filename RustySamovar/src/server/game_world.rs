@@ -7,7 +7,8 @@ use prost::Message;
 
 use chrono::Datelike;
 
-use rs_ipc::{IpcMessage, PushSocket};
+use rs_ipc::{IpcMessage, PacketSink};
+use rs_nodeconf::NodeConfig;
 
 use crate::utils::{AvatarBuilder, Remapper};
 
@@ -32,6 +33,7 @@ macro_rules! collection {
 }
 
 #[packet_processor(
+    middleware,
     PingReq,
     EnterSceneReadyReq,
     SceneInitFinishReq,
@@ -41,20 +43,23 @@ macro_rules! collection {
 )]
 pub struct GameWorld {
     //packets_to_send_tx: mpsc::Sender<IpcMessage>,
-    packets_to_send_tx: PushSocket,
+    packets_to_send_tx: Box<dyn PacketSink>,
     db: Arc<DatabaseManager>,
     jm: Arc<JsonManager>,
 }
 
 impl GameWorld {
-    pub fn new(db: Arc<DatabaseManager>, jm: Arc<JsonManager>/*, packets_to_send_tx: mpsc::Sender<IpcMessage>*/) -> GameWorld {
-        let mut packets_to_send_tx = PushSocket::connect_tcp("127.0.0.1", 9014).unwrap();
+    pub fn new(db: Arc<DatabaseManager>, jm: Arc<JsonManager>, node_config: &NodeConfig/*, packets_to_send_tx: mpsc::Sender<IpcMessage>*/) -> GameWorld {
+        return GameWorld::with_sink(db, jm, Box::new(node_config.connect_out_queue().unwrap()));
+    }
 
+    pub fn with_sink(db: Arc<DatabaseManager>, jm: Arc<JsonManager>, packets_to_send_tx: Box<dyn PacketSink>) -> GameWorld {
         let mut gw = GameWorld {
             packets_to_send_tx: packets_to_send_tx,
             db: db.clone(),
             jm: jm.clone(),
             packet_callbacks: HashMap::new(),
+            middleware: Arc::new(MiddlewareChain::new()),
         };
 
         gw.register();
@@ -66,12 +71,12 @@ impl GameWorld {
         rsp.client_time = req.client_time;
     }
 
-    fn process_enter_scene_ready(&mut self, user_id: u32, metadata: &proto::PacketHead, req: &proto::EnterSceneReadyReq, rsp: &mut proto::EnterSceneReadyRsp) {
+    fn process_enter_scene_ready(&mut self, user_id: u32, metadata: &proto::PacketHead, req: &proto::EnterSceneReadyReq, rsp: &mut proto::EnterSceneReadyRsp) -> Result<(), HandlerError> {
         rsp.enter_scene_token = req.enter_scene_token;
 
         let current_scene_info = match self.db.get_player_scene_info(user_id) {
             Some(scene_info) => scene_info,
-            None => panic!("Scene info not found for user {}!", user_id),
+            None => return Err(HandlerError::not_found(&format!("Scene info for user {}", user_id))),
         };
 
         build_and_send!(self, user_id, metadata, EnterScenePeerNotify {
@@ -80,25 +85,27 @@ impl GameWorld {
             host_peer_id: 1, // TODO
             enter_scene_token: req.enter_scene_token, // TODO??
         });
+
+        return Ok(());
     }
 
-    fn process_scene_init_finish(&mut self, user_id: u32, metadata: &proto::PacketHead, req: &proto::SceneInitFinishReq, rsp: &mut proto::SceneInitFinishRsp) {
+    fn process_scene_init_finish(&mut self, user_id: u32, metadata: &proto::PacketHead, req: &proto::SceneInitFinishReq, rsp: &mut proto::SceneInitFinishRsp) -> Result<(), HandlerError> {
         let (current_avatar_guid, current_team_id) = match self.db.get_player_team_selection(user_id) {
             Some(team_selection) => (team_selection.avatar, team_selection.team),
-            None => panic!("Team selection info not found for user {}!", user_id),
+            None => return Err(HandlerError::not_found(&format!("Team selection info for user {}", user_id))),
         };
 
         let current_scene_info = match self.db.get_player_scene_info(user_id) {
             Some(scene_info) => scene_info,
-            None => panic!("Scene info not found for user {}!", user_id),
+            None => return Err(HandlerError::not_found(&format!("Scene info for user {}", user_id))),
         };
 
         let user = match self.db.get_player_info(user_id) {
             Some(user) => user,
-            None => panic!("User {} not found!", user_id),
+            None => return Err(HandlerError::not_found(&format!("User {}", user_id))),
         };
 
-        let props = self.db.get_player_props(user_id).unwrap_or_else(|| panic!("Failed to get properties for user {}!", user_id));
+        let props = self.db.get_player_props(user_id).ok_or_else(|| HandlerError::not_found(&format!("Properties of user {}", user_id)))?;
 
         let user_level = props[&(proto::PropType::PropPlayerLevel as u32)] as u32;
         let world_level = props[&(proto::PropType::PropPlayerWorldLevel as u32)] as u32;
@@ -200,30 +207,36 @@ impl GameWorld {
             weapon_entity_id: IdManager::get_entity_id_by_type_and_sub_id(&proto::ProtEntityType::ProtEntityWeapon, DatabaseManager::SPOOFED_WEAPON_ID),
             weapon_ability_info: Some(build!(AbilitySyncStateInfo {})),
             is_player_cur_avatar: true, // TODO
-            scene_entity_info: Some(self.spoof_scene_default_avatar(user_id)),
+            scene_entity_info: Some(self.spoof_scene_default_avatar(user_id)?),
             ability_control_block: Some(self.spoof_default_abilities()),
         });
         build_and_send!(self, user_id, metadata, SceneTeamUpdateNotify {
             scene_team_avatar_list: vec![scene_team_avatar],
         });
+
+        return Ok(());
     }
 
-    fn process_enter_scene_done(&mut self, user_id: u32, metadata: &proto::PacketHead, req: &proto::EnterSceneDoneReq, rsp: &mut proto::EnterSceneDoneRsp) {
+    fn process_enter_scene_done(&mut self, user_id: u32, metadata: &proto::PacketHead, req: &proto::EnterSceneDoneReq, rsp: &mut proto::EnterSceneDoneRsp) -> Result<(), HandlerError> {
         rsp.enter_scene_token = req.enter_scene_token;
 
         build_and_send!(self, user_id, metadata, SceneEntityAppearNotify {
-            entity_list: vec![self.spoof_scene_default_avatar(user_id)],
+            entity_list: vec![self.spoof_scene_default_avatar(user_id)?],
             appear_type: proto::VisionType::VisionBorn as i32, // TODO
         });
+
+        return Ok(());
     }
 
-    fn process_post_enter_scene(&self, user_id: u32, metadata: &proto::PacketHead, req: &proto::PostEnterSceneReq, rsp: &mut proto::PostEnterSceneRsp) {
+    fn process_post_enter_scene(&self, user_id: u32, metadata: &proto::PacketHead, req: &proto::PostEnterSceneReq, rsp: &mut proto::PostEnterSceneRsp) -> Result<(), HandlerError> {
         let current_scene_info = match self.db.get_player_scene_info(user_id) {
             Some(scene_info) => scene_info,
-            None => panic!("Scene info not found for user {}!", user_id),
+            None => return Err(HandlerError::not_found(&format!("Scene info for user {}", user_id))),
         };
 
         rsp.enter_scene_token = current_scene_info.scene_token;
+
+        return Ok(());
     }
 
     fn process_enter_world_area(&self, user_id: u32, metadata: &proto::PacketHead, req: &proto::EnterWorldAreaReq, rsp: &mut proto::EnterWorldAreaRsp) {
@@ -231,21 +244,21 @@ impl GameWorld {
         rsp.area_id = req.area_id;
     }
 
-    fn spoof_scene_default_avatar(&self, user_id: u32) -> proto::SceneEntityInfo {
-        let user = self.db.get_player_scene_info(user_id).unwrap_or_else(|| panic!("User info not found for user {}!", user_id));
+    fn spoof_scene_default_avatar(&self, user_id: u32) -> Result<proto::SceneEntityInfo, HandlerError> {
+        let user = self.db.get_player_scene_info(user_id).ok_or_else(|| HandlerError::not_found(&format!("Scene info for user {}", user_id)))?;
 
         let current_avatar_guid = match self.db.get_player_team_selection(user_id) {
             Some(team_selection) => team_selection.avatar,
-            None => panic!("Team selection info not found for user {}!", user_id),
+            None => return Err(HandlerError::not_found(&format!("Team selection info for user {}", user_id))),
         };
 
-        let avatar_info = self.db.get_avatar(current_avatar_guid).unwrap_or_else(|| panic!("Avatar info for avatar {} not found!", current_avatar_guid));
+        let avatar_info = self.db.get_avatar(current_avatar_guid).ok_or_else(|| HandlerError::not_found(&format!("Avatar {}", current_avatar_guid)))?;
 
         let avatar_info = AvatarBuilder::build_avatar_info(self.jm.clone(), self.db.clone(), &avatar_info);
 
-        let current_avatar_props = self.db.get_avatar_props(current_avatar_guid).unwrap_or_else(|| panic!("Properties not found for avatar {}!", current_avatar_guid));
+        let current_avatar_props = self.db.get_avatar_props(current_avatar_guid).ok_or_else(|| HandlerError::not_found(&format!("Properties of avatar {}", current_avatar_guid)))?;
 
-        let current_avatar_fight_props = self.db.get_avatar_fight_props(current_avatar_guid).unwrap_or_else(|| panic!("Fight props not found for avatar {}!", current_avatar_guid));
+        let current_avatar_fight_props = self.db.get_avatar_fight_props(current_avatar_guid).ok_or_else(|| HandlerError::not_found(&format!("Fight properties of avatar {}", current_avatar_guid)))?;
 
         let motion_info = build!(MotionInfo {
             pos: Some(proto::Vector {x: user.pos_x, y: user.pos_y, z: user.pos_z}),
@@ -303,7 +316,7 @@ impl GameWorld {
             })],
         });
 
-        return scene_entity_info;
+        return Ok(scene_entity_info);
     }
 
     fn spoof_default_abilities(&self) -> proto::AbilityControlBlock {
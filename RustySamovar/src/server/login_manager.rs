@@ -3,7 +3,7 @@ use std::collections::HashMap;
 
 use prost::Message;
 
-use rs_ipc::{IpcMessage, PushSocket};
+use rs_ipc::{IpcMessage, PacketSink};
 
 use packet_processor_macro::*;
 #[macro_use]
@@ -16,12 +16,13 @@ use crate::utils::{AvatarBuilder, IdManager, Remapper};
 use rs_utils::TimeManager;
 
 use crate::dbmanager::database_manager::AvatarInfo as DbAvatarInfo;
+use crate::dbmanager::database_manager::PlayerInfo;
 use crate::entitymanager::EntityManager;
 use rs_nodeconf::NodeConfig;
 
-#[packet_processor(PlayerLoginReq)]
+#[packet_processor(middleware, PlayerLoginReq)]
 pub struct LoginManager {
-    packets_to_send_tx: PushSocket,
+    packets_to_send_tx: Box<dyn PacketSink>,
     db: Arc<DatabaseManager>,
     jm: Arc<JsonManager>,
     em: Arc<EntityManager>,
@@ -29,9 +30,14 @@ pub struct LoginManager {
 
 impl LoginManager {
     pub fn new(db: Arc<DatabaseManager>, jm: Arc<JsonManager>, em: Arc<EntityManager>, node_config: &NodeConfig) -> LoginManager {
+        return Self::with_sink(db, jm, em, Box::new(node_config.connect_out_queue().unwrap()));
+    }
+
+    pub fn with_sink(db: Arc<DatabaseManager>, jm: Arc<JsonManager>, em: Arc<EntityManager>, packets_to_send_tx: Box<dyn PacketSink>) -> LoginManager {
         let mut lm = LoginManager {
             packet_callbacks: HashMap::new(),
-            packets_to_send_tx: node_config.connect_out_queue().unwrap(),
+            middleware: Arc::new(MiddlewareChain::new()),
+            packets_to_send_tx: packets_to_send_tx,
             db: db,
             jm: jm,
             em: em,
@@ -42,25 +48,25 @@ impl LoginManager {
         return lm;
     }
 
-    fn process_player_login(&mut self, user_id: u32, metadata: &proto::PacketHead, req: &proto::PlayerLoginReq, rsp: &mut proto::PlayerLoginRsp) {
+    fn process_player_login(&mut self, user_id: u32, metadata: &proto::PacketHead, req: &proto::PlayerLoginReq, rsp: &mut proto::PlayerLoginRsp) -> Result<(), HandlerError> {
         let user = match self.db.get_player_info(user_id) {
             Some(user) => user,
-            None => panic!("User {} not found!", user_id),
+            None => self.create_player(user_id)?,
         };
 
         let player_props = match self.db.get_player_props(user_id) {
             Some(props) => Remapper::remap(&props),
-            None => panic!("Props for user {} not found!", user_id),
+            None => return Err(HandlerError::not_found(&format!("Properties of user {}", user_id))),
         };
 
         let open_state = match self.db.get_open_state(user_id) {
             Some(state) => state,
-            None => panic!("Open state for user {} not found!", user_id),
+            None => return Err(HandlerError::not_found(&format!("Open state of user {}", user_id))),
         };
 
         let inventory = match self.db.get_inventory(user_id) {
             Some(inventory) => inventory,
-            None => panic!("Inventory for user {} not found!", user_id),
+            None => return Err(HandlerError::not_found(&format!("Inventory of user {}", user_id))),
         };
 
         let avatar_list = match self.db.get_avatars(user_id) {
@@ -68,19 +74,19 @@ impl LoginManager {
                 .into_iter()
                 .map(|a| AvatarBuilder::build_avatar_info(self.jm.clone(), self.db.clone(), &a))
                 .collect(),
-            None => panic!("Avatars for user {} not found!", user_id),
+            None => return Err(HandlerError::not_found(&format!("Avatars of user {}", user_id))),
         };
 
-        let team_map = self.retrieve_team_info(user_id);
+        let team_map = self.retrieve_team_info(user_id)?;
 
         let (current_avatar, current_team) = match self.db.get_player_team_selection(user_id) {
             Some(team_selection) => (team_selection.avatar, team_selection.team),
-            None => panic!("Team selection info for user {} not found!", user_id),
+            None => return Err(HandlerError::not_found(&format!("Team selection info for user {}", user_id))),
         };
 
         let scene_info = match self.db.get_player_scene_info(user_id) {
             Some(scene_info) => scene_info,
-            None => panic!("Scene info for user {} not found!", user_id),
+            None => return Err(HandlerError::not_found(&format!("Scene info for user {}", user_id))),
         };
 
         let world_level = player_props[&(proto::PropType::PropPlayerWorldLevel as u32)].val as u32;
@@ -121,17 +127,34 @@ impl LoginManager {
         let pos = luamanager::Vector {x: scene_info.pos_x, y: scene_info.pos_y, z: scene_info.pos_z};
 
         self.em.player_teleported(user_id, pos, scene_info.scene_id, scene_info.scene_token, &proto::EnterType::EnterSelf);
+
+        return Ok(());
+    }
+
+    // Account was created on the first login, so the player has to be made as well
+    fn create_player(&self, user_id: u32) -> Result<PlayerInfo, HandlerError> {
+        tracing::info!("Creating player data for new user {}", user_id);
+
+        match self.db.create_player(user_id) {
+            Ok(_) => {},
+            Err(e) => return Err(HandlerError::rejected(proto::Retcode::RetSvrError, &format!("Failed to create user {}: {}", user_id, e))),
+        };
+
+        match self.db.get_player_info(user_id) {
+            Some(user) => return Ok(user),
+            None => return Err(HandlerError::not_found(&format!("User {}", user_id))),
+        };
     }
 
-    fn retrieve_team_info(&self, user_id: u32) -> HashMap<u32, proto::AvatarTeam> {
+    fn retrieve_team_info(&self, user_id: u32) -> Result<HashMap<u32, proto::AvatarTeam>, HandlerError> {
         let player_teams = match self.db.get_player_teams(user_id) {
             Some(teams) => teams,
-            None => panic!("Teams for user {} not found!", user_id),
+            None => return Err(HandlerError::not_found(&format!("Teams of user {}", user_id))),
         };
 
         let player_teams_avatars = match self.db.get_player_teams_avatars(user_id) {
             Some(team_avatars) => team_avatars,
-            None => panic!("Team avatars for user {} not found!", user_id),
+            None => return Err(HandlerError::not_found(&format!("Team avatars of user {}", user_id))),
         };
 
         let mut team_map = HashMap::<u32, proto::AvatarTeam>::new();
@@ -145,6 +168,116 @@ impl LoginManager {
             team_map.insert(team.id.into(), at);
         };
 
-        return team_map;
+        return Ok(team_map);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::NaiveDate;
+    use sea_orm::IntoActiveModel;
+
+    use rs_ipc::CapturingSink;
+
+    use crate::LuaManager;
+    use crate::dbmanager::database_manager::{PlayerInfo, PlayerProp, SceneInfo, TeamSelectionInfo};
+
+    const USER_ID: u32 = 1337;
+    const SCENE_ID: u32 = 3;
+    const SCENE_TOKEN: u32 = 0x1234;
+
+    fn login_manager() -> (LoginManager, Arc<EntityManager>, CapturingSink) {
+        let jm = Arc::new(JsonManager::from_fixtures(&[]));
+        let db = Arc::new(DatabaseManager::in_memory(jm.clone()));
+
+        db.insert(PlayerInfo {
+            uid: USER_ID,
+            nick_name: "Samovar".to_string(),
+            signature: "".to_string(),
+            birthday: NaiveDate::from_ymd(2000, 1, 1),
+            namecard_id: 210051,
+            finish_achievement_num: 0,
+            tower_floor_index: 1,
+            tower_level_index: 1,
+            avatar_id: 10000007,
+        }.into_active_model()).unwrap();
+
+        db.insert(PlayerProp {
+            uid: USER_ID,
+            prop_id: proto::PropType::PropPlayerWorldLevel as u32,
+            prop_value: 2,
+        }.into_active_model()).unwrap();
+
+        db.insert(SceneInfo {
+            uid: USER_ID,
+            scene_id: SCENE_ID,
+            scene_token: SCENE_TOKEN,
+            pos_x: -3400.0,
+            pos_y: 233.0,
+            pos_z: -3427.6,
+        }.into_active_model()).unwrap();
+
+        db.insert(TeamSelectionInfo {
+            uid: USER_ID,
+            avatar: 0,
+            team: 1,
+        }.into_active_model()).unwrap();
+
+        let sink = CapturingSink::new();
+        let player_sink = sink.clone();
+        let em = Arc::new(EntityManager::with_sinks(Arc::new(LuaManager::empty()), jm.clone(), db.clone(), Box::new(move || Box::new(player_sink.clone()))));
+
+        return (LoginManager::with_sink(db, jm, em.clone(), Box::new(sink.clone())), em, sink);
+    }
+
+    fn login(lm: &mut LoginManager, user_id: u32) {
+        lm.process(user_id, proto::PacketId::PlayerLoginReq, proto::PacketHead::default().encode_to_vec(), proto::PlayerLoginReq::default().encode_to_vec());
+    }
+
+    #[test]
+    fn sends_player_data_and_enters_scene() {
+        let (mut lm, em, sink) = login_manager();
+
+        login(&mut lm, USER_ID);
+
+        let (user_id, rsp) = sink.single::<proto::PlayerLoginRsp>(proto::PacketId::PlayerLoginRsp);
+        assert_eq!(user_id, USER_ID);
+        assert_eq!(rsp.retcode, 0);
+
+        // Response comes last, once the client has everything it needs
+        assert_eq!(sink.packet_ids().last(), Some(&proto::PacketId::PlayerLoginRsp));
+
+        let (_, player_data) = sink.single::<proto::PlayerDataNotify>(proto::PacketId::PlayerDataNotify);
+        assert_eq!(player_data.nick_name, "Samovar");
+        assert_eq!(player_data.prop_map[&(proto::PropType::PropPlayerWorldLevel as u32)].val, 2);
+
+        let (_, avatar_data) = sink.single::<proto::AvatarDataNotify>(proto::PacketId::AvatarDataNotify);
+        assert_eq!(avatar_data.cur_avatar_team_id, 1);
+        assert!(avatar_data.avatar_list.is_empty());
+
+        let (_, enter_scene) = sink.single::<proto::PlayerEnterSceneNotify>(proto::PacketId::PlayerEnterSceneNotify);
+        assert_eq!(enter_scene.scene_id, SCENE_ID);
+        assert_eq!(enter_scene.enter_scene_token, SCENE_TOKEN);
+        assert_eq!(enter_scene.world_level, 2);
+        assert_eq!(enter_scene.r#type, proto::EnterType::EnterSelf as i32);
+
+        assert!(em.is_online(USER_ID));
+    }
+
+    // New players are made on the fly, but that needs the starter avatar's data, missing from the fixtures
+    #[test]
+    fn unknown_player_is_refused_without_starter_data() {
+        let (mut lm, em, sink) = login_manager();
+
+        login(&mut lm, USER_ID + 1);
+
+        let (user_id, rsp) = sink.single::<proto::PlayerLoginRsp>(proto::PacketId::PlayerLoginRsp);
+        assert_eq!(user_id, USER_ID + 1);
+        assert_eq!(rsp.retcode, proto::Retcode::RetSvrError as i32);
+
+        assert_eq!(sink.packet_ids(), vec![proto::PacketId::PlayerLoginRsp]);
+        assert!(!em.is_online(USER_ID + 1));
     }
 }
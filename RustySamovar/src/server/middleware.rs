@@ -0,0 +1,74 @@
+use std::sync::Arc;
+use std::time::Instant;
+
+use packet_processor::{HandlerError, Middleware, PacketContext};
+use rs_metrics::Metrics;
+
+use crate::entitymanager::EntityManager;
+
+// Events logged through tracing while handling a packet are attributed to the user and the packet; println! output isn't
+pub struct TracingMiddleware;
+
+impl Middleware for TracingMiddleware {
+    fn handle(&self, context: &PacketContext, next: &mut dyn FnMut() -> Result<(), HandlerError>) -> Result<(), HandlerError> {
+        let span = tracing::info_span!("packet", uid = context.user_id, packet = ?context.packet_id, processor = context.processor);
+        let _entered = span.enter();
+
+        return next();
+    }
+}
+
+pub struct TimingMiddleware {
+    metrics: Arc<Metrics>,
+}
+
+impl TimingMiddleware {
+    pub fn new(metrics: Arc<Metrics>) -> TimingMiddleware {
+        return TimingMiddleware {
+            metrics: metrics,
+        };
+    }
+}
+
+impl Middleware for TimingMiddleware {
+    fn handle(&self, context: &PacketContext, next: &mut dyn FnMut() -> Result<(), HandlerError>) -> Result<(), HandlerError> {
+        let started = Instant::now();
+        let result = next();
+
+        let packet = format!("{:?}", context.packet_id);
+        self.metrics.observe("samovar_handler_seconds", &[("processor", context.processor), ("packet", packet.as_str())], started.elapsed());
+
+        return result;
+    }
+}
+
+/*
+  Player has to finish logging in before doing anything in the world.
+  Login itself and pings are the only packets allowed before that.
+ */
+pub struct LoginGateMiddleware {
+    entity_manager: Arc<EntityManager>,
+}
+
+impl LoginGateMiddleware {
+    pub fn new(entity_manager: Arc<EntityManager>) -> LoginGateMiddleware {
+        return LoginGateMiddleware {
+            entity_manager: entity_manager,
+        };
+    }
+}
+
+impl Middleware for LoginGateMiddleware {
+    fn handle(&self, context: &PacketContext, next: &mut dyn FnMut() -> Result<(), HandlerError>) -> Result<(), HandlerError> {
+        match context.packet_id {
+            proto::PacketId::PlayerLoginReq | proto::PacketId::PingReq => return next(),
+            _ => {},
+        };
+
+        if !self.entity_manager.is_online(context.user_id) {
+            return Err(HandlerError::rejected(proto::Retcode::RetFail, "player isn't logged in"));
+        }
+
+        return next();
+    }
+}
@@ -1,7 +1,9 @@
 mod game_server;
 mod game_world;
 mod login_manager;
+mod middleware;
 
 pub use self::game_server::GameServer;
 pub use self::game_world::GameWorld;
 pub use self::login_manager::LoginManager;
+pub use self::middleware::{TracingMiddleware, TimingMiddleware, LoginGateMiddleware};
@@ -3,7 +3,7 @@ use std::thread;
 use std::collections::{HashMap, HashSet};
 use std::collections::hash_map::Entry::{Occupied, Vacant};
 
-use rs_ipc::{IpcMessage, PushSocket};
+use rs_ipc::{IpcMessage, PacketSink};
 
 use prost::Message;
 
@@ -23,10 +23,11 @@ use crate::luamanager::Vector;
 use rs_nodeconf::NodeConfig;
 
 #[packet_processor(
+middleware,
 CombatInvocationsNotify,
 )]
 pub struct EntitySubsystem {
-    packets_to_send_tx: PushSocket,
+    packets_to_send_tx: Box<dyn PacketSink>,
     lua_manager: Arc<LuaManager>,
     json_manager: Arc<JsonManager>,
     db_manager: Arc<DatabaseManager>,
@@ -35,9 +36,14 @@ pub struct EntitySubsystem {
 
 impl EntitySubsystem {
     pub fn new(lua_manager: Arc<LuaManager>, json_manager: Arc<JsonManager>, db_manager: Arc<DatabaseManager>, entity_manager: Arc<EntityManager>, node_config: &NodeConfig) -> EntitySubsystem {
+        return Self::with_sink(lua_manager, json_manager, db_manager, entity_manager, Box::new(node_config.connect_out_queue().unwrap()));
+    }
+
+    pub fn with_sink(lua_manager: Arc<LuaManager>, json_manager: Arc<JsonManager>, db_manager: Arc<DatabaseManager>, entity_manager: Arc<EntityManager>, packets_to_send_tx: Box<dyn PacketSink>) -> EntitySubsystem {
         let mut es = EntitySubsystem {
-            packets_to_send_tx: node_config.connect_out_queue().unwrap(),
+            packets_to_send_tx: packets_to_send_tx,
             packet_callbacks: HashMap::new(),
+            middleware: Arc::new(MiddlewareChain::new()),
             lua_manager: lua_manager,
             json_manager: json_manager,
             db_manager: db_manager,
@@ -82,7 +88,7 @@ impl EntitySubsystem {
 
     fn ih_default(&self, user_id: u32, metadata: &proto::PacketHead, invoke: &proto::CombatInvokeEntry) {
         // TODO: this handler is just a stub!
-        println!("Unhandled CIN invoke: {:?}", invoke);
+        tracing::info!("Unhandled CIN invoke: {:?}", invoke);
     }
 
     fn ih_entity_move(&self, user_id: u32, metadata: &proto::PacketHead, invoke: &proto::EntityMoveInfo) {
@@ -136,7 +142,7 @@ impl EntitySubsystem {
 
     fn fw_default(&mut self, user_id: u32, metadata: &proto::PacketHead, invoke: &proto::CombatInvokeEntry) {
         // TODO: this handler is just a stub!
-        println!("Unhandled CIN forward: {:?}", invoke);
+        tracing::info!("Unhandled CIN forward: {:?}", invoke);
     }
 
     fn fw_to_all(&mut self, user_id: u32, metadata: &proto::PacketHead, invoke: &proto::CombatInvokeEntry) {
@@ -1,6 +1,6 @@
 use std::sync::{Arc, mpsc};
 
-use rs_ipc::{IpcMessage, PushSocket};
+use rs_ipc::{IpcMessage, PacketSink};
 use crate::{DatabaseManager, JsonManager};
 
 #[macro_use]
@@ -8,15 +8,19 @@ use packet_processor::*;
 use rs_nodeconf::NodeConfig;
 
 pub struct InventorySubsystem {
-    packets_to_send_tx: PushSocket,
+    packets_to_send_tx: Box<dyn PacketSink>,
     db: Arc<DatabaseManager>,
     jm: Arc<JsonManager>,
 }
 
 impl InventorySubsystem {
     pub fn new(jm: Arc<JsonManager>, db: Arc<DatabaseManager>, node_config: &NodeConfig) -> Self {
+        return Self::with_sink(jm, db, Box::new(node_config.connect_out_queue().unwrap()));
+    }
+
+    pub fn with_sink(jm: Arc<JsonManager>, db: Arc<DatabaseManager>, packets_to_send_tx: Box<dyn PacketSink>) -> Self {
         Self {
-            packets_to_send_tx: node_config.connect_out_queue().unwrap(),
+            packets_to_send_tx: packets_to_send_tx,
             db: db.clone(),
             jm: jm.clone(),
         }
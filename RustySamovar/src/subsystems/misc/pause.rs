@@ -3,7 +3,7 @@ use std::thread;
 use std::collections::{HashMap, HashSet};
 use std::collections::hash_map::Entry::{Occupied, Vacant};
 
-use rs_ipc::{IpcMessage, PushSocket};
+use rs_ipc::{IpcMessage, PacketSink};
 
 use prost::Message;
 
@@ -20,17 +20,23 @@ use crate::utils::{IdManager};
 use rs_utils::TimeManager;
 
 #[packet_processor(
+middleware,
 PlayerSetPauseReq,
 )]
 pub struct PauseSubsystem {
-    packets_to_send_tx: PushSocket,
+    packets_to_send_tx: Box<dyn PacketSink>,
 }
 
 impl PauseSubsystem {
     pub fn new(node_config: &NodeConfig) -> Self {
+        return Self::with_sink(Box::new(node_config.connect_out_queue().unwrap()));
+    }
+
+    pub fn with_sink(packets_to_send_tx: Box<dyn PacketSink>) -> Self {
         let mut ps = Self {
-            packets_to_send_tx: node_config.connect_out_queue().unwrap(),
+            packets_to_send_tx: packets_to_send_tx,
             packet_callbacks: HashMap::new(),
+            middleware: Arc::new(MiddlewareChain::new()),
         };
 
         ps.register();
@@ -3,7 +3,7 @@ use std::thread;
 use std::collections::{HashMap, HashSet};
 use std::collections::hash_map::Entry::{Occupied, Vacant};
 
-use rs_ipc::{IpcMessage, PushSocket};
+use rs_ipc::{IpcMessage, PacketSink};
 
 use prost::Message;
 
@@ -20,19 +20,25 @@ use crate::utils::{IdManager};
 use rs_utils::TimeManager;
 
 #[packet_processor(
+middleware,
 GetSceneAreaReq,
 GetScenePointReq,
 )]
 pub struct SceneSubsystem {
-    packets_to_send_tx: PushSocket,
+    packets_to_send_tx: Box<dyn PacketSink>,
     db: Arc<DatabaseManager>,
 }
 
 impl SceneSubsystem {
     pub fn new(db: Arc<DatabaseManager>, node_config: &NodeConfig) -> Self {
+        return Self::with_sink(db, Box::new(node_config.connect_out_queue().unwrap()));
+    }
+
+    pub fn with_sink(db: Arc<DatabaseManager>, packets_to_send_tx: Box<dyn PacketSink>) -> Self {
         let mut scs = Self {
-            packets_to_send_tx: node_config.connect_out_queue().unwrap(),
+            packets_to_send_tx: packets_to_send_tx,
             packet_callbacks: HashMap::new(),
+            middleware: Arc::new(MiddlewareChain::new()),
             db: db,
         };
 
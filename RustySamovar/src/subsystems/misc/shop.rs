@@ -3,7 +3,7 @@ use std::thread;
 use std::collections::{HashMap, HashSet};
 use std::collections::hash_map::Entry::{Occupied, Vacant};
 
-use rs_ipc::{IpcMessage, PushSocket};
+use rs_ipc::{IpcMessage, PacketSink};
 
 use prost::Message;
 
@@ -21,11 +21,12 @@ use crate::utils::{IdManager};
 use rs_utils::TimeManager;
 
 #[packet_processor(
+middleware,
 GetShopReq,
 BuyGoodsReq,
 )]
 pub struct ShopSubsystem {
-    packets_to_send_tx: PushSocket,
+    packets_to_send_tx: Box<dyn PacketSink>,
     json_manager: Arc<JsonManager>,
     db_manager: Arc<DatabaseManager>,
     inventory: Mutex<InventorySubsystem>,
@@ -33,9 +34,14 @@ pub struct ShopSubsystem {
 
 impl ShopSubsystem {
     pub fn new(jm: Arc<JsonManager>, db: Arc<DatabaseManager>, inv: Mutex<InventorySubsystem>, node_config: &NodeConfig) -> Self {
+        return Self::with_sink(jm, db, inv, Box::new(node_config.connect_out_queue().unwrap()));
+    }
+
+    pub fn with_sink(jm: Arc<JsonManager>, db: Arc<DatabaseManager>, inv: Mutex<InventorySubsystem>, packets_to_send_tx: Box<dyn PacketSink>) -> Self {
         let mut ss = Self {
-            packets_to_send_tx: node_config.connect_out_queue().unwrap(),
+            packets_to_send_tx: packets_to_send_tx,
             packet_callbacks: HashMap::new(),
+            middleware: Arc::new(MiddlewareChain::new()),
             json_manager: jm.clone(),
             db_manager: db.clone(),
             inventory: inv,
@@ -46,7 +52,7 @@ impl ShopSubsystem {
         return ss;
     }
 
-    fn process_get_shop(&self, user_id: u32, metadata: &proto::PacketHead, req: &proto::GetShopReq, rsp: &mut proto::GetShopRsp) {
+    fn process_get_shop(&self, user_id: u32, metadata: &proto::PacketHead, req: &proto::GetShopReq, rsp: &mut proto::GetShopRsp) -> Result<(), HandlerError> {
         let fuck_you_borrow_checker: Vec<crate::jsonmanager::ShopGoods> = vec![];
 
         let shop_goods = self.json_manager.shop_goods.get(&req.shop_type).unwrap_or(&fuck_you_borrow_checker);
@@ -54,8 +60,9 @@ impl ShopSubsystem {
         // TODO: each item should have it's own refresh time!
         let next_refresh_time = TimeManager::timestamp() as u32 + 86400;
 
-        let player_level = self.db_manager.get_player_level(user_id).unwrap();
+        let player_level = self.db_manager.get_player_level(user_id).ok_or_else(|| HandlerError::not_found(&format!("Level of user {}", user_id)))?;
 
+        // Broken shop data fails the whole request instead of showing a shop with goods missing
         let goods = shop_goods.iter().filter_map(|item| {
             // If player's AR is too low or too high, then we don't even show this item to him
             if player_level >= item.min_show_level || player_level <= item.max_show_level.unwrap_or(99) {
@@ -63,15 +70,15 @@ impl ShopSubsystem {
                     Some(item_id) => item_id,
                     None => match item.rotate_id {
                         Some(rotate_id) => {
-                            let rotate = match self.json_manager.shop_rotate.get(&rotate_id) {
+                            let rotate = match self.json_manager.shop_rotate.get(&rotate_id).and_then(|rotate| rotate.first()) {
                                 Some(rotate) => rotate,
-                                None => panic!("Rotate {} not found!", rotate_id),
+                                None => return Some(Err(HandlerError::not_found(&format!("Rotate {} of goods {}", rotate_id, item.goods_id)))),
                             };
 
-                            rotate[0].item_id // TODO: should be rotated obviously!
+                            rotate.item_id // TODO: should be rotated obviously!
                         },
                         None => {
-                            panic!("Both item_id and rotate_id are empty for item {}!", item.goods_id)
+                            return Some(Err(HandlerError::not_found(&format!("Item of goods {} (both item_id and rotate_id are empty)", item.goods_id))));
                         }
                     }
                 };
@@ -104,20 +111,22 @@ impl ShopSubsystem {
 
                 // TODO: SubTabId / secondary_sheet_id is not filled by a server?
 
-                Some(good)
+                Some(Ok(good))
             } else {
                 None
             }
-        }).collect();
+        }).collect::<Result<Vec<proto::ShopGoods>, HandlerError>>()?;
 
         rsp.shop = Some(build!(Shop {
             shop_type: req.shop_type,
             goods_list: goods,
             next_refresh_time: next_refresh_time,
         }));
+
+        return Ok(());
     }
 
-    fn process_buy_goods(&mut self, user_id: u32, metadata: &proto::PacketHead, req: &proto::BuyGoodsReq, rsp: &mut proto::BuyGoodsRsp) {
+    fn process_buy_goods(&mut self, user_id: u32, metadata: &proto::PacketHead, req: &proto::BuyGoodsReq, rsp: &mut proto::BuyGoodsRsp) -> Result<(), HandlerError> {
         // Buying goods can produce the following packets:
         // 1) Response packet
         // 2) AddHintNotify (to show nice graphical image to user)
@@ -129,7 +138,7 @@ impl ShopSubsystem {
         // Also, we don't have any 'state' yet, so we never gonna run "out of stock"
 
         // Retrieve goods in question
-        let mut good = req.goods.clone().unwrap();
+        let mut good = req.goods.clone().ok_or_else(|| HandlerError::invalid_request("no goods to buy"))?;
 
         good.bought_num = req.buy_count;
 
@@ -140,7 +149,7 @@ impl ShopSubsystem {
         rsp.goods = Some(good.clone());
         rsp.goods_list = vec![good.clone()];
 
-        let goods_item = good.goods_item.as_ref().unwrap();
+        let goods_item = good.goods_item.as_ref().ok_or_else(|| HandlerError::invalid_request("goods without an item"))?;
 
         let total_count = goods_item.count * req.buy_count;
 
@@ -151,6 +160,8 @@ impl ShopSubsystem {
 
         // TODO!
         //self.inventory.sub_item(user_id, metadata, good.goods_item.as_ref().unwrap().item_id, req.buy_count, &proto::ActionReasonType::ActionReasonShop);
+
+        return Ok(());
     }
 
     fn get_shop_refresh_time(&self, shop_type: u32, item_id: u32) -> u32 {
@@ -158,3 +169,126 @@ impl ShopSubsystem {
         (TimeManager::timestamp() + 86400) as u32
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use sea_orm::IntoActiveModel;
+
+    use rs_ipc::CapturingSink;
+
+    use crate::dbmanager::database_manager::PlayerProp;
+
+    const USER_ID: u32 = 1337;
+    const SHOP_TYPE: u32 = 1001;
+
+    const GOODS: &str = r#"[
+        {
+            "GoodsId": 100101, "ShopType": 1001, "ItemId": 104003, "ItemCount": 5,
+            "CostItems": [{"ItemId": 202, "Count": 100}, {}],
+            "BeginTime": "", "EndTime": "", "MinShowLevel": 1, "MaxShowLevel": 60, "SortLevel": 0,
+            "PlatformTypeList": [], "CostScoin": 100, "PreconditionParamList": []
+        }
+    ]"#;
+
+    // Rotated goods pointing at a rotation that doesn't exist
+    const BROKEN_GOODS: &str = r#"[
+        {
+            "GoodsId": 100102, "ShopType": 1001, "RotateId": 7, "ItemCount": 1, "CostItems": [],
+            "BeginTime": "", "EndTime": "", "MinShowLevel": 1, "SortLevel": 0,
+            "PlatformTypeList": [], "PreconditionParamList": []
+        }
+    ]"#;
+
+    fn shop(goods: &str) -> (ShopSubsystem, CapturingSink) {
+        let jm = Arc::new(JsonManager::from_fixtures(&[("ShopGoodsExcelConfigData", goods)]));
+        let db = Arc::new(DatabaseManager::in_memory(jm.clone()));
+
+        db.insert(PlayerProp {
+            uid: USER_ID,
+            prop_id: proto::PropType::PropPlayerLevel as u32,
+            prop_value: 30,
+        }.into_active_model()).unwrap();
+
+        let sink = CapturingSink::new();
+        let inv = InventorySubsystem::with_sink(jm.clone(), db.clone(), Box::new(sink.clone()));
+
+        return (ShopSubsystem::with_sink(jm, db, Mutex::new(inv), Box::new(sink.clone())), sink);
+    }
+
+    fn get_shop(shop: &mut ShopSubsystem, user_id: u32) {
+        let req = build!(GetShopReq { shop_type: SHOP_TYPE, });
+
+        shop.process(user_id, PacketId::GetShopReq, proto::PacketHead::default().encode_to_vec(), req.encode_to_vec());
+    }
+
+    #[test]
+    fn lists_goods_of_the_shop() {
+        let (mut shop, sink) = shop(GOODS);
+
+        get_shop(&mut shop, USER_ID);
+
+        let (user_id, rsp) = sink.single::<proto::GetShopRsp>(PacketId::GetShopRsp);
+        assert_eq!(user_id, USER_ID);
+        assert_eq!(rsp.retcode, 0);
+
+        let shop = rsp.shop.unwrap();
+        assert_eq!(shop.shop_type, SHOP_TYPE);
+        assert_eq!(shop.goods_list.len(), 1);
+
+        let goods = &shop.goods_list[0];
+        assert_eq!(goods.goods_id, 100101);
+        assert_eq!(goods.goods_item.as_ref().unwrap().item_id, 104003);
+        assert_eq!(goods.goods_item.as_ref().unwrap().count, 5);
+        assert_eq!(goods.scoin, 100);
+        // Empty cost entries are left out
+        assert_eq!(goods.cost_item_list.len(), 1);
+        assert_eq!(goods.cost_item_list[0].item_id, 202);
+    }
+
+    #[test]
+    fn unknown_shop_is_empty() {
+        let (mut shop, sink) = shop("[]");
+
+        get_shop(&mut shop, USER_ID);
+
+        let (_, rsp) = sink.single::<proto::GetShopRsp>(PacketId::GetShopRsp);
+        assert_eq!(rsp.retcode, 0);
+        assert!(rsp.shop.unwrap().goods_list.is_empty());
+    }
+
+    #[test]
+    fn broken_goods_fail_the_request() {
+        let (mut shop, sink) = shop(BROKEN_GOODS);
+
+        get_shop(&mut shop, USER_ID);
+
+        let (_, rsp) = sink.single::<proto::GetShopRsp>(PacketId::GetShopRsp);
+        assert_eq!(rsp.retcode, proto::Retcode::RetSvrError as i32);
+        assert!(rsp.shop.is_none());
+    }
+
+    #[test]
+    fn unknown_player_fails_the_request() {
+        let (mut shop, sink) = shop(GOODS);
+
+        get_shop(&mut shop, USER_ID + 1);
+
+        let (user_id, rsp) = sink.single::<proto::GetShopRsp>(PacketId::GetShopRsp);
+        assert_eq!(user_id, USER_ID + 1);
+        assert_eq!(rsp.retcode, proto::Retcode::RetSvrError as i32);
+    }
+
+    #[test]
+    fn buying_nothing_is_rejected() {
+        let (mut shop, sink) = shop(GOODS);
+
+        let req = build!(BuyGoodsReq { shop_type: SHOP_TYPE, buy_count: 1, });
+        shop.process(USER_ID, PacketId::BuyGoodsReq, proto::PacketHead::default().encode_to_vec(), req.encode_to_vec());
+
+        let (_, rsp) = sink.single::<proto::BuyGoodsRsp>(PacketId::BuyGoodsRsp);
+        assert_eq!(rsp.retcode, proto::Retcode::RetFail as i32);
+        assert!(rsp.goods.is_none());
+    }
+}
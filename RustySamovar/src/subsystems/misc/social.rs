@@ -3,7 +3,7 @@ use std::thread;
 use std::collections::{HashMap, HashSet};
 use std::collections::hash_map::Entry::{Occupied, Vacant};
 
-use rs_ipc::{IpcMessage, PushSocket};
+use rs_ipc::{IpcMessage, PacketSink};
 
 use chrono::Datelike;
 
@@ -22,20 +22,26 @@ use crate::utils::{IdManager};
 use rs_utils::TimeManager;
 
 #[packet_processor(
+middleware,
 GetPlayerBlacklistReq,
 GetPlayerFriendListReq,
 GetPlayerSocialDetailReq,
 )]
 pub struct SocialSubsystem {
-    packets_to_send_tx: PushSocket,
+    packets_to_send_tx: Box<dyn PacketSink>,
     db: Arc<DatabaseManager>,
 }
 
 impl SocialSubsystem {
     pub fn new(db: Arc<DatabaseManager>, node_config: &NodeConfig) -> Self {
+        return Self::with_sink(db, Box::new(node_config.connect_out_queue().unwrap()));
+    }
+
+    pub fn with_sink(db: Arc<DatabaseManager>, packets_to_send_tx: Box<dyn PacketSink>) -> Self {
         let mut socs = Self {
-            packets_to_send_tx: node_config.connect_out_queue().unwrap(),
+            packets_to_send_tx: packets_to_send_tx,
             packet_callbacks: HashMap::new(),
+            middleware: Arc::new(MiddlewareChain::new()),
             db: db.clone(),
         };
 
@@ -52,13 +58,13 @@ impl SocialSubsystem {
         // TODO!
     }
 
-    fn process_get_player_social_detail(&self, user_id: u32, metadata: &proto::PacketHead, req: &proto::GetPlayerSocialDetailReq, rsp: &mut proto::GetPlayerSocialDetailRsp) {
+    fn process_get_player_social_detail(&self, user_id: u32, metadata: &proto::PacketHead, req: &proto::GetPlayerSocialDetailReq, rsp: &mut proto::GetPlayerSocialDetailRsp) -> Result<(), HandlerError> {
         let user = match self.db.get_player_info(user_id) {
             Some(user) => user,
-            None => panic!("User {} not found!", user_id),
+            None => return Err(HandlerError::not_found(&format!("User {}", user_id))),
         };
 
-        let props = self.db.get_player_props(user_id).unwrap_or_else(|| panic!("Failed to get properties for user {}!", user_id));
+        let props = self.db.get_player_props(user_id).ok_or_else(|| HandlerError::not_found(&format!("Properties of user {}", user_id)))?;
 
         let user_level = props[&(proto::PropType::PropPlayerLevel as u32)] as u32;
         let world_level = props[&(proto::PropType::PropPlayerWorldLevel as u32)] as u32;
@@ -91,5 +97,7 @@ impl SocialSubsystem {
         });
 
         rsp.detail_data = Some(details);
+
+        return Ok(());
     }
 }
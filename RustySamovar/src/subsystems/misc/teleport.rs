@@ -3,7 +3,7 @@ use std::thread;
 use std::collections::{HashMap, HashSet};
 use std::collections::hash_map::Entry::{Occupied, Vacant};
 
-use rs_ipc::{IpcMessage, PushSocket};
+use rs_ipc::{IpcMessage, PacketSink};
 
 use prost::Message;
 
@@ -22,11 +22,12 @@ use crate::utils::{IdManager};
 use rs_utils::TimeManager;
 
 #[packet_processor(
+middleware,
 SceneTransToPointReq,
 UnlockTransPointReq,
 )]
 pub struct TeleportSubsystem {
-    packets_to_send_tx: PushSocket,
+    packets_to_send_tx: Box<dyn PacketSink>,
     jm: Arc<JsonManager>,
     em: Arc<EntityManager>,
     db: Arc<DatabaseManager>
@@ -34,9 +35,14 @@ pub struct TeleportSubsystem {
 
 impl TeleportSubsystem {
     pub fn new(jm: Arc<JsonManager>, db: Arc<DatabaseManager>, em: Arc<EntityManager>, node_config: &NodeConfig) -> Self {
+        return Self::with_sink(jm, db, em, Box::new(node_config.connect_out_queue().unwrap()));
+    }
+
+    pub fn with_sink(jm: Arc<JsonManager>, db: Arc<DatabaseManager>, em: Arc<EntityManager>, packets_to_send_tx: Box<dyn PacketSink>) -> Self {
         let mut nt = Self {
-            packets_to_send_tx: node_config.connect_out_queue().unwrap(),
+            packets_to_send_tx: packets_to_send_tx,
             packet_callbacks: HashMap::new(),
+            middleware: Arc::new(MiddlewareChain::new()),
             jm: jm,
             em: em,
             db: db,
@@ -47,7 +53,7 @@ impl TeleportSubsystem {
         return nt;
     }
 
-    fn process_scene_trans_to_point(&self, user_id: u32, metadata: &proto::PacketHead, req: &proto::SceneTransToPointReq, rsp: &mut proto::SceneTransToPointRsp) {
+    fn process_scene_trans_to_point(&self, user_id: u32, metadata: &proto::PacketHead, req: &proto::SceneTransToPointReq, rsp: &mut proto::SceneTransToPointRsp) -> Result<(), HandlerError> {
         let s_id = req.scene_id;
         let p_id = req.point_id;
 
@@ -65,7 +71,7 @@ impl TeleportSubsystem {
         let pos = match pos {
             Some(pos) => Vector {x: pos.x, y: pos.y, z: pos.z},
             None => {
-                println!("Warning: unknown TP point {}-{}, moving player to origin!", s_id, p_id);
+                tracing::warn!("Unknown TP point {}-{}, moving player to origin!", s_id, p_id);
                 Vector {x: 0.0, y: 500.0, z: 0.0}
             }
         };
@@ -73,10 +79,12 @@ impl TeleportSubsystem {
         // TODO: scene_token can probably be random?
         let scene_info = match self.db.get_player_scene_info(user_id) {
             Some(scene_info) => scene_info,
-            None => panic!("Scene info for user {} not found!", user_id),
+            None => return Err(HandlerError::not_found(&format!("Scene info for user {}", user_id))),
         };
 
         self.em.player_teleported(user_id, pos, s_id, scene_info.scene_token, &proto::EnterType::EnterGoto);
+
+        return Ok(());
     }
 
     pub fn process_unlock_trans_point(&mut self, user_id: u32, metadata: &proto::PacketHead, req: &proto::UnlockTransPointReq, rsp: &mut proto::UnlockTransPointRsp) {
@@ -93,3 +101,120 @@ impl TeleportSubsystem {
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use sea_orm::IntoActiveModel;
+
+    use rs_ipc::CapturingSink;
+
+    use crate::dbmanager::database_manager::{PlayerProp, SceneInfo};
+
+    const USER_ID: u32 = 1337;
+    const SCENE_ID: u32 = 3;
+    const SCENE_TOKEN: u32 = 0x1234;
+
+    const TELEPORT_POINTS: &str = r#"[
+        {"SceneId": 3, "PointId": 7, "X": 100.0, "Y": 200.0, "Z": 300.0, "Rotation": {}}
+    ]"#;
+
+    fn teleport() -> (TeleportSubsystem, Arc<DatabaseManager>, CapturingSink) {
+        let jm = Arc::new(JsonManager::from_fixtures(&[("TeleportPoints", TELEPORT_POINTS)]));
+        let db = Arc::new(DatabaseManager::in_memory(jm.clone()));
+
+        db.insert(PlayerProp {
+            uid: USER_ID,
+            prop_id: proto::PropType::PropPlayerWorldLevel as u32,
+            prop_value: 1,
+        }.into_active_model()).unwrap();
+
+        db.insert(SceneInfo {
+            uid: USER_ID,
+            scene_id: SCENE_ID,
+            scene_token: SCENE_TOKEN,
+            pos_x: 0.0,
+            pos_y: 0.0,
+            pos_z: 0.0,
+        }.into_active_model()).unwrap();
+
+        let sink = CapturingSink::new();
+        let player_sink = sink.clone();
+        let em = Arc::new(EntityManager::with_sinks(Arc::new(LuaManager::empty()), jm.clone(), db.clone(), Box::new(move || Box::new(player_sink.clone()))));
+
+        return (TeleportSubsystem::with_sink(jm, db.clone(), em, Box::new(sink.clone())), db, sink);
+    }
+
+    fn trans_to_point(teleport: &mut TeleportSubsystem, user_id: u32, point_id: u32) {
+        let req = build!(SceneTransToPointReq { scene_id: SCENE_ID, point_id: point_id, });
+
+        teleport.process(user_id, PacketId::SceneTransToPointReq, proto::PacketHead::default().encode_to_vec(), req.encode_to_vec());
+    }
+
+    #[test]
+    fn moves_player_to_the_point() {
+        let (mut teleport, db, sink) = teleport();
+
+        trans_to_point(&mut teleport, USER_ID, 7);
+
+        let (_, rsp) = sink.single::<proto::SceneTransToPointRsp>(PacketId::SceneTransToPointRsp);
+        assert_eq!(rsp.retcode, 0);
+        assert_eq!(rsp.scene_id, SCENE_ID);
+        assert_eq!(rsp.point_id, 7);
+
+        let (user_id, notify) = sink.single::<proto::PlayerEnterSceneNotify>(PacketId::PlayerEnterSceneNotify);
+        assert_eq!(user_id, USER_ID);
+        assert_eq!(notify.scene_id, SCENE_ID);
+        assert_eq!(notify.enter_scene_token, SCENE_TOKEN);
+        assert_eq!(notify.r#type, proto::EnterType::EnterGoto as i32);
+
+        let pos = notify.pos.unwrap();
+        assert_eq!((pos.x, pos.y, pos.z), (100.0, 200.0, 300.0));
+
+        let scene_info = db.get_player_scene_info(USER_ID).unwrap();
+        assert_eq!((scene_info.pos_x, scene_info.pos_y, scene_info.pos_z), (100.0, 200.0, 300.0));
+    }
+
+    #[test]
+    fn unknown_point_moves_player_to_the_origin() {
+        let (mut teleport, _, sink) = teleport();
+
+        trans_to_point(&mut teleport, USER_ID, 8);
+
+        let (_, rsp) = sink.single::<proto::SceneTransToPointRsp>(PacketId::SceneTransToPointRsp);
+        assert_eq!(rsp.retcode, 0);
+
+        let (_, notify) = sink.single::<proto::PlayerEnterSceneNotify>(PacketId::PlayerEnterSceneNotify);
+        let pos = notify.pos.unwrap();
+        assert_eq!((pos.x, pos.y, pos.z), (0.0, 500.0, 0.0));
+    }
+
+    #[test]
+    fn player_without_scene_stays_put() {
+        let (mut teleport, _, sink) = teleport();
+
+        trans_to_point(&mut teleport, USER_ID + 1, 7);
+
+        let (_, rsp) = sink.single::<proto::SceneTransToPointRsp>(PacketId::SceneTransToPointRsp);
+        assert_eq!(rsp.retcode, proto::Retcode::RetSvrError as i32);
+        assert!(sink.decoded::<proto::PlayerEnterSceneNotify>(PacketId::PlayerEnterSceneNotify).is_empty());
+    }
+
+    #[test]
+    fn unlocks_points() {
+        let (mut teleport, db, sink) = teleport();
+
+        let req = build!(UnlockTransPointReq { scene_id: SCENE_ID, point_id: 7, });
+        teleport.process(USER_ID, PacketId::UnlockTransPointReq, proto::PacketHead::default().encode_to_vec(), req.encode_to_vec());
+
+        let (_, notify) = sink.single::<proto::ScenePointUnlockNotify>(PacketId::ScenePointUnlockNotify);
+        assert_eq!(notify.scene_id, SCENE_ID);
+        assert_eq!(notify.point_list, vec![7]);
+
+        let (_, rsp) = sink.single::<proto::UnlockTransPointRsp>(PacketId::UnlockTransPointRsp);
+        assert_eq!(rsp.retcode, 0);
+
+        assert_eq!(db.get_scene_trans_points(USER_ID, SCENE_ID), vec![7]);
+    }
+}
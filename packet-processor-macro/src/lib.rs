@@ -22,6 +22,10 @@ pub fn packet_processor(args: TokenStream, input: TokenStream) -> TokenStream {
     let args = parse_macro_input!(args as AttributeArgs);
     //let input = parse_macro_input!(input as DeriveInput);
 
+    let args: Vec<String> = args.iter().map(|a| get_nested_meta_name(a)).collect();
+    // Processors opt into middleware with a `middleware` flag among the packet names
+    let with_middleware = args.iter().any(|a| a == "middleware");
+
     let mut found_struct = false;
     let mut struct_name = None;
 
@@ -39,8 +43,14 @@ pub fn packet_processor(args: TokenStream, input: TokenStream) -> TokenStream {
                 let mut stream = proc_macro::TokenStream::new();
 
                 stream.extend(
-                    vec![proc_macro::TokenStream::from(quote!(packet_callbacks: HashMap<proto::PacketId, fn(&mut Self, u32, &proto::PacketHead, Vec<u8>) -> ()>,))]
+                    vec![proc_macro::TokenStream::from(quote!(packet_callbacks: HashMap<proto::PacketId, fn(&mut Self, u32, Result<proto::PacketHead, packet_processor::HandlerError>, Vec<u8>, &packet_processor::MiddlewareChain) -> ()>,))]
                 );
+
+                if with_middleware {
+                    stream.extend(
+                        vec![proc_macro::TokenStream::from(quote!(middleware: std::sync::Arc<packet_processor::MiddlewareChain>,))]
+                    );
+                }
                 stream.extend(group.stream());
 
                 proc_macro::TokenTree::Group(
@@ -54,7 +64,6 @@ pub fn packet_processor(args: TokenStream, input: TokenStream) -> TokenStream {
         }
     }).collect();
 
-    let args = args.clone().into_iter().map(|a| get_nested_meta_name(&a));
     let re = Regex::new(r"Req$").unwrap();
 
     let request = args.clone().into_iter().filter(|a| a.ends_with("Req"));
@@ -74,8 +83,31 @@ pub fn packet_processor(args: TokenStream, input: TokenStream) -> TokenStream {
         Some(name) => name.clone().parse().unwrap(),
     };
 
+    let (use_middleware, chain) = if with_middleware {
+        (quote!(self.middleware = chain;), quote!(self.middleware.clone()))
+    } else {
+        (quote!(let _ = chain;), quote!(packet_processor::MiddlewareChain::empty()))
+    };
+
     let implementation = vec![proc_macro::TokenStream::from(quote!(
+        // Lets the server know what a processor handles without building one
+        impl #struct_name {
+            pub const PROCESSOR_NAME: &'static str = stringify!(#struct_name);
+
+            pub fn supported_packets() -> Vec<proto::PacketId> {
+                return vec![#(proto::PacketId::#request,)* #(proto::PacketId::#notify,)*];
+            }
+        }
+
         impl PacketProcessor for #struct_name {
+            fn name(&self) -> &'static str {
+                return Self::PROCESSOR_NAME;
+            }
+
+            fn use_middleware(&mut self, chain: std::sync::Arc<packet_processor::MiddlewareChain>) {
+                #use_middleware
+            }
+
             fn register(&mut self) {
                 let mut callbacks = &mut self.packet_callbacks;
                 #(register_callback!(callbacks, #request, #response, #req_handler);)*
@@ -92,11 +124,14 @@ pub fn packet_processor(args: TokenStream, input: TokenStream) -> TokenStream {
 
             fn process(&mut self, user_id: u32, packet_id: proto::PacketId, metadata: Vec<u8>, data: Vec<u8>) {
                 let callback = self.packet_callbacks.get(&packet_id);
-                let metadata = proto::PacketHead::decode(&mut std::io::Cursor::new(metadata)).unwrap();
+                // Callback reports a broken head the same way as a broken body, so requests still get their retcode
+                let metadata = proto::PacketHead::decode(&mut std::io::Cursor::new(metadata)).map_err(packet_processor::HandlerError::from);
+
+                let chain = #chain;
 
                 match callback {
-                    Some(callback) => callback(self, user_id, &metadata, data),
-                    None => println!("Unhandled packet {:?}", packet_id),
+                    Some(callback) => callback(self, user_id, metadata, data, &chain),
+                    None => packet_processor::tracing::warn!("Unhandled packet {:?}", packet_id),
                 }
             }
         }
@@ -0,0 +1,73 @@
+use std::fmt;
+
+/*
+  Reasons a packet handler may give up on a request.
+  Client still gets the response, empty one with the retcode set, so it doesn't hang waiting for it.
+ */
+#[derive(Debug)]
+pub enum HandlerError {
+    Decode(prost::DecodeError),             // Request itself is malformed
+    InvalidRequest(String),                 // Request decoded fine, but makes no sense
+    NotFound(String),                       // Something that has to be in the database isn't there
+    Rejected(proto::Retcode, String),       // Handler has a specific retcode in mind
+    Panicked(String),                       // Handler crashed, caught by PanicIsolationMiddleware
+}
+
+impl HandlerError {
+    pub fn invalid_request(reason: &str) -> HandlerError {
+        return HandlerError::InvalidRequest(reason.to_string());
+    }
+
+    pub fn not_found(what: &str) -> HandlerError {
+        return HandlerError::NotFound(what.to_string());
+    }
+
+    pub fn rejected(retcode: proto::Retcode, reason: &str) -> HandlerError {
+        return HandlerError::Rejected(retcode, reason.to_string());
+    }
+
+    pub fn retcode(&self) -> proto::Retcode {
+        match self {
+            HandlerError::Decode(_) => proto::Retcode::RetFail,
+            HandlerError::InvalidRequest(_) => proto::Retcode::RetFail,
+            HandlerError::NotFound(_) => proto::Retcode::RetSvrError,
+            HandlerError::Rejected(retcode, _) => *retcode,
+            HandlerError::Panicked(_) => proto::Retcode::RetSvrError,
+        }
+    }
+}
+
+impl fmt::Display for HandlerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HandlerError::Decode(e) => write!(f, "malformed request: {}", e),
+            HandlerError::InvalidRequest(reason) => write!(f, "invalid request: {}", reason),
+            HandlerError::NotFound(what) => write!(f, "{} not found", what),
+            HandlerError::Rejected(retcode, reason) => write!(f, "rejected with {:?}: {}", retcode, reason),
+            HandlerError::Panicked(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl From<prost::DecodeError> for HandlerError {
+    fn from(error: prost::DecodeError) -> HandlerError {
+        return HandlerError::Decode(error);
+    }
+}
+
+// Lets handlers that can't fail keep returning nothing
+pub trait IntoHandlerResult {
+    fn into_handler_result(self) -> Result<(), HandlerError>;
+}
+
+impl IntoHandlerResult for () {
+    fn into_handler_result(self) -> Result<(), HandlerError> {
+        return Ok(());
+    }
+}
+
+impl IntoHandlerResult for Result<(), HandlerError> {
+    fn into_handler_result(self) -> Result<(), HandlerError> {
+        return self;
+    }
+}
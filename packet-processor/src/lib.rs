@@ -1,32 +1,99 @@
+mod handler_error;
+mod middleware;
+
+pub use handler_error::{HandlerError, IntoHandlerResult};
+pub use middleware::{PacketContext, Middleware, MiddlewareChain, LoggingMiddleware, PanicIsolationMiddleware};
+
+// Macros below log through it, so crates using them don't need a dependency of their own
+pub use tracing;
+
 pub trait PacketProcessor {
+    fn name(&self) -> &'static str;
     fn register(&mut self);
+    // Only processors declared with `middleware` flag of the attribute take it, the rest ignore it
+    fn use_middleware(&mut self, chain: std::sync::Arc<MiddlewareChain>);
     fn supported(&self) -> Vec<proto::PacketId>;
     fn is_supported(&self, packet_id: &proto::PacketId) -> bool;
     fn process(&mut self, user_id: u32, packet_id: proto::PacketId, metadata: Vec<u8>, data: Vec<u8>);
 }
 
+/*
+  Handlers may return either nothing or Result<(), HandlerError>, and are called through the middleware chain given.
+  Failed request still gets a response: an empty one, with the retcode telling the client what went wrong.
+  That includes requests whose head failed to decode, although their response can't carry the sequence ID then.
+ */
 #[macro_export]
 macro_rules! register_callback {
     ($hashmap:ident, $req:ident, $rsp:ident, $handler:ident) => {
-        $hashmap.insert(proto::PacketId::$req, |slef: &mut Self, user_id: u32, metadata: &proto::PacketHead, data: Vec<u8>| {
-            let req = proto::$req::decode(&mut std::io::Cursor::new(data)).unwrap();
+        $hashmap.insert(proto::PacketId::$req, |slef: &mut Self, user_id: u32, metadata: Result<proto::PacketHead, $crate::HandlerError>, data: Vec<u8>, chain: &$crate::MiddlewareChain| {
             let mut rsp = proto::$rsp::default();
 
-            println!("Received REQ {:?}", req);
+            let (metadata, result) = match metadata {
+                Ok(metadata) => {
+                    let result = match proto::$req::decode(&mut std::io::Cursor::new(data)) {
+                        Ok(req) => {
+                            let context = $crate::PacketContext {
+                                processor: $crate::PacketProcessor::name(slef),
+                                user_id: user_id,
+                                packet_id: &proto::PacketId::$req,
+                                request: &req,
+                            };
+
+                            chain.run(&context, &mut || $crate::IntoHandlerResult::into_handler_result(slef.$handler(user_id, &metadata, &req, &mut rsp)))
+                        },
+                        Err(e) => Err($crate::HandlerError::from(e)),
+                    };
 
-            slef.$handler(user_id, &metadata, &req, &mut rsp);
+                    (metadata, result)
+                },
+                Err(e) => (proto::PacketHead::default(), Err(e)),
+            };
 
-            let message = IpcMessage::new_from_proto(proto::PacketId::$rsp, user_id, metadata, &rsp);
-            slef.packets_to_send_tx.send(message).unwrap();
+            match result {
+                Ok(_) => {},
+                Err(e) => {
+                    $crate::tracing::warn!("Failed to process {} from user {}: {}", stringify!($req), user_id, e);
+
+                    rsp = proto::$rsp::default();
+                    rsp.retcode = e.retcode() as i32;
+                },
+            };
+
+            let message = IpcMessage::new_from_proto(proto::PacketId::$rsp, user_id, &metadata, &rsp);
+
+            match slef.packets_to_send_tx.send(message) {
+                Ok(_) => {},
+                Err(e) => $crate::tracing::warn!("Failed to send {} to user {}: {}", stringify!($rsp), user_id, e),
+            };
         });
     };
 
     ($hashmap:ident, $notify:ident, $handler:ident) => {
-        $hashmap.insert(proto::PacketId::$notify, |slef: &mut Self, user_id: u32, metadata: &proto::PacketHead, data: Vec<u8>| {
-            let notify = proto::$notify::decode(&mut std::io::Cursor::new(data)).unwrap();
-            println!("Received NOTIFY {:?}", notify);
+        $hashmap.insert(proto::PacketId::$notify, |slef: &mut Self, user_id: u32, metadata: Result<proto::PacketHead, $crate::HandlerError>, data: Vec<u8>, chain: &$crate::MiddlewareChain| {
+            let decoded = match metadata {
+                Ok(metadata) => proto::$notify::decode(&mut std::io::Cursor::new(data)).map(|notify| (metadata, notify)).map_err($crate::HandlerError::from),
+                Err(e) => Err(e),
+            };
+
+            let result = match decoded {
+                Ok((metadata, notify)) => {
+                    let context = $crate::PacketContext {
+                        processor: $crate::PacketProcessor::name(slef),
+                        user_id: user_id,
+                        packet_id: &proto::PacketId::$notify,
+                        request: &notify,
+                    };
+
+                    chain.run(&context, &mut || $crate::IntoHandlerResult::into_handler_result(slef.$handler(user_id, &metadata, &notify)))
+                },
+                Err(e) => Err(e),
+            };
 
-            slef.$handler(user_id, &metadata, &notify);
+            // Nobody to tell about it, notifies don't get responses
+            match result {
+                Ok(_) => {},
+                Err(e) => $crate::tracing::warn!("Failed to process {} from user {}: {}", stringify!($notify), user_id, e),
+            };
         });
     };
 }
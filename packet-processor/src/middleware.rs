@@ -0,0 +1,92 @@
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+
+use crate::HandlerError;
+
+// What middleware gets to know about the packet being handled
+pub struct PacketContext<'a> {
+    pub processor: &'static str,
+    pub user_id: u32,
+    pub packet_id: &'a proto::PacketId,
+    pub request: &'a dyn fmt::Debug,
+}
+
+/*
+  Wraps handler invocation: may do something before and after calling `next`, or not call it at all.
+  Error returned instead of calling the handler is reported to the client the same way handler's own errors are.
+ */
+pub trait Middleware: Send + Sync {
+    fn handle(&self, context: &PacketContext, next: &mut dyn FnMut() -> Result<(), HandlerError>) -> Result<(), HandlerError>;
+}
+
+// Middleware are called in the order they were added, the first one being the outermost
+#[derive(Default, Clone)]
+pub struct MiddlewareChain {
+    layers: Vec<Arc<dyn Middleware>>,
+}
+
+impl MiddlewareChain {
+    pub fn new() -> MiddlewareChain {
+        return MiddlewareChain::default();
+    }
+
+    // Shared by processors without middleware, so that they don't build a chain for every packet
+    pub fn empty() -> &'static MiddlewareChain {
+        static EMPTY: MiddlewareChain = MiddlewareChain { layers: Vec::new() };
+
+        return &EMPTY;
+    }
+
+    pub fn with<M: Middleware + 'static>(mut self, middleware: M) -> MiddlewareChain {
+        self.layers.push(Arc::new(middleware));
+        return self;
+    }
+
+    pub fn run(&self, context: &PacketContext, handler: &mut dyn FnMut() -> Result<(), HandlerError>) -> Result<(), HandlerError> {
+        return self.run_from(0, context, handler);
+    }
+
+    fn run_from(&self, index: usize, context: &PacketContext, handler: &mut dyn FnMut() -> Result<(), HandlerError>) -> Result<(), HandlerError> {
+        match self.layers.get(index) {
+            Some(layer) => return layer.handle(context, &mut || self.run_from(index + 1, context, handler)),
+            None => return handler(),
+        };
+    }
+}
+
+pub struct LoggingMiddleware;
+
+impl Middleware for LoggingMiddleware {
+    fn handle(&self, context: &PacketContext, next: &mut dyn FnMut() -> Result<(), HandlerError>) -> Result<(), HandlerError> {
+        tracing::info!("Received {:?} from user {}: {:?}", context.packet_id, context.user_id, context.request);
+
+        return next();
+    }
+}
+
+/*
+  Turns handler's panic into an error, so that a single bad packet doesn't take the whole server down.
+  Panic message is still printed by the panic hook.
+  Mutexes the handler held are left poisoned, so state shared with the rest of the server has to recover them.
+ */
+pub struct PanicIsolationMiddleware;
+
+impl Middleware for PanicIsolationMiddleware {
+    fn handle(&self, context: &PacketContext, next: &mut dyn FnMut() -> Result<(), HandlerError>) -> Result<(), HandlerError> {
+        match panic::catch_unwind(AssertUnwindSafe(|| next())) {
+            Ok(result) => return result,
+            Err(payload) => {
+                let message = match payload.downcast_ref::<&str>() {
+                    Some(message) => message.to_string(),
+                    None => match payload.downcast_ref::<String>() {
+                        Some(message) => message.clone(),
+                        None => "unknown panic".to_string(),
+                    },
+                };
+
+                return Err(HandlerError::Panicked(format!("{} panicked on {:?}: {}", context.processor, context.packet_id, message)));
+            },
+        };
+    }
+}
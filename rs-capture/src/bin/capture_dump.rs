@@ -0,0 +1,30 @@
+use std::env;
+
+use rs_capture::{CaptureReader, BodyDecoders};
+
+// Prints every record of the given capture files as a line of JSON
+fn main() {
+    let decoders = BodyDecoders::server();
+
+    for filename in env::args().skip(1) {
+        let reader = match CaptureReader::open(&filename) {
+            Ok(reader) => reader,
+            Err(e) => {
+                eprintln!("Failed to open {}: {}", filename, e);
+                continue;
+            },
+        };
+
+        for record in reader {
+            let json = record.and_then(|record| record.to_json(&decoders));
+
+            match json {
+                Ok(json) => println!("{}", json),
+                Err(e) => {
+                    eprintln!("Failed to read {}: {}", filename, e);
+                    break;
+                },
+            };
+        }
+    }
+}
@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+use std::io::Cursor;
+
+use crate::CaptureError;
+
+type BodyDecoder = fn(&[u8]) -> Result<serde_json::Value, CaptureError>;
+
+/*
+  Knows which proto message hides behind which packet ID.
+  Only messages explicitly registered here are decoded to JSON, the rest are left raw (base64).
+
+  Coverage is partial on purpose: `proto` only gives us the PacketId enum and the message structs, with nothing tying one to the other,
+  and not every ID has a message of the same name. So the table can't be generated from `proto` as it is; instead it lists
+  everything our servers actually receive or send. Add packets to `server` when processors start handling or sending them.
+ */
+pub struct BodyDecoders {
+    decoders: HashMap<proto::PacketId, BodyDecoder>,
+}
+
+#[macro_export]
+macro_rules! register_decoders {
+    ($decoders:ident, $($id:ident),* $(,)?) => {
+        $( $decoders.register::<proto::$id>(proto::PacketId::$id); )*
+    };
+}
+
+impl BodyDecoders {
+    pub fn new() -> BodyDecoders {
+        return BodyDecoders {
+            decoders: HashMap::new(),
+        };
+    }
+
+    // Decoders for login sequence, which is what most of the captures are about
+    pub fn login() -> BodyDecoders {
+        let mut decoders = BodyDecoders::new();
+
+        register_decoders!(decoders,
+            GetPlayerTokenReq, GetPlayerTokenRsp,
+            PlayerLoginReq, PlayerLoginRsp,
+            PingReq, PingRsp,
+            PlayerLogoutReq,
+        );
+
+        return decoders;
+    }
+
+    // Login sequence plus every packet game server handles or sends
+    pub fn server() -> BodyDecoders {
+        let mut decoders = BodyDecoders::login();
+
+        register_decoders!(decoders,
+            // Requests handled by processors and their responses
+            BuyGoodsReq, BuyGoodsRsp,
+            EnterSceneDoneReq, EnterSceneDoneRsp,
+            EnterSceneReadyReq, EnterSceneReadyRsp,
+            EnterWorldAreaReq, EnterWorldAreaRsp,
+            GetPlayerBlacklistReq, GetPlayerBlacklistRsp,
+            GetPlayerFriendListReq, GetPlayerFriendListRsp,
+            GetPlayerSocialDetailReq, GetPlayerSocialDetailRsp,
+            GetSceneAreaReq, GetSceneAreaRsp,
+            GetScenePointReq, GetScenePointRsp,
+            GetShopReq, GetShopRsp,
+            NpcTalkReq, NpcTalkRsp,
+            PlayerSetPauseReq, PlayerSetPauseRsp,
+            PostEnterSceneReq, PostEnterSceneRsp,
+            SceneInitFinishReq, SceneInitFinishRsp,
+            SceneTransToPointReq, SceneTransToPointRsp,
+            UnlockTransPointReq, UnlockTransPointRsp,
+            // Notifies
+            AvatarDataNotify, CombatInvocationsNotify, CoopDataNotify,
+            EnterScenePeerNotify, HostPlayerNotify, ItemAddHintNotify,
+            OpenStateUpdateNotify, PlayerDataNotify, PlayerEnterSceneInfoNotify,
+            PlayerEnterSceneNotify, PlayerGameTimeNotify, PlayerStoreNotify,
+            SceneDataNotify, SceneEntityAppearNotify, SceneEntityDisappearNotify,
+            ScenePlayerInfoNotify, ScenePointUnlockNotify, SceneTeamUpdateNotify,
+            SceneTimeNotify, StoreItemChangeNotify, StoreItemDelNotify,
+            StoreWeightLimitNotify, UnionCmdNotify, WorldDataNotify,
+            WorldPlayerInfoNotify,
+        );
+
+        return decoders;
+    }
+
+    pub fn register<M: prost::Message + Default + serde::Serialize>(&mut self, packet_id: proto::PacketId) {
+        self.decoders.insert(packet_id, BodyDecoders::decode_message::<M>);
+    }
+
+    pub fn decode(&self, packet_id: &proto::PacketId, data: &[u8]) -> Option<Result<serde_json::Value, CaptureError>> {
+        return self.decoders.get(packet_id).map(|decoder| decoder(data));
+    }
+
+    fn decode_message<M: prost::Message + Default + serde::Serialize>(data: &[u8]) -> Result<serde_json::Value, CaptureError> {
+        let message = M::decode(&mut Cursor::new(data)).map_err(CaptureError::Decode)?;
+
+        return serde_json::to_value(&message).map_err(CaptureError::Json);
+    }
+}
@@ -0,0 +1,9 @@
+mod record;
+mod writer;
+mod reader;
+mod decoders;
+
+pub use record::{CaptureRecord, Direction, CaptureError};
+pub use writer::CaptureWriter;
+pub use reader::CaptureReader;
+pub use decoders::BodyDecoders;
@@ -0,0 +1,43 @@
+use std::fs::File;
+use std::io::{BufReader, Read};
+
+use crate::{CaptureRecord, CaptureError, CaptureWriter};
+
+pub struct CaptureReader {
+    file: BufReader<File>,
+}
+
+impl CaptureReader {
+    pub fn open(filename: &str) -> Result<CaptureReader, CaptureError> {
+        let mut file = BufReader::new(File::open(filename)?);
+
+        let mut header = [0u8; 6];
+        file.read_exact(&mut header)?;
+
+        if &header[0..4] != CaptureWriter::MAGIC {
+            return Err(CaptureError::BadMagic);
+        }
+
+        let version = u16::from_le_bytes([header[4], header[5]]);
+
+        if version != CaptureWriter::VERSION {
+            return Err(CaptureError::UnsupportedVersion(version));
+        }
+
+        return Ok(CaptureReader {
+            file: file,
+        });
+    }
+
+    pub fn read_all(&mut self) -> Result<Vec<CaptureRecord>, CaptureError> {
+        return self.collect();
+    }
+}
+
+impl Iterator for CaptureReader {
+    type Item = Result<CaptureRecord, CaptureError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        return CaptureRecord::read_from(&mut self.file).transpose();
+    }
+}
@@ -0,0 +1,265 @@
+use std::fmt;
+use std::io;
+use std::io::{Cursor, Read};
+use std::convert::TryInto;
+
+use num_traits::FromPrimitive;
+use prost::Message;
+
+use crate::BodyDecoders;
+
+#[derive(Debug)]
+pub enum CaptureError {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion(u16),
+    UnknownDirection(u8),
+    UnknownPacketId(u16),
+    Decode(prost::DecodeError),
+    Json(serde_json::Error),
+}
+
+impl fmt::Display for CaptureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CaptureError::Io(e) => write!(f, "I/O error: {}", e),
+            CaptureError::BadMagic => write!(f, "Not a capture file"),
+            CaptureError::UnsupportedVersion(v) => write!(f, "Unsupported capture version {}", v),
+            CaptureError::UnknownDirection(d) => write!(f, "Unknown direction {}", d),
+            CaptureError::UnknownPacketId(id) => write!(f, "Unknown packet ID {}", id),
+            CaptureError::Decode(e) => write!(f, "Failed to decode message: {}", e),
+            CaptureError::Json(e) => write!(f, "Failed to convert message to JSON: {}", e),
+        }
+    }
+}
+
+impl From<io::Error> for CaptureError {
+    fn from(e: io::Error) -> CaptureError {
+        return CaptureError::Io(e);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    ClientToServer = 0,
+    ServerToClient = 1,
+}
+
+/*
+  Single decrypted packet as seen by the gateway.
+  Packet ID is the canonical one, and UnionCmdNotify is already unpacked into separate records.
+
+  Binary layout (all integers are LE):
+    timestamp: u64, direction: u8, conv: u32, user_id: u32, packet_id: u16,
+    metadata_len: u32, data_len: u32, metadata, data
+ */
+#[derive(Debug, Clone)]
+pub struct CaptureRecord {
+    pub timestamp: u64,
+    pub direction: Direction,
+    pub conv: u32,
+    pub user_id: u32,
+    pub packet_id: proto::PacketId,
+    pub metadata: Vec<u8>,
+    pub data: Vec<u8>,
+}
+
+impl CaptureRecord {
+    pub fn head(&self) -> Result<proto::PacketHead, CaptureError> {
+        return proto::PacketHead::decode(&mut Cursor::new(&self.metadata)).map_err(CaptureError::Decode);
+    }
+
+    pub fn decode_body<M: prost::Message + Default>(&self) -> Result<M, CaptureError> {
+        return M::decode(&mut Cursor::new(&self.data)).map_err(CaptureError::Decode);
+    }
+
+    // Bodies of packets the decoders don't know about are dumped as base64
+    pub fn to_json(&self, decoders: &BodyDecoders) -> Result<serde_json::Value, CaptureError> {
+        let head = serde_json::to_value(&self.head()?).map_err(CaptureError::Json)?;
+
+        let body = match decoders.decode(&self.packet_id, &self.data) {
+            Some(body) => body?,
+            None => serde_json::json!({ "raw": base64::encode(&self.data) }),
+        };
+
+        return Ok(serde_json::json!({
+            "timestamp": self.timestamp,
+            "direction": format!("{:?}", self.direction),
+            "conv": self.conv,
+            "user_id": self.user_id,
+            "packet_id": format!("{:?}", self.packet_id),
+            "head": head,
+            "body": body,
+        }));
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data: Vec<u8> = vec![];
+
+        data.extend_from_slice(&self.timestamp.to_le_bytes());
+        data.push(self.direction as u8);
+        data.extend_from_slice(&self.conv.to_le_bytes());
+        data.extend_from_slice(&self.user_id.to_le_bytes());
+        data.extend_from_slice(&(self.packet_id.clone() as u16).to_le_bytes());
+        data.extend_from_slice(&(self.metadata.len() as u32).to_le_bytes());
+        data.extend_from_slice(&(self.data.len() as u32).to_le_bytes());
+
+        data.extend_from_slice(&self.metadata);
+
+        data.extend_from_slice(&self.data);
+
+        return data;
+    }
+
+    // Returns None if the stream ended right before the record
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Option<CaptureRecord>, CaptureError> {
+        let mut header = [0u8; 27];
+
+        if reader.read(&mut header[..1])? == 0 {
+            return Ok(None);
+        }
+
+        reader.read_exact(&mut header[1..])?;
+
+        let timestamp = u64::from_le_bytes(header[0..8].try_into().unwrap());
+
+        let direction = match header[8] {
+            0 => Direction::ClientToServer,
+            1 => Direction::ServerToClient,
+            d => return Err(CaptureError::UnknownDirection(d)),
+        };
+
+        let conv = u32::from_le_bytes(header[9..13].try_into().unwrap());
+        let user_id = u32::from_le_bytes(header[13..17].try_into().unwrap());
+        let packet_id = u16::from_le_bytes(header[17..19].try_into().unwrap());
+        let metadata_len = u32::from_le_bytes(header[19..23].try_into().unwrap());
+        let data_len = u32::from_le_bytes(header[23..27].try_into().unwrap());
+
+        let mut metadata = vec![0u8; metadata_len as usize];
+        reader.read_exact(&mut metadata)?;
+
+        let mut data = vec![0u8; data_len as usize];
+        reader.read_exact(&mut data)?;
+
+        let packet_id = match FromPrimitive::from_u16(packet_id) {
+            Some(packet_id) => packet_id,
+            None => return Err(CaptureError::UnknownPacketId(packet_id)),
+        };
+
+        return Ok(Some(CaptureRecord {
+            timestamp: timestamp,
+            direction: direction,
+            conv: conv,
+            user_id: user_id,
+            packet_id: packet_id,
+            metadata: metadata,
+            data: data,
+        }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use proptest::prelude::*;
+    use proto::PacketId;
+
+    fn packet_ids() -> impl Strategy<Value = PacketId> {
+        return prop::sample::select(vec![
+            PacketId::GetPlayerTokenReq, PacketId::GetPlayerTokenRsp,
+            PacketId::PlayerLoginReq, PacketId::PlayerLoginRsp,
+            PacketId::PingReq, PacketId::PingRsp,
+            PacketId::UnionCmdNotify,
+        ]);
+    }
+
+    fn directions() -> impl Strategy<Value = Direction> {
+        return prop::sample::select(vec![Direction::ClientToServer, Direction::ServerToClient]);
+    }
+
+    fn records() -> impl Strategy<Value = CaptureRecord> {
+        return (any::<u64>(), directions(), any::<u32>(), any::<u32>(), packet_ids(), prop::collection::vec(any::<u8>(), 0..256), prop::collection::vec(any::<u8>(), 0..1024))
+            .prop_map(|(timestamp, direction, conv, user_id, packet_id, metadata, data)| CaptureRecord {
+                timestamp: timestamp,
+                direction: direction,
+                conv: conv,
+                user_id: user_id,
+                packet_id: packet_id,
+                metadata: metadata,
+                data: data,
+            });
+    }
+
+    fn assert_same(decoded: &CaptureRecord, record: &CaptureRecord) -> Result<(), TestCaseError> {
+        prop_assert_eq!(decoded.timestamp, record.timestamp);
+        prop_assert_eq!(decoded.direction, record.direction);
+        prop_assert_eq!(decoded.conv, record.conv);
+        prop_assert_eq!(decoded.user_id, record.user_id);
+        prop_assert_eq!(&decoded.packet_id, &record.packet_id);
+        prop_assert_eq!(&decoded.metadata, &record.metadata);
+        prop_assert_eq!(&decoded.data, &record.data);
+
+        return Ok(());
+    }
+
+    proptest! {
+        #[test]
+        fn round_trips(record in records()) {
+            let bytes = record.to_bytes();
+            let mut reader = Cursor::new(&bytes);
+
+            let decoded = CaptureRecord::read_from(&mut reader).unwrap().unwrap();
+
+            assert_same(&decoded, &record)?;
+            prop_assert_eq!(reader.position() as usize, bytes.len());
+        }
+
+        #[test]
+        fn stops_at_end_of_last_record(records in prop::collection::vec(records(), 0..8)) {
+            let bytes: Vec<u8> = records.iter().flat_map(|record| record.to_bytes()).collect();
+            let mut reader = Cursor::new(&bytes);
+
+            for record in records.iter() {
+                assert_same(&CaptureRecord::read_from(&mut reader).unwrap().unwrap(), record)?;
+            }
+
+            prop_assert!(CaptureRecord::read_from(&mut reader).unwrap().is_none());
+        }
+
+        #[test]
+        fn rejects_truncated_records(record in records(), cut in any::<prop::sample::Index>()) {
+            let bytes = record.to_bytes();
+            let len = cut.index(bytes.len());
+
+            match CaptureRecord::read_from(&mut Cursor::new(&bytes[..len])) {
+                Ok(None) => prop_assert_eq!(len, 0),
+                Err(CaptureError::Io(e)) => prop_assert!(len > 0 && e.kind() == io::ErrorKind::UnexpectedEof),
+                Err(e) => prop_assert!(false, "unexpected error {:?}", e),
+                Ok(Some(_)) => prop_assert!(false, "truncated record of {} bytes out of {} decoded", len, bytes.len()),
+            };
+        }
+
+        #[test]
+        fn rejects_unknown_directions(record in records(), direction in 2u8..) {
+            let mut bytes = record.to_bytes();
+            bytes[8] = direction;
+
+            match CaptureRecord::read_from(&mut Cursor::new(&bytes)) {
+                Err(CaptureError::UnknownDirection(d)) => prop_assert_eq!(d, direction),
+                other => prop_assert!(false, "unexpected result {:?}", other),
+            };
+        }
+
+        #[test]
+        fn rejects_unknown_packet_ids(record in records(), packet_id in any::<u16>().prop_filter("known packet ID", |id| PacketId::from_u16(*id).is_none())) {
+            let mut bytes = record.to_bytes();
+            bytes[17..19].copy_from_slice(&packet_id.to_le_bytes());
+
+            match CaptureRecord::read_from(&mut Cursor::new(&bytes)) {
+                Err(CaptureError::UnknownPacketId(id)) => prop_assert_eq!(id, packet_id),
+                other => prop_assert!(false, "unexpected result {:?}", other),
+            };
+        }
+    }
+}
@@ -0,0 +1,42 @@
+use std::fs;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use rs_utils::TimeManager;
+
+use crate::{CaptureRecord, CaptureError};
+
+pub struct CaptureWriter {
+    file: BufWriter<File>,
+}
+
+impl CaptureWriter {
+    pub const MAGIC: &'static [u8; 4] = b"RSCP";
+    pub const VERSION: u16 = 1;
+
+    pub fn create(filename: &str) -> Result<CaptureWriter, CaptureError> {
+        let mut file = BufWriter::new(File::create(filename)?);
+
+        file.write_all(CaptureWriter::MAGIC)?;
+        file.write_all(&CaptureWriter::VERSION.to_le_bytes())?;
+
+        return Ok(CaptureWriter {
+            file: file,
+        });
+    }
+
+    // Every session gets its own file, named after the moment it started
+    pub fn for_session(directory: &str, conv: u32) -> Result<CaptureWriter, CaptureError> {
+        fs::create_dir_all(directory)?;
+
+        return CaptureWriter::create(&format!("{}/{}_{:08x}.rscap", directory, TimeManager::timestamp(), conv));
+    }
+
+    pub fn write(&mut self, record: &CaptureRecord) -> Result<(), CaptureError> {
+        self.file.write_all(&record.to_bytes())?;
+        // Sessions tend to end abruptly, so don't keep anything in the buffer
+        self.file.flush()?;
+
+        return Ok(());
+    }
+}
@@ -0,0 +1,244 @@
+use std::convert::{TryFrom, TryInto};
+use std::time::SystemTime;
+
+use crate::ipc::{IpcMessage, IpcDecodeError};
+
+/*
+  Messages nodes send to each other about sessions and themselves, as opposed to game packets.
+  They travel over the same sockets under a topic of their own, which never clashes with packet topics.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum ControlMessage {
+    SessionOpened(u32),     // User ID that has just logged in
+    SessionClosed(u32),     // User ID whose client is gone
+    KickUid(u32),           // Ask the gateway to disconnect the client of this user
+    ReloadConfig,
+    ShuttingDown,
+    NodeStarted(u8),        // Game node with this ID is ready to take players
+    NodeStopping(u8),       // Game node with this ID is going down, its players have to go elsewhere
+    NodeAlive(u8),          // Heartbeat of the game node with this ID
+}
+
+// Anything that can come out of a socket
+pub enum IpcEvent {
+    Packet(IpcMessage),
+    Control(ControlMessage),
+}
+
+impl ControlMessage {
+    pub const TOPIC: &'static str = "ctrl";
+    const FORMAT_VERSION: u8 = 1;
+    const HEADER_SIZE: usize = 6;
+
+    const KIND_SESSION_OPENED: u8 = 1;
+    const KIND_SESSION_CLOSED: u8 = 2;
+    const KIND_KICK_UID: u8 = 3;
+    const KIND_RELOAD_CONFIG: u8 = 4;
+    const KIND_SHUTTING_DOWN: u8 = 5;
+    const KIND_NODE_STARTED: u8 = 6;
+    const KIND_NODE_STOPPING: u8 = 7;
+    const KIND_NODE_ALIVE: u8 = 8;
+
+    fn kind(&self) -> u8 {
+        match self {
+            ControlMessage::SessionOpened(_) => ControlMessage::KIND_SESSION_OPENED,
+            ControlMessage::SessionClosed(_) => ControlMessage::KIND_SESSION_CLOSED,
+            ControlMessage::KickUid(_) => ControlMessage::KIND_KICK_UID,
+            ControlMessage::ReloadConfig => ControlMessage::KIND_RELOAD_CONFIG,
+            ControlMessage::ShuttingDown => ControlMessage::KIND_SHUTTING_DOWN,
+            ControlMessage::NodeStarted(_) => ControlMessage::KIND_NODE_STARTED,
+            ControlMessage::NodeStopping(_) => ControlMessage::KIND_NODE_STOPPING,
+            ControlMessage::NodeAlive(_) => ControlMessage::KIND_NODE_ALIVE,
+        }
+    }
+
+    fn user_id(bytes: &[u8]) -> Result<u32, IpcDecodeError> {
+        match bytes.try_into() {
+            Ok(bytes) => return Ok(u32::from_le_bytes(bytes)),
+            Err(_) => return Err(IpcDecodeError::LengthMismatch { expected: ControlMessage::HEADER_SIZE + 4, actual: ControlMessage::HEADER_SIZE + bytes.len() }),
+        };
+    }
+
+    fn node_id(bytes: &[u8]) -> Result<u8, IpcDecodeError> {
+        match bytes {
+            [node] => return Ok(*node),
+            _ => return Err(IpcDecodeError::LengthMismatch { expected: ControlMessage::HEADER_SIZE + 1, actual: ControlMessage::HEADER_SIZE + bytes.len() }),
+        };
+    }
+
+    fn empty(bytes: &[u8], message: ControlMessage) -> Result<ControlMessage, IpcDecodeError> {
+        if !bytes.is_empty() {
+            return Err(IpcDecodeError::LengthMismatch { expected: ControlMessage::HEADER_SIZE, actual: ControlMessage::HEADER_SIZE + bytes.len() });
+        }
+
+        return Ok(message);
+    }
+}
+
+/*
+  Wire format:
+    4 bytes - "ctrl" topic
+    1 byte  - format version
+    1 byte  - message kind
+    payload - user ID for the messages about a user, node ID for the ones about a node, nothing for the rest
+ */
+impl From<&ControlMessage> for Vec<u8> {
+    fn from(input: &ControlMessage) -> Vec<u8> {
+        let mut data: Vec<u8> = vec![];
+
+        data.extend_from_slice(ControlMessage::TOPIC.as_bytes());
+        data.push(ControlMessage::FORMAT_VERSION);
+        data.push(input.kind());
+
+        match input {
+            ControlMessage::SessionOpened(user_id) |
+            ControlMessage::SessionClosed(user_id) |
+            ControlMessage::KickUid(user_id) => data.extend_from_slice(&user_id.to_le_bytes()),
+            ControlMessage::NodeStarted(node) |
+            ControlMessage::NodeStopping(node) |
+            ControlMessage::NodeAlive(node) => data.push(*node),
+            ControlMessage::ReloadConfig |
+            ControlMessage::ShuttingDown => {},
+        };
+
+        data
+    }
+}
+
+impl TryFrom<Vec<u8>> for ControlMessage {
+    type Error = IpcDecodeError;
+
+    fn try_from(input: Vec<u8>) -> Result<ControlMessage, IpcDecodeError> {
+        if input.len() < ControlMessage::HEADER_SIZE {
+            return Err(IpcDecodeError::TooShort(input.len()));
+        }
+
+        if !input.starts_with(ControlMessage::TOPIC.as_bytes()) {
+            return Err(IpcDecodeError::MalformedTopic);
+        }
+
+        if input[4] != ControlMessage::FORMAT_VERSION {
+            return Err(IpcDecodeError::UnsupportedVersion(input[4]));
+        }
+
+        let payload = &input[ControlMessage::HEADER_SIZE..];
+
+        match input[5] {
+            ControlMessage::KIND_SESSION_OPENED => return Ok(ControlMessage::SessionOpened(ControlMessage::user_id(payload)?)),
+            ControlMessage::KIND_SESSION_CLOSED => return Ok(ControlMessage::SessionClosed(ControlMessage::user_id(payload)?)),
+            ControlMessage::KIND_KICK_UID => return Ok(ControlMessage::KickUid(ControlMessage::user_id(payload)?)),
+            ControlMessage::KIND_RELOAD_CONFIG => return ControlMessage::empty(payload, ControlMessage::ReloadConfig),
+            ControlMessage::KIND_SHUTTING_DOWN => return ControlMessage::empty(payload, ControlMessage::ShuttingDown),
+            ControlMessage::KIND_NODE_STARTED => return Ok(ControlMessage::NodeStarted(ControlMessage::node_id(payload)?)),
+            ControlMessage::KIND_NODE_STOPPING => return Ok(ControlMessage::NodeStopping(ControlMessage::node_id(payload)?)),
+            ControlMessage::KIND_NODE_ALIVE => return Ok(ControlMessage::NodeAlive(ControlMessage::node_id(payload)?)),
+            kind => return Err(IpcDecodeError::UnknownControlKind(kind)),
+        };
+    }
+}
+
+impl TryFrom<Vec<u8>> for IpcEvent {
+    type Error = IpcDecodeError;
+
+    fn try_from(input: Vec<u8>) -> Result<IpcEvent, IpcDecodeError> {
+        return IpcEvent::decode_timed(input).map(|(event, _)| event);
+    }
+}
+
+impl IpcEvent {
+    // Also returns the time packets were sent at; control messages don't carry one
+    pub fn decode_timed(input: Vec<u8>) -> Result<(IpcEvent, Option<SystemTime>), IpcDecodeError> {
+        if input.starts_with(ControlMessage::TOPIC.as_bytes()) {
+            return Ok((IpcEvent::Control(ControlMessage::try_from(input)?), None));
+        }
+
+        let (message, sent_at) = IpcMessage::decode_timed(input)?;
+
+        return Ok((IpcEvent::Packet(message), Some(sent_at)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use proptest::prelude::*;
+
+    fn control_messages() -> impl Strategy<Value = ControlMessage> {
+        return prop_oneof![
+            any::<u32>().prop_map(ControlMessage::SessionOpened),
+            any::<u32>().prop_map(ControlMessage::SessionClosed),
+            any::<u32>().prop_map(ControlMessage::KickUid),
+            Just(ControlMessage::ReloadConfig),
+            Just(ControlMessage::ShuttingDown),
+            any::<u8>().prop_map(ControlMessage::NodeStarted),
+            any::<u8>().prop_map(ControlMessage::NodeStopping),
+            any::<u8>().prop_map(ControlMessage::NodeAlive),
+        ];
+    }
+
+    proptest! {
+        #[test]
+        fn round_trips(message in control_messages()) {
+            let frame: Vec<u8> = (&message).into();
+
+            prop_assert_eq!(ControlMessage::try_from(frame.clone()), Ok(message.clone()));
+
+            match IpcEvent::try_from(frame) {
+                Ok(IpcEvent::Control(decoded)) => prop_assert_eq!(decoded, message),
+                Ok(IpcEvent::Packet(_)) => prop_assert!(false, "control message decoded as a packet"),
+                Err(e) => prop_assert!(false, "failed to decode: {:?}", e),
+            };
+        }
+
+        #[test]
+        fn rejects_truncated_frames(message in control_messages(), cut in any::<prop::sample::Index>()) {
+            let frame: Vec<u8> = (&message).into();
+            let len = cut.index(frame.len());
+
+            match ControlMessage::try_from(frame[..len].to_vec()) {
+                Err(IpcDecodeError::TooShort(actual)) => prop_assert!(len < ControlMessage::HEADER_SIZE && actual == len),
+                Err(IpcDecodeError::LengthMismatch { expected, actual }) => prop_assert!(expected == frame.len() && actual == len),
+                Err(e) => prop_assert!(false, "unexpected error {:?}", e),
+                Ok(decoded) => prop_assert!(false, "truncated frame of {} bytes out of {} decoded as {:?}", len, frame.len(), decoded),
+            };
+        }
+
+        #[test]
+        fn rejects_trailing_bytes(message in control_messages(), extra in prop::collection::vec(any::<u8>(), 1..16)) {
+            let mut frame: Vec<u8> = (&message).into();
+            let expected = frame.len();
+            frame.extend_from_slice(&extra);
+
+            prop_assert_eq!(ControlMessage::try_from(frame.clone()), Err(IpcDecodeError::LengthMismatch { expected: expected, actual: frame.len() }));
+        }
+
+        #[test]
+        fn rejects_unsupported_versions(message in control_messages(), version in any::<u8>().prop_filter("supported version", |v| *v != ControlMessage::FORMAT_VERSION)) {
+            let mut frame: Vec<u8> = (&message).into();
+            frame[4] = version;
+
+            prop_assert_eq!(ControlMessage::try_from(frame), Err(IpcDecodeError::UnsupportedVersion(version)));
+        }
+
+        #[test]
+        fn rejects_unknown_kinds(kind in any::<u8>().prop_filter("known kind", |k| !(ControlMessage::KIND_SESSION_OPENED..=ControlMessage::KIND_NODE_ALIVE).contains(k)),
+                                 payload in prop::collection::vec(any::<u8>(), 0..8)) {
+            let mut frame: Vec<u8> = ControlMessage::TOPIC.as_bytes().to_vec();
+            frame.push(ControlMessage::FORMAT_VERSION);
+            frame.push(kind);
+            frame.extend_from_slice(&payload);
+
+            prop_assert_eq!(ControlMessage::try_from(frame), Err(IpcDecodeError::UnknownControlKind(kind)));
+        }
+
+        #[test]
+        fn never_panics_on_garbage(payload in prop::collection::vec(any::<u8>(), 0..64)) {
+            let mut frame: Vec<u8> = ControlMessage::TOPIC.as_bytes().to_vec();
+            frame.extend_from_slice(&payload);
+
+            let _ = ControlMessage::try_from(frame.clone());
+            let _ = IpcEvent::try_from(frame);
+        }
+    }
+}
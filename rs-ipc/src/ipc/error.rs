@@ -0,0 +1,34 @@
+use std::fmt;
+
+use zeromq::ZmqError;
+
+use crate::ipc::IpcDecodeError;
+
+#[derive(Debug)]
+pub enum IpcError {
+    Zmq(ZmqError),
+    InProc(String),
+    Decode(IpcDecodeError),
+}
+
+impl fmt::Display for IpcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IpcError::Zmq(e) => write!(f, "ZeroMQ error: {}", e),
+            IpcError::InProc(reason) => write!(f, "In-process transport error: {}", reason),
+            IpcError::Decode(e) => write!(f, "Malformed message: {}", e),
+        }
+    }
+}
+
+impl From<ZmqError> for IpcError {
+    fn from(e: ZmqError) -> IpcError {
+        return IpcError::Zmq(e);
+    }
+}
+
+impl From<IpcDecodeError> for IpcError {
+    fn from(e: IpcDecodeError) -> IpcError {
+        return IpcError::Decode(e);
+    }
+}
@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+use std::sync::{mpsc, Arc, Mutex, OnceLock};
+
+use crate::ipc::IpcError;
+
+/*
+  Channel-based stand-in for ZeroMQ, for nodes living in the same process.
+  Endpoints are registered by name, either side may come first, just like with ZeroMQ.
+  Frames are the very same bytes that would go over the wire, so topic filtering works the same way.
+ */
+
+type Frame = Vec<u8>;
+
+struct Subscription {
+    topics: Arc<Mutex<Vec<String>>>,
+    tx: mpsc::Sender<Frame>,
+}
+
+struct Hub {
+    bound: bool,
+    subscriptions: Vec<Subscription>,
+}
+
+struct Queue {
+    tx: mpsc::Sender<Frame>,
+    rx: Option<mpsc::Receiver<Frame>>,
+}
+
+fn hubs() -> &'static Mutex<HashMap<String, Arc<Mutex<Hub>>>> {
+    static HUBS: OnceLock<Mutex<HashMap<String, Arc<Mutex<Hub>>>>> = OnceLock::new();
+    return HUBS.get_or_init(|| Mutex::new(HashMap::new()));
+}
+
+fn queues() -> &'static Mutex<HashMap<String, Queue>> {
+    static QUEUES: OnceLock<Mutex<HashMap<String, Queue>>> = OnceLock::new();
+    return QUEUES.get_or_init(|| Mutex::new(HashMap::new()));
+}
+
+fn hub(name: &str) -> Arc<Mutex<Hub>> {
+    return hubs().lock().unwrap().entry(name.to_string()).or_insert_with(|| Arc::new(Mutex::new(Hub {
+        bound: false,
+        subscriptions: vec![],
+    }))).clone();
+}
+
+fn with_queue<T, F: FnOnce(&mut Queue) -> T>(name: &str, f: F) -> T {
+    let mut queues = queues().lock().unwrap();
+
+    let queue = queues.entry(name.to_string()).or_insert_with(|| {
+        let (tx, rx) = mpsc::channel();
+        Queue { tx: tx, rx: Some(rx) }
+    });
+
+    return f(queue);
+}
+
+fn closed(name: &str) -> IpcError {
+    return IpcError::InProc(format!("Endpoint {} is closed", name));
+}
+
+pub struct Publisher {
+    hub: Arc<Mutex<Hub>>,
+}
+
+impl Publisher {
+    pub fn bind(name: &str) -> Result<Publisher, IpcError> {
+        let hub = hub(name);
+
+        {
+            let mut state = hub.lock().unwrap();
+
+            if state.bound {
+                return Err(IpcError::InProc(format!("Endpoint {} is already bound", name)));
+            }
+
+            state.bound = true;
+        }
+
+        return Ok(Publisher { hub: hub });
+    }
+
+    pub fn send(&mut self, frame: Frame) {
+        let mut hub = self.hub.lock().unwrap();
+
+        // Subscribers that went away are forgotten, same as ZeroMQ drops messages nobody listens to
+        hub.subscriptions.retain(|subscription| {
+            let wanted = subscription.topics.lock().unwrap().iter().any(|topic| frame.starts_with(topic.as_bytes()));
+
+            if !wanted {
+                return true;
+            }
+
+            return subscription.tx.send(frame.clone()).is_ok();
+        });
+    }
+}
+
+impl Drop for Publisher {
+    fn drop(&mut self) {
+        self.hub.lock().unwrap().bound = false;
+    }
+}
+
+pub struct Subscriber {
+    name: String,
+    topics: Arc<Mutex<Vec<String>>>,
+    rx: mpsc::Receiver<Frame>,
+}
+
+impl Subscriber {
+    pub fn connect(name: &str) -> Subscriber {
+        let topics = Arc::new(Mutex::new(vec![]));
+        let (tx, rx) = mpsc::channel();
+
+        hub(name).lock().unwrap().subscriptions.push(Subscription {
+            topics: topics.clone(),
+            tx: tx,
+        });
+
+        return Subscriber {
+            name: name.to_string(),
+            topics: topics,
+            rx: rx,
+        };
+    }
+
+    pub fn subscribe(&mut self, topic: &str) {
+        self.topics.lock().unwrap().push(topic.to_string());
+    }
+
+    pub fn recv(&mut self) -> Result<Frame, IpcError> {
+        return self.rx.recv().map_err(|_| closed(&self.name));
+    }
+}
+
+pub struct Pusher {
+    name: String,
+    tx: mpsc::Sender<Frame>,
+}
+
+impl Pusher {
+    pub fn connect(name: &str) -> Pusher {
+        return Pusher {
+            name: name.to_string(),
+            tx: with_queue(name, |queue| queue.tx.clone()),
+        };
+    }
+
+    pub fn send(&mut self, frame: Frame) -> Result<(), IpcError> {
+        return self.tx.send(frame).map_err(|_| closed(&self.name));
+    }
+}
+
+pub struct Puller {
+    name: String,
+    rx: mpsc::Receiver<Frame>,
+}
+
+impl Puller {
+    pub fn bind(name: &str) -> Result<Puller, IpcError> {
+        let rx = match with_queue(name, |queue| queue.rx.take()) {
+            Some(rx) => rx,
+            None => return Err(IpcError::InProc(format!("Endpoint {} is already bound", name))),
+        };
+
+        return Ok(Puller {
+            name: name.to_string(),
+            rx: rx,
+        });
+    }
+
+    pub fn recv(&mut self) -> Result<Frame, IpcError> {
+        return self.rx.recv().map_err(|_| closed(&self.name));
+    }
+}
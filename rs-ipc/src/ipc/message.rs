@@ -1,4 +1,6 @@
-use std::convert::{From, TryInto};
+use std::convert::{From, TryFrom, TryInto};
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use prost::Message;
 use zeromq::ZmqMessage;
@@ -7,6 +9,9 @@ use num_traits::FromPrimitive;
 pub struct IpcMessage(pub proto::PacketId, pub u32, pub Vec<u8>, pub Vec<u8>);
 
 impl IpcMessage {
+    const FORMAT_VERSION: u8 = 2;
+    const HEADER_SIZE: usize = 25;
+
     pub fn new_from_proto<M: prost::Message>(packet_id: proto::PacketId, user_id: u32, metadata: &proto::PacketHead, data: &M) -> IpcMessage {
         println!("Replying with {:?}", packet_id);
         println!("Data: {:?}", data);
@@ -30,42 +35,61 @@ impl IpcMessage {
     pub fn format_topic(topic: proto::PacketId) -> String {
         format!("{:04x}", topic as u16)
     }
-}
 
-impl From<Vec<u8>> for IpcMessage {
-    fn from (input: Vec<u8>) -> IpcMessage {
-        let packet_id = String::from_utf8_lossy(&input[0..4]);
-        let packet_id = u16::from_str_radix(&packet_id, 16).unwrap();
-        //let packet_id = (((input[0] - 48) as u16) << 12) |  (((input[1] - 48) as u16) << 8) | (((input[2] - 48) as u16) << 4) | (((input[3] - 48) as u16) << 0);
-        let packet_id = FromPrimitive::from_u16(packet_id).unwrap(); // Should be 100% correct
+    /*
+      Time since the frame was sent, as far as the clocks of the two nodes agree.
+      Sender's clock being ahead of ours makes it zero instead of negative.
+     */
+    pub fn queue_latency(sent_at: SystemTime) -> Duration {
+        return SystemTime::now().duration_since(sent_at).unwrap_or(Duration::ZERO);
+    }
 
-        let user_id: u32 = u32::from_le_bytes(input[4..8].try_into().unwrap());
+    // Same as try_from(), but also returns the time the frame was sent at
+    pub fn decode_timed(input: Vec<u8>) -> Result<(IpcMessage, SystemTime), IpcDecodeError> {
+        if input.len() < IpcMessage::HEADER_SIZE {
+            return Err(IpcDecodeError::TooShort(input.len()));
+        }
 
-        let metadata_len: u32 = u32::from_le_bytes(input[8..12].try_into().unwrap());
-        let data_len: u32 = u32::from_le_bytes(input[12..16].try_into().unwrap());
+        let topic = std::str::from_utf8(&input[0..4]).map_err(|_| IpcDecodeError::MalformedTopic)?;
+        let packet_id = u16::from_str_radix(topic, 16).map_err(|_| IpcDecodeError::MalformedTopic)?;
 
-        let metadata = input[16..(metadata_len+16) as usize].to_owned();
-        let data = input[(metadata_len+16) as usize..(data_len+metadata_len+16) as usize].to_owned();
+        if input[4] != IpcMessage::FORMAT_VERSION {
+            return Err(IpcDecodeError::UnsupportedVersion(input[4]));
+        }
 
-        IpcMessage(packet_id, user_id, metadata, data)
-    }
-}
+        let packet_id = match FromPrimitive::from_u16(packet_id) {
+            Some(packet_id) => packet_id,
+            None => return Err(IpcDecodeError::UnknownPacketId(packet_id)),
+        };
 
-impl From<ZmqMessage> for IpcMessage {
-    fn from (input: ZmqMessage) -> IpcMessage {
-        // ZmqMessage::into_vec returns a vector of Bytes object
-        // We flat_map them into Vec<u8>
-        let input: Vec<u8> = input.into_vec().iter().flat_map(|b| b.to_vec()).collect();
+        // unwrap()s are fine, sizes are fixed and checked above
+        let sent_at = u64::from_le_bytes(input[5..13].try_into().unwrap());
+        let user_id = u32::from_le_bytes(input[13..17].try_into().unwrap());
+        let metadata_len = u32::from_le_bytes(input[17..21].try_into().unwrap()) as usize;
+        let data_len = u32::from_le_bytes(input[21..25].try_into().unwrap()) as usize;
 
-        input.into()
+        let expected = IpcMessage::HEADER_SIZE as u64 + metadata_len as u64 + data_len as u64;
+
+        if expected != input.len() as u64 {
+            return Err(IpcDecodeError::LengthMismatch { expected: expected as usize, actual: input.len() });
+        }
+
+        let metadata_end = IpcMessage::HEADER_SIZE + metadata_len;
+
+        let metadata = input[IpcMessage::HEADER_SIZE..metadata_end].to_owned();
+        let data = input[metadata_end..].to_owned();
+
+        return Ok((IpcMessage(packet_id, user_id, metadata, data), UNIX_EPOCH + Duration::from_micros(sent_at)));
     }
-}
 
-impl From<IpcMessage> for Vec<u8> {
-    fn from (input: IpcMessage) -> Vec<u8> {
+    fn encode_at(input: IpcMessage, sent_at: SystemTime) -> Vec<u8> {
+        let sent_at = sent_at.duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO).as_micros() as u64;
+
         let mut data: Vec<u8> = vec![];
 
         data.extend_from_slice(IpcMessage::format_topic(input.0).as_bytes());
+        data.push(IpcMessage::FORMAT_VERSION);
+        data.extend_from_slice(&sent_at.to_le_bytes());
         data.extend_from_slice(&input.1.to_le_bytes());
         data.extend_from_slice(&(input.2.len() as u32).to_le_bytes());
         data.extend_from_slice(&(input.3.len() as u32).to_le_bytes());
@@ -74,7 +98,67 @@ impl From<IpcMessage> for Vec<u8> {
 
         data.extend_from_slice(&input.3);
 
-        data
+        return data;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum IpcDecodeError {
+    TooShort(usize),
+    MalformedTopic,
+    UnsupportedVersion(u8),
+    UnknownPacketId(u16),
+    UnknownControlKind(u8),
+    LengthMismatch { expected: usize, actual: usize },
+}
+
+impl fmt::Display for IpcDecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IpcDecodeError::TooShort(len) => write!(f, "Frame of {} bytes is too short", len),
+            IpcDecodeError::MalformedTopic => write!(f, "Malformed topic"),
+            IpcDecodeError::UnsupportedVersion(version) => write!(f, "Unsupported format version {}", version),
+            IpcDecodeError::UnknownPacketId(packet_id) => write!(f, "Unknown packet ID {}", packet_id),
+            IpcDecodeError::UnknownControlKind(kind) => write!(f, "Unknown control message kind {}", kind),
+            IpcDecodeError::LengthMismatch { expected, actual } => write!(f, "Expected {} bytes, got {}", expected, actual),
+        }
+    }
+}
+
+/*
+  Wire format:
+    4 bytes  - packet ID as hex digits, doubles as the topic for subscriptions
+    1 byte   - format version
+    8 bytes  - time the frame was sent at, microseconds since the Unix epoch
+    4 bytes  - user ID
+    4 bytes  - metadata length
+    4 bytes  - data length
+    metadata, then data
+  All the numbers are little-endian.
+ */
+impl TryFrom<Vec<u8>> for IpcMessage {
+    type Error = IpcDecodeError;
+
+    fn try_from(input: Vec<u8>) -> Result<IpcMessage, IpcDecodeError> {
+        return IpcMessage::decode_timed(input).map(|(message, _)| message);
+    }
+}
+
+impl TryFrom<ZmqMessage> for IpcMessage {
+    type Error = IpcDecodeError;
+
+    fn try_from(input: ZmqMessage) -> Result<IpcMessage, IpcDecodeError> {
+        // ZmqMessage::into_vec returns a vector of Bytes object
+        // We flat_map them into Vec<u8>
+        let input: Vec<u8> = input.into_vec().iter().flat_map(|b| b.to_vec()).collect();
+
+        return input.try_into();
+    }
+}
+
+impl From<IpcMessage> for Vec<u8> {
+    fn from (input: IpcMessage) -> Vec<u8> {
+        IpcMessage::encode_at(input, SystemTime::now())
     }
 }
 
@@ -83,4 +167,109 @@ impl From<IpcMessage> for ZmqMessage {
         let input: Vec<u8> = input.into();
         input.into()
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use proptest::prelude::*;
+    use proto::PacketId;
+
+    fn packet_ids() -> impl Strategy<Value = PacketId> {
+        return prop::sample::select(vec![
+            PacketId::GetPlayerTokenReq, PacketId::GetPlayerTokenRsp,
+            PacketId::PlayerLoginReq, PacketId::PlayerLoginRsp,
+            PacketId::PingReq, PacketId::PingRsp,
+            PacketId::UnionCmdNotify,
+        ]);
+    }
+
+    fn messages() -> impl Strategy<Value = (PacketId, u32, Vec<u8>, Vec<u8>)> {
+        return (packet_ids(), any::<u32>(), prop::collection::vec(any::<u8>(), 0..256), prop::collection::vec(any::<u8>(), 0..1024));
+    }
+
+    fn encode((packet_id, user_id, metadata, data): &(PacketId, u32, Vec<u8>, Vec<u8>)) -> Vec<u8> {
+        return IpcMessage(*packet_id, *user_id, metadata.clone(), data.clone()).into();
+    }
+
+    proptest! {
+        #[test]
+        fn round_trips(message in messages()) {
+            let decoded = IpcMessage::try_from(encode(&message)).unwrap();
+
+            prop_assert_eq!(decoded.0, message.0);
+            prop_assert_eq!(decoded.1, message.1);
+            prop_assert_eq!(decoded.2, message.2);
+            prop_assert_eq!(decoded.3, message.3);
+        }
+
+        #[test]
+        fn carries_send_time(message in messages(), sent_at in 0u64..(1u64 << 53)) {
+            let sent_at = UNIX_EPOCH + Duration::from_micros(sent_at);
+            let (packet_id, user_id, metadata, data) = message;
+
+            let (_, decoded) = IpcMessage::decode_timed(IpcMessage::encode_at(IpcMessage(packet_id, user_id, metadata, data), sent_at)).unwrap();
+
+            prop_assert_eq!(decoded, sent_at);
+        }
+
+        #[test]
+        fn rejects_truncated_frames(message in messages(), cut in any::<prop::sample::Index>()) {
+            let frame = encode(&message);
+            let len = cut.index(frame.len());
+
+            match IpcMessage::try_from(frame[..len].to_vec()) {
+                Err(IpcDecodeError::TooShort(actual)) => prop_assert!(len < IpcMessage::HEADER_SIZE && actual == len),
+                Err(IpcDecodeError::LengthMismatch { expected, actual }) => prop_assert!(expected == frame.len() && actual == len),
+                Err(e) => prop_assert!(false, "unexpected error {:?}", e),
+                Ok(_) => prop_assert!(false, "truncated frame of {} bytes out of {} decoded", len, frame.len()),
+            };
+        }
+
+        #[test]
+        fn rejects_trailing_bytes(message in messages(), extra in prop::collection::vec(any::<u8>(), 1..64)) {
+            let mut frame = encode(&message);
+            let expected = frame.len();
+            frame.extend_from_slice(&extra);
+
+            prop_assert_eq!(IpcMessage::try_from(frame.clone()).err(), Some(IpcDecodeError::LengthMismatch { expected: expected, actual: frame.len() }));
+        }
+
+        #[test]
+        fn rejects_unsupported_versions(message in messages(), version in any::<u8>().prop_filter("supported version", |v| *v != IpcMessage::FORMAT_VERSION)) {
+            let mut frame = encode(&message);
+            frame[4] = version;
+
+            prop_assert_eq!(IpcMessage::try_from(frame).err(), Some(IpcDecodeError::UnsupportedVersion(version)));
+        }
+
+        #[test]
+        fn rejects_unknown_packet_ids(message in messages(), packet_id in any::<u16>().prop_filter("known packet ID", |id| PacketId::from_u16(*id).is_none())) {
+            let mut frame = encode(&message);
+            frame[0..4].copy_from_slice(format!("{:04x}", packet_id).as_bytes());
+
+            prop_assert_eq!(IpcMessage::try_from(frame).err(), Some(IpcDecodeError::UnknownPacketId(packet_id)));
+        }
+
+        #[test]
+        fn rejects_malformed_topics(message in messages(), topic in prop::collection::vec(any::<u8>(), 4)) {
+            prop_assume!(std::str::from_utf8(&topic).map(|t| u16::from_str_radix(t, 16).is_err()).unwrap_or(true));
+
+            let mut frame = encode(&message);
+            frame[0..4].copy_from_slice(&topic);
+
+            prop_assert_eq!(IpcMessage::try_from(frame).err(), Some(IpcDecodeError::MalformedTopic));
+        }
+
+        #[test]
+        fn never_panics_on_garbage(frame in prop::collection::vec(any::<u8>(), 0..512)) {
+            let _ = IpcMessage::try_from(frame);
+        }
+    }
+
+    #[test]
+    fn latency_is_never_negative() {
+        assert_eq!(IpcMessage::queue_latency(SystemTime::now() + Duration::from_secs(60)), Duration::ZERO);
+        assert!(IpcMessage::queue_latency(SystemTime::now() - Duration::from_secs(60)) >= Duration::from_secs(60));
+    }
+}
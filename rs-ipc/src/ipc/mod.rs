@@ -1,5 +1,13 @@
 mod message;
 mod socket;
+mod error;
+mod inproc;
+mod control;
+mod routing;
+mod sink;
 
-pub use message::IpcMessage;
-pub use socket::{SubSocket, PubSocket, PushSocket, PullSocket, Result};
\ No newline at end of file
+pub use message::{IpcMessage, IpcDecodeError};
+pub use socket::{SubSocket, PubSocket, PushSocket, PullSocket, Result};
+pub use error::IpcError;
+pub use control::{ControlMessage, IpcEvent};
+pub use sink::{PacketSink, CapturingSink};
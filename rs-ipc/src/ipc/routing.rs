@@ -0,0 +1,27 @@
+/*
+  Frames meant for a single game node are prefixed with its ID, so that every node subscribes to its own share only.
+  Prefix doesn't start with a hex digit, so it never clashes with packet topics, nor with the control one.
+ */
+const NODE_PREFIX: u8 = b'n';
+const NODE_PREFIX_SIZE: usize = 3;
+
+pub fn node_topic(node: u8) -> String {
+    return format!("{}{:02x}", NODE_PREFIX as char, node);
+}
+
+pub fn route(node: u8, frame: Vec<u8>) -> Vec<u8> {
+    let mut data = node_topic(node).into_bytes();
+
+    data.extend_from_slice(&frame);
+
+    return data;
+}
+
+// Subscriber has already filtered by the prefix, so it's just dropped
+pub fn unroute(mut frame: Vec<u8>) -> Vec<u8> {
+    if frame.len() >= NODE_PREFIX_SIZE && frame[0] == NODE_PREFIX {
+        frame.drain(..NODE_PREFIX_SIZE);
+    }
+
+    return frame;
+}
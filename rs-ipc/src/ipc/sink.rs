@@ -0,0 +1,100 @@
+use std::fmt::{Debug, Error, Formatter};
+use std::result::Result as StdResult;
+use std::sync::{Arc, Mutex};
+
+use prost::Message;
+
+use proto::PacketId;
+
+use crate::IpcMessage;
+use crate::ipc::{PushSocket, Result};
+
+/*
+  Anything packet processors can send their outbound packets into.
+  Normally it's a PushSocket connected to the out queue, but tests and tools can plug in something else.
+ */
+pub trait PacketSink: Send + Sync + Debug {
+    fn send(&mut self, message: IpcMessage) -> Result<()>;
+}
+
+impl PacketSink for PushSocket {
+    fn send(&mut self, message: IpcMessage) -> Result<()> {
+        return PushSocket::send(self, message);
+    }
+}
+
+/*
+  Sink that just records everything sent into it.
+  Clones share the same record, so one clone can be handed to the processor and the other kept for assertions.
+ */
+#[derive(Clone, Default)]
+pub struct CapturingSink {
+    sent: Arc<Mutex<Vec<IpcMessage>>>,
+}
+
+impl Debug for CapturingSink {
+    fn fmt(&self, f: &mut Formatter<'_>) -> StdResult<(), Error> {
+        return write!(f, "CapturingSink({} messages)", self.len());
+    }
+}
+
+impl CapturingSink {
+    pub fn new() -> CapturingSink {
+        return CapturingSink {
+            sent: Arc::new(Mutex::new(Vec::new())),
+        };
+    }
+
+    pub fn len(&self) -> usize {
+        return self.sent.lock().unwrap().len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.len() == 0;
+    }
+
+    pub fn clear(&self) {
+        self.sent.lock().unwrap().clear();
+    }
+
+    // Removes and returns everything captured so far
+    pub fn take(&self) -> Vec<IpcMessage> {
+        return self.sent.lock().unwrap().drain(..).collect();
+    }
+
+    // Packet IDs of everything captured so far, in the order they were sent
+    pub fn packet_ids(&self) -> Vec<PacketId> {
+        return self.sent.lock().unwrap().iter().map(|m| m.0).collect();
+    }
+
+    /*
+      Decodes every captured packet with the given ID into its proto message, paired with the receiving user ID.
+      Panics if the payload doesn't decode, as that means the sender built a wrong message for this ID.
+     */
+    pub fn decoded<M: Message + Default>(&self, packet_id: PacketId) -> Vec<(u32, M)> {
+        return self.sent.lock().unwrap().iter()
+            .filter(|m| m.0 == packet_id)
+            .map(|m| {
+                let message = M::decode(&m.3[..]).unwrap_or_else(|e| panic!("Failed to decode captured {:?}: {}", packet_id, e));
+                (m.1, message)
+            })
+            .collect();
+    }
+
+    // Same as above, but expects exactly one such packet
+    pub fn single<M: Message + Default>(&self, packet_id: PacketId) -> (u32, M) {
+        let mut decoded = self.decoded(packet_id);
+
+        match decoded.len() {
+            1 => return decoded.remove(0),
+            n => panic!("Expected exactly one {:?}, captured {}", packet_id, n),
+        };
+    }
+}
+
+impl PacketSink for CapturingSink {
+    fn send(&mut self, message: IpcMessage) -> Result<()> {
+        self.sent.lock().unwrap().push(message);
+        return Ok(());
+    }
+}
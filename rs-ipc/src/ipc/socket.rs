@@ -1,13 +1,17 @@
 use std::fmt::{Debug, Error, Formatter};
 use std::result::Result as StdResult;
+use std::time::Duration;
 
-use zeromq::{Socket, SocketRecv, SocketSend, ZmqResult};
+use zeromq::{Socket, SocketRecv, SocketSend, ZmqMessage};
 
 use proto::PacketId;
 
 use crate::IpcMessage;
+use crate::ipc::{ControlMessage, IpcEvent, IpcError};
+use crate::ipc::inproc;
+use crate::ipc::routing;
 
-pub type Result<T> = ZmqResult<T>;
+pub type Result<T> = StdResult<T, IpcError>;
 
 /*
   This is used to convert async operations into sync ones
@@ -26,24 +30,49 @@ impl<F,T> Block for F
 
 // -------------
 
+fn flatten(message: ZmqMessage) -> Vec<u8> {
+    // ZmqMessage::into_vec returns a vector of Bytes object
+    return message.into_vec().iter().flat_map(|b| b.to_vec()).collect();
+}
+
 // Socket for client subscription; can only receive data
 pub struct SubSocket {
-    socket: zeromq::SubSocket,
+    socket: SubTransport,
 }
 
 // Socket for server to publish the data; can only transmit data
 pub struct PubSocket {
-    socket: zeromq::PubSocket,
+    socket: PubTransport,
 }
 
 // Socket for pushing the data towards the receiver
 pub struct PushSocket {
-    socket: zeromq::PushSocket,
+    socket: PushTransport,
 }
 
 // Socket for pulling the data
 pub struct PullSocket {
-    socket: zeromq::PullSocket,
+    socket: PullTransport,
+}
+
+enum SubTransport {
+    Zmq(zeromq::SubSocket),
+    InProc(inproc::Subscriber),
+}
+
+enum PubTransport {
+    Zmq(zeromq::PubSocket),
+    InProc(inproc::Publisher),
+}
+
+enum PushTransport {
+    Zmq(zeromq::PushSocket),
+    InProc(inproc::Pusher),
+}
+
+enum PullTransport {
+    Zmq(zeromq::PullSocket),
+    InProc(inproc::Puller),
 }
 
 impl Debug for PushSocket {
@@ -54,116 +83,225 @@ impl Debug for PushSocket {
 
 impl SubSocket {
     pub fn connect_tcp(address: &str, port: u16) -> Result<Self> {
-        let mut socket = zeromq::SubSocket::new();
+        return SubSocket::connect_zmq(&format!("tcp://{}:{}", address, port));
+    }
 
-        socket.connect(&format!("tcp://{}:{}", address, port)).wait()?;
+    pub fn connect_unix(address: &str) -> Result<Self> {
+        return SubSocket::connect_zmq(&format!("ipc://{}", address));
+    }
 
+    pub fn connect_inproc(name: &str) -> Result<Self> {
         Ok(SubSocket {
-            socket: socket,
+            socket: SubTransport::InProc(inproc::Subscriber::connect(name)),
         })
     }
 
-    pub fn connect_unix(address: &str) -> Result<Self> {
+    fn connect_zmq(endpoint: &str) -> Result<Self> {
         let mut socket = zeromq::SubSocket::new();
 
-        socket.connect(&format!("ipc://{}", address)).wait()?;
+        socket.connect(endpoint).wait()?;
 
         Ok(SubSocket {
-            socket: socket,
+            socket: SubTransport::Zmq(socket),
         })
     }
 
     pub fn subscribe(&mut self, topics: Vec<PacketId>) -> Result<()> {
         for topic in topics {
-            self.socket.subscribe(&IpcMessage::format_topic(topic)).wait()?;
+            self.subscribe_topic(&IpcMessage::format_topic(topic))?;
         }
 
         Ok(())
     }
 
     pub fn subscribe_all(&mut self) -> Result<()> {
-        Ok(self.socket.subscribe("").wait()?)
+        self.subscribe_topic("")
+    }
+
+    pub fn subscribe_control(&mut self) -> Result<()> {
+        self.subscribe_topic(ControlMessage::TOPIC)
     }
 
+    // Packets and control messages sent to this particular game node with send_to()
+    pub fn subscribe_node(&mut self, node: u8) -> Result<()> {
+        self.subscribe_topic(&routing::node_topic(node))
+    }
+
+    fn subscribe_topic(&mut self, topic: &str) -> Result<()> {
+        match &mut self.socket {
+            SubTransport::Zmq(socket) => socket.subscribe(topic).wait()?,
+            SubTransport::InProc(socket) => socket.subscribe(topic),
+        };
+
+        Ok(())
+    }
+
+    // Control messages are skipped, use recv_event() to get those as well
     pub fn recv(&mut self) -> Result<IpcMessage> {
-        Ok(self.socket.recv().wait()?.into())
+        loop {
+            match self.recv_event()? {
+                IpcEvent::Packet(message) => return Ok(message),
+                IpcEvent::Control(_) => continue,
+            };
+        }
+    }
+
+    pub fn recv_event(&mut self) -> Result<IpcEvent> {
+        self.recv_event_timed().map(|(event, _)| event)
+    }
+
+    // Also tells how long a packet has been in transit, see IpcMessage::queue_latency()
+    pub fn recv_event_timed(&mut self) -> Result<(IpcEvent, Option<Duration>)> {
+        let frame = match &mut self.socket {
+            SubTransport::Zmq(socket) => flatten(socket.recv().wait()?),
+            SubTransport::InProc(socket) => socket.recv()?,
+        };
+
+        let (event, sent_at) = IpcEvent::decode_timed(routing::unroute(frame))?;
+
+        Ok((event, sent_at.map(IpcMessage::queue_latency)))
     }
 }
 
 impl PubSocket {
     pub fn bind_tcp(address: &str, port: u16) -> Result<Self> {
-        let mut socket = zeromq::PubSocket::new();
+        return PubSocket::bind_zmq(&format!("tcp://{}:{}", address, port));
+    }
 
-        socket.bind(&format!("tcp://{}:{}", address, port)).wait()?;
+    pub fn bind_unix(address: &str) -> Result<Self> {
+        return PubSocket::bind_zmq(&format!("ipc://{}", address));
+    }
 
+    pub fn bind_inproc(name: &str) -> Result<Self> {
         Ok(PubSocket {
-            socket: socket,
+            socket: PubTransport::InProc(inproc::Publisher::bind(name)?),
         })
     }
 
-    pub fn bind_unix(address: &str) -> Result<Self> {
+    fn bind_zmq(endpoint: &str) -> Result<Self> {
         let mut socket = zeromq::PubSocket::new();
 
-        socket.bind(&format!("ipc://{}", address)).wait()?;
+        socket.bind(endpoint).wait()?;
 
         Ok(PubSocket {
-            socket: socket,
+            socket: PubTransport::Zmq(socket),
         })
     }
 
     pub fn send(&mut self, message: IpcMessage) -> Result<()> {
-        Ok(self.socket.send( message.into() ).wait()?)
+        self.send_frame(message.into())
+    }
+
+    pub fn send_control(&mut self, message: &ControlMessage) -> Result<()> {
+        self.send_frame(message.into())
+    }
+
+    // Only the game node subscribed with subscribe_node() gets these
+    pub fn send_to(&mut self, node: u8, message: IpcMessage) -> Result<()> {
+        self.send_frame(routing::route(node, message.into()))
+    }
+
+    pub fn send_control_to(&mut self, node: u8, message: &ControlMessage) -> Result<()> {
+        self.send_frame(routing::route(node, message.into()))
+    }
+
+    fn send_frame(&mut self, frame: Vec<u8>) -> Result<()> {
+        match &mut self.socket {
+            PubTransport::Zmq(socket) => Ok(socket.send( frame.into() ).wait()?),
+            PubTransport::InProc(socket) => Ok(socket.send( frame )),
+        }
     }
 }
 
 impl PushSocket {
     pub fn connect_tcp(address: &str, port: u16) -> Result<Self> {
-        let mut socket = zeromq::PushSocket::new();
+        return PushSocket::connect_zmq(&format!("tcp://{}:{}", address, port));
+    }
 
-        socket.connect(&format!("tcp://{}:{}", address, port)).wait()?;
+    pub fn connect_unix(address: &str) -> Result<Self> {
+        return PushSocket::connect_zmq(&format!("ipc://{}", address));
+    }
 
+    pub fn connect_inproc(name: &str) -> Result<Self> {
         Ok(PushSocket {
-            socket: socket,
+            socket: PushTransport::InProc(inproc::Pusher::connect(name)),
         })
     }
 
-    pub fn connect_unix(address: &str) -> Result<Self> {
+    fn connect_zmq(endpoint: &str) -> Result<Self> {
         let mut socket = zeromq::PushSocket::new();
 
-        socket.connect(&format!("ipc://{}", address)).wait()?;
+        socket.connect(endpoint).wait()?;
 
         Ok(PushSocket {
-            socket: socket,
+            socket: PushTransport::Zmq(socket),
         })
     }
 
     pub fn send(&mut self, message: IpcMessage) -> Result<()> {
-        Ok(self.socket.send( message.into() ).wait()?)
+        self.send_frame(message.into())
+    }
+
+    pub fn send_control(&mut self, message: &ControlMessage) -> Result<()> {
+        self.send_frame(message.into())
+    }
+
+    fn send_frame(&mut self, frame: Vec<u8>) -> Result<()> {
+        match &mut self.socket {
+            PushTransport::Zmq(socket) => Ok(socket.send( frame.into() ).wait()?),
+            PushTransport::InProc(socket) => socket.send( frame ),
+        }
     }
 }
 
 impl PullSocket {
     pub fn bind_tcp(address: &str, port: u16) -> Result<Self> {
-        let mut socket = zeromq::PullSocket::new();
+        return PullSocket::bind_zmq(&format!("tcp://{}:{}", address, port));
+    }
 
-        socket.bind(&format!("tcp://{}:{}", address, port)).wait()?;
+    pub fn bind_unix(address: &str) -> Result<Self> {
+        return PullSocket::bind_zmq(&format!("ipc://{}", address));
+    }
 
+    pub fn bind_inproc(name: &str) -> Result<Self> {
         Ok(PullSocket {
-            socket: socket,
+            socket: PullTransport::InProc(inproc::Puller::bind(name)?),
         })
     }
 
-    pub fn bind_unix(address: &str) -> Result<Self> {
+    fn bind_zmq(endpoint: &str) -> Result<Self> {
         let mut socket = zeromq::PullSocket::new();
 
-        socket.bind(&format!("ipc://{}", address)).wait()?;
+        socket.bind(endpoint).wait()?;
 
         Ok(PullSocket {
-            socket: socket,
+            socket: PullTransport::Zmq(socket),
         })
     }
 
+    // Control messages are skipped, use recv_event() to get those as well
     pub fn recv(&mut self) -> Result<IpcMessage> {
-        Ok(self.socket.recv().wait()?.into())
+        loop {
+            match self.recv_event()? {
+                IpcEvent::Packet(message) => return Ok(message),
+                IpcEvent::Control(_) => continue,
+            };
+        }
+    }
+
+    pub fn recv_event(&mut self) -> Result<IpcEvent> {
+        self.recv_event_timed().map(|(event, _)| event)
+    }
+
+    // Also tells how long a packet has been in transit, see IpcMessage::queue_latency()
+    pub fn recv_event_timed(&mut self) -> Result<(IpcEvent, Option<Duration>)> {
+        let frame = match &mut self.socket {
+            PullTransport::Zmq(socket) => flatten(socket.recv().wait()?),
+            PullTransport::InProc(socket) => socket.recv()?,
+        };
+
+        let (event, sent_at) = IpcEvent::decode_timed(frame)?;
+
+        Ok((event, sent_at.map(IpcMessage::queue_latency)))
     }
-}
\ No newline at end of file
+}
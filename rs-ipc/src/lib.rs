@@ -1,4 +1,6 @@
 mod ipc;
 
-pub use ipc::IpcMessage;
-pub use ipc::{SubSocket, PubSocket, PushSocket, PullSocket, Result};
\ No newline at end of file
+pub use ipc::{IpcMessage, IpcDecodeError};
+pub use ipc::{ControlMessage, IpcEvent};
+pub use ipc::{PacketSink, CapturingSink};
+pub use ipc::{SubSocket, PubSocket, PushSocket, PullSocket, Result, IpcError};
@@ -0,0 +1,5 @@
+mod metrics;
+mod metrics_server;
+
+pub use metrics::Metrics;
+pub use metrics_server::MetricsServer;
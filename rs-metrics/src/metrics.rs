@@ -0,0 +1,254 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum MetricKind {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+impl MetricKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            MetricKind::Counter => "counter",
+            MetricKind::Gauge => "gauge",
+            MetricKind::Histogram => "histogram",
+        }
+    }
+}
+
+struct Histogram {
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+// Series are keyed by name and rendered label set, so they come out sorted and grouped
+type SeriesKey = (String, String);
+
+type Collector = Box<dyn Fn(&Metrics) + Send + Sync>;
+
+/*
+  Minimal registry of counters, gauges and histograms, rendered in Prometheus text exposition format.
+  Collectors are run right before rendering, for values that are already counted elsewhere.
+ */
+pub struct Metrics {
+    descriptions: Mutex<BTreeMap<String, String>>,
+    counters: Mutex<BTreeMap<SeriesKey, u64>>,
+    gauges: Mutex<BTreeMap<SeriesKey, f64>>,
+    histograms: Mutex<BTreeMap<SeriesKey, Histogram>>,
+    collectors: Mutex<Vec<Collector>>,
+}
+
+impl Default for Metrics {
+    fn default() -> Metrics {
+        return Metrics::new();
+    }
+}
+
+impl Metrics {
+    // Upper bounds in seconds; fine enough for anything between a hash lookup and a database query
+    const BUCKETS: [f64; 12] = [0.0001, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0];
+
+    pub fn new() -> Metrics {
+        return Metrics {
+            descriptions: Mutex::new(BTreeMap::new()),
+            counters: Mutex::new(BTreeMap::new()),
+            gauges: Mutex::new(BTreeMap::new()),
+            histograms: Mutex::new(BTreeMap::new()),
+            collectors: Mutex::new(vec![]),
+        };
+    }
+
+    pub fn describe(&self, name: &str, help: &str) {
+        self.descriptions.lock().unwrap().insert(name.to_string(), help.to_string());
+    }
+
+    pub fn add_collector<F: Fn(&Metrics) + Send + Sync + 'static>(&self, collector: F) {
+        self.collectors.lock().unwrap().push(Box::new(collector));
+    }
+
+    pub fn inc(&self, name: &str, labels: &[(&str, &str)]) {
+        self.add(name, labels, 1);
+    }
+
+    pub fn add(&self, name: &str, labels: &[(&str, &str)], value: u64) {
+        *self.counters.lock().unwrap().entry(Metrics::key(name, labels)).or_insert(0) += value;
+    }
+
+    // For counters maintained elsewhere; meant to be used from collectors
+    pub fn set_counter(&self, name: &str, labels: &[(&str, &str)], value: u64) {
+        self.counters.lock().unwrap().insert(Metrics::key(name, labels), value);
+    }
+
+    pub fn set_gauge(&self, name: &str, labels: &[(&str, &str)], value: f64) {
+        self.gauges.lock().unwrap().insert(Metrics::key(name, labels), value);
+    }
+
+    pub fn observe(&self, name: &str, labels: &[(&str, &str)], value: Duration) {
+        let value = value.as_secs_f64();
+
+        let mut histograms = self.histograms.lock().unwrap();
+        let histogram = histograms.entry(Metrics::key(name, labels)).or_insert_with(|| Histogram {
+            buckets: vec![0; Metrics::BUCKETS.len()],
+            sum: 0.0,
+            count: 0,
+        });
+
+        for (i, bound) in Metrics::BUCKETS.iter().enumerate() {
+            if value <= *bound {
+                histogram.buckets[i] += 1;
+            }
+        }
+
+        histogram.sum += value;
+        histogram.count += 1;
+    }
+
+    pub fn render(&self) -> String {
+        for collector in self.collectors.lock().unwrap().iter() {
+            collector(self);
+        }
+
+        let descriptions = self.descriptions.lock().unwrap();
+        let mut out = String::new();
+        let mut last_name = String::new();
+
+        let mut header = |out: &mut String, name: &str, kind: MetricKind| {
+            if name == last_name {
+                return;
+            }
+
+            let help = match descriptions.get(name) {
+                Some(help) => help.as_str(),
+                None => "",
+            };
+
+            writeln!(out, "# HELP {} {}", name, help).unwrap();
+            writeln!(out, "# TYPE {} {}", name, kind.as_str()).unwrap();
+            last_name = name.to_string();
+        };
+
+        for ((name, labels), value) in self.counters.lock().unwrap().iter() {
+            header(&mut out, name, MetricKind::Counter);
+            writeln!(out, "{}{} {}", name, Metrics::braced(labels), value).unwrap();
+        }
+
+        for ((name, labels), value) in self.gauges.lock().unwrap().iter() {
+            header(&mut out, name, MetricKind::Gauge);
+            writeln!(out, "{}{} {}", name, Metrics::braced(labels), value).unwrap();
+        }
+
+        for ((name, labels), histogram) in self.histograms.lock().unwrap().iter() {
+            header(&mut out, name, MetricKind::Histogram);
+
+            for (i, bound) in Metrics::BUCKETS.iter().enumerate() {
+                writeln!(out, "{}_bucket{} {}", name, Metrics::braced(&Metrics::with_label(labels, "le", &bound.to_string())), histogram.buckets[i]).unwrap();
+            }
+
+            writeln!(out, "{}_bucket{} {}", name, Metrics::braced(&Metrics::with_label(labels, "le", "+Inf")), histogram.count).unwrap();
+            writeln!(out, "{}_sum{} {}", name, Metrics::braced(labels), histogram.sum).unwrap();
+            writeln!(out, "{}_count{} {}", name, Metrics::braced(labels), histogram.count).unwrap();
+        }
+
+        return out;
+    }
+
+    fn key(name: &str, labels: &[(&str, &str)]) -> SeriesKey {
+        let labels: Vec<String> = labels.iter()
+            .map(|(k, v)| format!("{}=\"{}\"", k, v.replace('\\', "\\\\").replace('"', "\\\"")))
+            .collect();
+
+        return (name.to_string(), labels.join(","));
+    }
+
+    fn with_label(labels: &str, name: &str, value: &str) -> String {
+        if labels.is_empty() {
+            return format!("{}=\"{}\"", name, value);
+        }
+
+        return format!("{},{}=\"{}\"", labels, name, value);
+    }
+
+    fn braced(labels: &str) -> String {
+        if labels.is_empty() {
+            return String::new();
+        }
+
+        return format!("{{{}}}", labels);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(out: &str) -> Vec<&str> {
+        return out.lines().collect();
+    }
+
+    #[test]
+    fn writes_headers_once_per_metric() {
+        let metrics = Metrics::new();
+        metrics.describe("requests_total", "Requests served");
+        metrics.inc("requests_total", &[("kind", "a")]);
+        metrics.inc("requests_total", &[("kind", "b")]);
+        metrics.set_gauge("sessions", &[], 3.0);
+
+        assert_eq!(lines(&metrics.render()), vec![
+            "# HELP requests_total Requests served",
+            "# TYPE requests_total counter",
+            "requests_total{kind=\"a\"} 1",
+            "requests_total{kind=\"b\"} 1",
+            "# HELP sessions ",
+            "# TYPE sessions gauge",
+            "sessions 3",
+        ]);
+    }
+
+    #[test]
+    fn escapes_label_values() {
+        let metrics = Metrics::new();
+        metrics.add("errors_total", &[("reason", "bad \"quote\" and \\slash")], 2);
+
+        let out = metrics.render();
+        assert!(out.contains("errors_total{reason=\"bad \\\"quote\\\" and \\\\slash\"} 2\n"), "{}", out);
+    }
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let metrics = Metrics::new();
+        metrics.observe("latency_seconds", &[("node", "0")], Duration::from_micros(50));
+        metrics.observe("latency_seconds", &[("node", "0")], Duration::from_millis(20));
+        metrics.observe("latency_seconds", &[("node", "0")], Duration::from_secs(2));
+
+        let out = metrics.render();
+        let out = lines(&out);
+
+        assert!(out.contains(&"# TYPE latency_seconds histogram"));
+        assert!(out.contains(&"latency_seconds_bucket{node=\"0\",le=\"0.0001\"} 1"));
+        assert!(out.contains(&"latency_seconds_bucket{node=\"0\",le=\"0.01\"} 1"));
+        assert!(out.contains(&"latency_seconds_bucket{node=\"0\",le=\"0.025\"} 2"));
+        assert!(out.contains(&"latency_seconds_bucket{node=\"0\",le=\"1\"} 2"));
+        assert!(out.contains(&"latency_seconds_bucket{node=\"0\",le=\"+Inf\"} 3"));
+        assert!(out.contains(&"latency_seconds_count{node=\"0\"} 3"));
+
+        // Buckets never decrease
+        let buckets: Vec<u64> = out.iter()
+            .filter(|l| l.starts_with("latency_seconds_bucket"))
+            .map(|l| l.rsplit(' ').next().unwrap().parse().unwrap())
+            .collect();
+        assert!(buckets.windows(2).all(|w| w[0] <= w[1]));
+    }
+
+    #[test]
+    fn runs_collectors_before_rendering() {
+        let metrics = Metrics::new();
+        metrics.add_collector(|m| m.set_counter("collected_total", &[], 42));
+
+        assert!(metrics.render().contains("collected_total 42\n"));
+    }
+}
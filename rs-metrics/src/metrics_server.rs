@@ -0,0 +1,62 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::Metrics;
+
+/*
+  Serves metrics to whoever asks, regardless of the path requested.
+  Meant to be bound to a local address only, there's no authentication whatsoever.
+ */
+pub struct MetricsServer {
+}
+
+impl MetricsServer {
+    // Scrapers send the whole request at once, anyone slower than that is just holding the accept thread
+    const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+    pub fn start(metrics: Arc<Metrics>, address: &str, port: u16) -> std::io::Result<()> {
+        let listener = TcpListener::bind(format!("{}:{}", address, port))?;
+
+        println!("Serving metrics on http://{}:{}/metrics", address, port);
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => match MetricsServer::respond(stream, &metrics) {
+                        Ok(_) => {},
+                        Err(e) => println!("Failed to serve metrics: {}", e),
+                    },
+                    Err(e) => println!("Failed to accept metrics connection: {}", e),
+                };
+            }
+        });
+
+        return Ok(());
+    }
+
+    fn respond(stream: TcpStream, metrics: &Metrics) -> std::io::Result<()> {
+        stream.set_read_timeout(Some(MetricsServer::READ_TIMEOUT))?;
+
+        let mut reader = BufReader::new(stream.try_clone()?);
+
+        // Skip the request, we have just one thing to say anyway
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+                break;
+            }
+        }
+
+        let body = metrics.render();
+
+        let mut stream = stream;
+        write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len())?;
+        stream.write_all(body.as_bytes())?;
+
+        return Ok(());
+    }
+}
@@ -1,35 +1,591 @@
-use rs_ipc::{PubSocket, PullSocket, PushSocket, Result, SubSocket};
+use std::collections::HashSet;
+use std::env;
+use std::fmt;
+use std::fs;
 
+use serde::{Deserialize, Serialize};
+
+use rs_ipc::{PubSocket, PullSocket, PushSocket, Result as IpcResult, SubSocket};
+
+#[derive(Debug, Clone)]
+pub struct NodeConfigError {
+    reason: String,
+}
+
+impl NodeConfigError {
+    pub fn new(reason: &str) -> NodeConfigError {
+        return NodeConfigError {reason: reason.to_string()};
+    }
+}
+
+impl fmt::Display for NodeConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "NodeConfigError: {}", self.reason)
+    }
+}
+
+// How nodes talk to each other; in-process one only works for nodes sharing the process
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IpcTransport {
+    Tcp,
+    Unix,
+    InProc,
+}
+
+/*
+  Settings shared by all the nodes.
+  Loaded from a TOML file, every field can also be overridden by RS_<FIELD NAME> environment variable.
+ */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct NodeConfig {
+    pub ipc_transport: IpcTransport,
+    pub in_queue_path: String,
+    pub out_queue_path: String,
     pub in_queue_addr: String,
     pub in_queue_port: u16,
     pub out_queue_addr: String,
     pub out_queue_port: u16,
+    pub gateway_addr: String,
+    pub gateway_port: u16,
+    pub gateway_bind_addr: String,
+    pub dispatch_addr: String,
+    pub dispatch_port: u16,
+    pub database_url: String,
+    pub json_data_dir: String,
+    pub lua_data_dir: String,
+    pub packet_ids_dir: String,
+    pub keys_dir: String,
+    pub max_sessions: usize,
+    pub handshakes_per_ip_per_minute: u32,
+    pub datagrams_per_session_per_second: u32,
+    pub ban_duration_secs: u64,
+    pub address_change_interval_secs: u64,
+    pub batch_window_ms: u64,
+    pub union_small_notifies: bool,
+    pub shutdown_timeout_secs: u64,
+    pub metrics_addr: String,
+    pub gateway_metrics_port: u16,
+    pub game_metrics_port: u16,
+    pub game_nodes: Vec<u8>,
+    pub game_node_id: u8,
+    pub node_heartbeat_interval_secs: u64,
+    pub node_timeout_secs: u64,
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        NodeConfig::new()
+    }
 }
 
 impl NodeConfig {
+    const CONFIG_PATH_VAR: &'static str = "RS_CONFIG";
+    const DEFAULT_CONFIG_PATH: &'static str = "./config.toml";
+    const ENV_PREFIX: &'static str = "RS_";
+
     pub fn new() -> Self {
         NodeConfig {
+            ipc_transport: IpcTransport::Tcp,
+            in_queue_path: "/tmp/samovar_in_queue".to_string(),
+            out_queue_path: "/tmp/samovar_out_queue".to_string(),
             in_queue_addr: "127.0.0.1".to_string(),
             in_queue_port: 9012,
             out_queue_addr: "127.0.0.1".to_string(),
             out_queue_port: 9014,
+            gateway_addr: "127.0.0.1".to_string(),
+            gateway_port: 4242,
+            gateway_bind_addr: "0.0.0.0".to_string(),
+            dispatch_addr: "127.0.0.1".to_string(),
+            dispatch_port: 8099,
+            database_url: "sqlite://./database.db3".to_string(),
+            json_data_dir: "./data/json".to_string(),
+            lua_data_dir: "./data/lua".to_string(),
+            packet_ids_dir: "./data/packet_ids".to_string(),
+            keys_dir: "keys".to_string(),
+            max_sessions: 256,
+            handshakes_per_ip_per_minute: 20,
+            datagrams_per_session_per_second: 500,
+            ban_duration_secs: 300,
+            address_change_interval_secs: 10,
+            batch_window_ms: 10,
+            union_small_notifies: false,
+            shutdown_timeout_secs: 10,
+            metrics_addr: "127.0.0.1".to_string(),
+            gateway_metrics_port: 9100,
+            game_metrics_port: 9101,
+            game_nodes: vec![0],
+            game_node_id: 0,
+            node_heartbeat_interval_secs: 2,
+            node_timeout_secs: 10,
         }
     }
 
-    pub fn bind_in_queue(&self) -> Result<PubSocket> {
-        PubSocket::bind_tcp(&self.in_queue_addr, self.in_queue_port)
+    /*
+      Reads the file pointed to by RS_CONFIG, or ./config.toml if the variable isn't set.
+      Missing default file is fine, defaults are used then; explicitly requested one has to exist.
+     */
+    pub fn load() -> Result<Self, NodeConfigError> {
+        let (path, explicit) = match env::var(NodeConfig::CONFIG_PATH_VAR) {
+            Ok(path) => (path, true),
+            Err(_) => (NodeConfig::DEFAULT_CONFIG_PATH.to_string(), false),
+        };
+
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if explicit => return Err(NodeConfigError::new(format!("Failed to read {}: {}", path, e).as_str())),
+            Err(_) => {
+                println!("Config file {} not found, using defaults", path);
+                String::new()
+            },
+        };
+
+        let config = NodeConfig::from_toml(&contents, env::vars())?;
+        config.validate()?;
+
+        return Ok(config);
     }
 
-    pub fn bind_out_queue(&self) -> Result<PullSocket> {
-        PullSocket::bind_tcp(&self.out_queue_addr, self.out_queue_port)
+    // Parses the config and applies overrides from the environment variables given
+    pub fn from_toml<I: Iterator<Item = (String, String)>>(contents: &str, vars: I) -> Result<Self, NodeConfigError> {
+        let config: NodeConfig = toml::from_str(contents)
+            .map_err(|e| NodeConfigError::new(format!("Malformed config: {}", e).as_str()))?;
+
+        // Round trip through TOML value lets us handle all the fields the same way, by their type
+        let mut table = match toml::Value::try_from(&config) {
+            Ok(toml::Value::Table(table)) => table,
+            _ => return Err(NodeConfigError::new("Failed to serialize config")),
+        };
+
+        for (name, value) in vars {
+            let field = match name.strip_prefix(NodeConfig::ENV_PREFIX) {
+                Some(field) => field.to_lowercase(),
+                None => continue,
+            };
+
+            let old_value = match table.get(&field) {
+                Some(old_value) => old_value,
+                None => continue, // RS_CONFIG and whatever else isn't ours
+            };
+
+            let new_value = match old_value {
+                toml::Value::String(_) => toml::Value::String(value.clone()),
+                toml::Value::Integer(_) => toml::Value::Integer(value.parse()
+                    .map_err(|e| NodeConfigError::new(format!("{} should be a number: {}", name, e).as_str()))?),
+                toml::Value::Boolean(_) => toml::Value::Boolean(value.parse()
+                    .map_err(|e| NodeConfigError::new(format!("{} should be true or false: {}", name, e).as_str()))?),
+                // Only lists of numbers are there so far, given as "1,2,3"
+                toml::Value::Array(_) => toml::Value::Array(value.split(',')
+                    .map(|item| item.trim().parse().map(toml::Value::Integer))
+                    .collect::<Result<Vec<toml::Value>, _>>()
+                    .map_err(|e| NodeConfigError::new(format!("{} should be a comma-separated list of numbers: {}", name, e).as_str()))?),
+                _ => return Err(NodeConfigError::new(format!("{} can't be set from environment", name).as_str())),
+            };
+
+            table.insert(field, new_value);
+        }
+
+        return toml::Value::Table(table).try_into()
+            .map_err(|e| NodeConfigError::new(format!("Invalid override: {}", e).as_str()));
     }
 
-    pub fn connect_in_queue(&self) -> Result<SubSocket> {
-        SubSocket::connect_tcp(&self.in_queue_addr, self.in_queue_port)
+    pub fn validate(&self) -> Result<(), NodeConfigError> {
+        let ports = [
+            ("in_queue_port", self.in_queue_port),
+            ("out_queue_port", self.out_queue_port),
+            ("gateway_port", self.gateway_port),
+            ("dispatch_port", self.dispatch_port),
+            ("gateway_metrics_port", self.gateway_metrics_port),
+            ("game_metrics_port", self.game_metrics_port),
+        ];
+
+        for (name, port) in ports.iter() {
+            if *port == 0 {
+                return Err(NodeConfigError::new(format!("{} can't be zero", name).as_str()));
+            }
+        }
+
+        if self.in_queue_endpoint() == self.out_queue_endpoint() {
+            return Err(NodeConfigError::new("In and out queues can't share the endpoint"));
+        }
+
+        #[cfg(not(unix))]
+        if self.ipc_transport == IpcTransport::Unix {
+            return Err(NodeConfigError::new("Unix sockets aren't available on this platform"));
+        }
+
+        for node in self.game_nodes.iter() {
+            match self.game_metrics_port.checked_add(*node as u16) {
+                Some(port) if port == self.gateway_metrics_port => return Err(NodeConfigError::new("Gateway and game server can't share the metrics port")),
+                Some(_) => {},
+                None => return Err(NodeConfigError::new(format!("Metrics port of game node {} is out of range", node).as_str())),
+            };
+        }
+
+        if self.game_nodes.is_empty() {
+            return Err(NodeConfigError::new("At least one game node is required"));
+        }
+
+        if self.game_nodes.iter().collect::<HashSet<_>>().len() != self.game_nodes.len() {
+            return Err(NodeConfigError::new("Game node IDs have to be unique"));
+        }
+
+        if self.max_sessions == 0 || self.handshakes_per_ip_per_minute == 0 || self.datagrams_per_session_per_second == 0 {
+            return Err(NodeConfigError::new("Session and rate limits have to be positive"));
+        }
+
+        if self.node_heartbeat_interval_secs == 0 || self.node_timeout_secs <= self.node_heartbeat_interval_secs {
+            return Err(NodeConfigError::new("node_heartbeat_interval_secs has to be positive and below node_timeout_secs"));
+        }
+
+        let strings = [
+            ("in_queue_path", &self.in_queue_path),
+            ("out_queue_path", &self.out_queue_path),
+            ("in_queue_addr", &self.in_queue_addr),
+            ("out_queue_addr", &self.out_queue_addr),
+            ("gateway_addr", &self.gateway_addr),
+            ("gateway_bind_addr", &self.gateway_bind_addr),
+            ("dispatch_addr", &self.dispatch_addr),
+            ("database_url", &self.database_url),
+            ("json_data_dir", &self.json_data_dir),
+            ("lua_data_dir", &self.lua_data_dir),
+            ("packet_ids_dir", &self.packet_ids_dir),
+            ("keys_dir", &self.keys_dir),
+            ("metrics_addr", &self.metrics_addr),
+        ];
+
+        for (name, value) in strings.iter() {
+            if value.is_empty() {
+                return Err(NodeConfigError::new(format!("{} can't be empty", name).as_str()));
+            }
+        }
+
+        return Ok(());
+    }
+
+    // Only game server cares which node it is, other nodes keep the default
+    pub fn validate_game_node(&self) -> Result<(), NodeConfigError> {
+        if !self.game_nodes.contains(&self.game_node_id) {
+            return Err(NodeConfigError::new(format!("Game node {} isn't listed in game_nodes", self.game_node_id).as_str()));
+        }
+
+        return Ok(());
     }
 
-    pub fn connect_out_queue(&self) -> Result<PushSocket> {
-        PushSocket::connect_tcp(&self.out_queue_addr, self.out_queue_port)
+    // Each game node exposes metrics on a port of its own
+    pub fn game_node_metrics_port(&self) -> u16 {
+        return self.game_metrics_port + self.game_node_id as u16;
     }
-}
\ No newline at end of file
+
+    /*
+      Rendezvous hashing: every user goes to the node scoring highest for them.
+      When a node goes down, only its users move elsewhere, the rest stay where they were.
+      If all the nodes are down, the choice is made among all of them, so that users still have somewhere to wait.
+     */
+    pub fn pick_game_node(&self, user_id: u32, down: &HashSet<u8>) -> u8 {
+        let live = self.game_nodes.iter()
+            .filter(|node| !down.contains(node))
+            .max_by_key(|node| NodeConfig::node_score(user_id, **node));
+
+        let node = match live {
+            Some(node) => Some(node),
+            None => self.game_nodes.iter().max_by_key(|node| NodeConfig::node_score(user_id, **node)),
+        };
+
+        return node.cloned().unwrap_or(self.game_node_id);
+    }
+
+    // SplitMix64 finalizer, stable across builds and platforms unlike std hashers
+    fn node_score(user_id: u32, node: u8) -> u64 {
+        let mut x = ((user_id as u64) << 8 | node as u64).wrapping_add(0x9e3779b97f4a7c15);
+
+        x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+
+        return x ^ (x >> 31);
+    }
+
+    // Unix socket path serves as the endpoint name for the in-process transport as well
+    fn in_queue_endpoint(&self) -> String {
+        match self.ipc_transport {
+            IpcTransport::Tcp => format!("{}:{}", self.in_queue_addr, self.in_queue_port),
+            IpcTransport::Unix | IpcTransport::InProc => self.in_queue_path.clone(),
+        }
+    }
+
+    fn out_queue_endpoint(&self) -> String {
+        match self.ipc_transport {
+            IpcTransport::Tcp => format!("{}:{}", self.out_queue_addr, self.out_queue_port),
+            IpcTransport::Unix | IpcTransport::InProc => self.out_queue_path.clone(),
+        }
+    }
+
+    pub fn bind_in_queue(&self) -> IpcResult<PubSocket> {
+        match self.ipc_transport {
+            IpcTransport::Tcp => PubSocket::bind_tcp(&self.in_queue_addr, self.in_queue_port),
+            IpcTransport::Unix => PubSocket::bind_unix(&self.in_queue_path),
+            IpcTransport::InProc => PubSocket::bind_inproc(&self.in_queue_path),
+        }
+    }
+
+    pub fn bind_out_queue(&self) -> IpcResult<PullSocket> {
+        match self.ipc_transport {
+            IpcTransport::Tcp => PullSocket::bind_tcp(&self.out_queue_addr, self.out_queue_port),
+            IpcTransport::Unix => PullSocket::bind_unix(&self.out_queue_path),
+            IpcTransport::InProc => PullSocket::bind_inproc(&self.out_queue_path),
+        }
+    }
+
+    pub fn connect_in_queue(&self) -> IpcResult<SubSocket> {
+        match self.ipc_transport {
+            IpcTransport::Tcp => SubSocket::connect_tcp(&self.in_queue_addr, self.in_queue_port),
+            IpcTransport::Unix => SubSocket::connect_unix(&self.in_queue_path),
+            IpcTransport::InProc => SubSocket::connect_inproc(&self.in_queue_path),
+        }
+    }
+
+    pub fn connect_out_queue(&self) -> IpcResult<PushSocket> {
+        match self.ipc_transport {
+            IpcTransport::Tcp => PushSocket::connect_tcp(&self.out_queue_addr, self.out_queue_port),
+            IpcTransport::Unix => PushSocket::connect_unix(&self.out_queue_path),
+            IpcTransport::InProc => PushSocket::connect_inproc(&self.out_queue_path),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(vars: &[(&str, &str)]) -> std::vec::IntoIter<(String, String)> {
+        return vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect::<Vec<_>>()
+            .into_iter();
+    }
+
+    fn reason(result: Result<NodeConfig, NodeConfigError>) -> String {
+        match result {
+            Ok(_) => panic!("Config was accepted"),
+            Err(e) => return e.reason,
+        };
+    }
+
+    fn invalid(config: NodeConfig) -> String {
+        match config.validate() {
+            Ok(_) => panic!("Config passed validation"),
+            Err(e) => return e.reason,
+        };
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        let config = NodeConfig::from_toml("", vars(&[])).unwrap();
+
+        assert_eq!(config.gateway_port, NodeConfig::new().gateway_port);
+        assert!(config.validate().is_ok());
+        assert!(config.validate_game_node().is_ok());
+    }
+
+    #[test]
+    fn reads_file() {
+        let config = NodeConfig::from_toml("gateway_port = 5000\ngame_nodes = [0, 1]\nipc_transport = \"unix\"", vars(&[])).unwrap();
+
+        assert_eq!(config.gateway_port, 5000);
+        assert_eq!(config.game_nodes, vec![0, 1]);
+        assert_eq!(config.ipc_transport, IpcTransport::Unix);
+        assert_eq!(config.dispatch_port, NodeConfig::new().dispatch_port);
+    }
+
+    #[test]
+    fn rejects_unknown_fields_in_file() {
+        assert!(reason(NodeConfig::from_toml("gateway_prot = 5000", vars(&[]))).starts_with("Malformed config"));
+    }
+
+    #[test]
+    fn applies_overrides() {
+        let config = NodeConfig::from_toml("gateway_port = 5000", vars(&[
+            ("RS_GATEWAY_PORT", "6000"),
+            ("RS_GAME_NODES", "0,1"),
+            ("RS_UNION_SMALL_NOTIFIES", "true"),
+            ("RS_DATABASE_URL", "sqlite://./other.db3"),
+            ("RS_IPC_TRANSPORT", "inproc"),
+        ])).unwrap();
+
+        assert_eq!(config.gateway_port, 6000);
+        assert_eq!(config.game_nodes, vec![0, 1]);
+        assert!(config.union_small_notifies);
+        assert_eq!(config.database_url, "sqlite://./other.db3");
+        assert_eq!(config.ipc_transport, IpcTransport::InProc);
+    }
+
+    #[test]
+    fn trims_list_items() {
+        let config = NodeConfig::from_toml("", vars(&[("RS_GAME_NODES", " 2 , 3 ")])).unwrap();
+
+        assert_eq!(config.game_nodes, vec![2, 3]);
+    }
+
+    #[test]
+    fn ignores_foreign_variables() {
+        let config = NodeConfig::from_toml("", vars(&[
+            ("RS_CONFIG", "/etc/samovar.toml"),
+            ("RS_GATEWAY_PROT", "abc"),
+            ("GATEWAY_PORT", "abc"),
+            ("PATH", "/usr/bin"),
+        ])).unwrap();
+
+        assert_eq!(config.gateway_port, NodeConfig::new().gateway_port);
+    }
+
+    #[test]
+    fn rejects_mistyped_overrides() {
+        assert!(reason(NodeConfig::from_toml("", vars(&[("RS_GATEWAY_PORT", "abc")]))).starts_with("RS_GATEWAY_PORT should be a number"));
+        assert!(reason(NodeConfig::from_toml("", vars(&[("RS_UNION_SMALL_NOTIFIES", "yes")]))).starts_with("RS_UNION_SMALL_NOTIFIES should be true or false"));
+        assert!(reason(NodeConfig::from_toml("", vars(&[("RS_GAME_NODES", "0,x")]))).starts_with("RS_GAME_NODES should be a comma-separated list"));
+    }
+
+    #[test]
+    fn rejects_out_of_range_overrides() {
+        assert!(reason(NodeConfig::from_toml("", vars(&[("RS_GATEWAY_PORT", "70000")]))).starts_with("Invalid override"));
+        assert!(reason(NodeConfig::from_toml("", vars(&[("RS_GAME_NODES", "0,256")]))).starts_with("Invalid override"));
+        assert!(reason(NodeConfig::from_toml("", vars(&[("RS_IPC_TRANSPORT", "pigeon")]))).starts_with("Invalid override"));
+    }
+
+    #[test]
+    fn rejects_zero_ports() {
+        let mut config = NodeConfig::new();
+        config.dispatch_port = 0;
+
+        assert_eq!(invalid(config), "dispatch_port can't be zero");
+    }
+
+    #[test]
+    fn rejects_shared_queue_endpoint() {
+        let mut config = NodeConfig::new();
+        config.out_queue_port = config.in_queue_port;
+
+        assert_eq!(invalid(config), "In and out queues can't share the endpoint");
+
+        let mut config = NodeConfig::new();
+        config.ipc_transport = IpcTransport::InProc;
+        config.out_queue_path = config.in_queue_path.clone();
+
+        assert_eq!(invalid(config), "In and out queues can't share the endpoint");
+    }
+
+    #[test]
+    fn rejects_metrics_port_clash() {
+        let mut config = NodeConfig::new();
+        config.game_nodes = vec![0, 1];
+        config.gateway_metrics_port = config.game_metrics_port + 1;
+
+        assert_eq!(invalid(config), "Gateway and game server can't share the metrics port");
+
+        let mut config = NodeConfig::new();
+        config.game_metrics_port = u16::MAX;
+        config.game_nodes = vec![0, 1];
+
+        assert_eq!(invalid(config), "Metrics port of game node 1 is out of range");
+    }
+
+    #[test]
+    fn rejects_bad_game_nodes() {
+        let mut config = NodeConfig::new();
+        config.game_nodes = vec![];
+
+        assert_eq!(invalid(config), "At least one game node is required");
+
+        let mut config = NodeConfig::new();
+        config.game_nodes = vec![0, 1, 0];
+
+        assert_eq!(invalid(config), "Game node IDs have to be unique");
+
+        let mut config = NodeConfig::new();
+        config.game_node_id = 1;
+
+        assert!(config.validate().is_ok());
+        assert!(config.validate_game_node().is_err());
+    }
+
+    #[test]
+    fn rejects_zero_limits() {
+        let mut config = NodeConfig::new();
+        config.handshakes_per_ip_per_minute = 0;
+
+        assert_eq!(invalid(config), "Session and rate limits have to be positive");
+    }
+
+    #[test]
+    fn rejects_heartbeat_not_below_timeout() {
+        let mut config = NodeConfig::new();
+        config.node_heartbeat_interval_secs = config.node_timeout_secs;
+
+        assert!(invalid(config).starts_with("node_heartbeat_interval_secs"));
+
+        let mut config = NodeConfig::new();
+        config.node_heartbeat_interval_secs = 0;
+
+        assert!(invalid(config).starts_with("node_heartbeat_interval_secs"));
+    }
+
+    #[test]
+    fn picks_same_node_every_time() {
+        let mut config = NodeConfig::new();
+        config.game_nodes = vec![0, 1, 2, 3];
+
+        let down = HashSet::new();
+
+        for user_id in 0..1000 {
+            assert_eq!(config.pick_game_node(user_id, &down), config.pick_game_node(user_id, &down));
+        }
+    }
+
+    #[test]
+    fn spreads_users_over_nodes() {
+        let mut config = NodeConfig::new();
+        config.game_nodes = vec![0, 1, 2, 3];
+
+        let picked: HashSet<u8> = (0..1000).map(|user_id| config.pick_game_node(user_id, &HashSet::new())).collect();
+
+        assert_eq!(picked, config.game_nodes.iter().cloned().collect());
+    }
+
+    #[test]
+    fn moves_only_users_of_node_going_down() {
+        let mut config = NodeConfig::new();
+        config.game_nodes = vec![0, 1, 2, 3];
+
+        let down: HashSet<u8> = [2].iter().cloned().collect();
+
+        for user_id in 0..1000 {
+            let before = config.pick_game_node(user_id, &HashSet::new());
+            let after = config.pick_game_node(user_id, &down);
+
+            match before {
+                2 => assert_ne!(after, 2),
+                _ => assert_eq!(after, before),
+            };
+        }
+    }
+
+    #[test]
+    fn picks_among_all_nodes_if_all_are_down() {
+        let mut config = NodeConfig::new();
+        config.game_nodes = vec![0, 1];
+
+        let down: HashSet<u8> = [0, 1].iter().cloned().collect();
+
+        for user_id in 0..100 {
+            assert_eq!(config.pick_game_node(user_id, &down), config.pick_game_node(user_id, &HashSet::new()));
+        }
+    }
+
+    #[test]
+    fn rejects_empty_strings() {
+        let config = NodeConfig::from_toml("", vars(&[("RS_KEYS_DIR", "")])).unwrap();
+
+        assert_eq!(invalid(config), "keys_dir can't be empty");
+    }
+}
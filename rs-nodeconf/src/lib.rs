@@ -1,3 +1,3 @@
 mod config;
 
-pub use crate::config::NodeConfig;
\ No newline at end of file
+pub use crate::config::{NodeConfig, NodeConfigError, IpcTransport};
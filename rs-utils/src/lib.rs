@@ -1,3 +1,7 @@
 mod time_manager;
+mod shutdown_signal;
+mod reload_signal;
 
-pub use time_manager::TimeManager;
\ No newline at end of file
+pub use time_manager::TimeManager;
+pub use shutdown_signal::ShutdownSignal;
+pub use reload_signal::ReloadSignal;
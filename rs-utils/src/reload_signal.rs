@@ -0,0 +1,59 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+/*
+  Raised on SIGHUP, which is what daemons traditionally reload their config on.
+  There's no such signal on Windows, so there it never fires.
+ */
+#[derive(Clone)]
+pub struct ReloadSignal {
+    requested: Arc<AtomicBool>,
+}
+
+impl ReloadSignal {
+    pub fn install() -> ReloadSignal {
+        let signal = ReloadSignal {
+            requested: Arc::new(AtomicBool::new(false)),
+        };
+
+        #[cfg(unix)]
+        {
+            let requested = signal.requested.clone();
+
+            thread::spawn(move || {
+                let runtime = match tokio::runtime::Builder::new_current_thread().enable_all().build() {
+                    Ok(runtime) => runtime,
+                    Err(e) => {
+                        println!("Failed to set up signal handling: {}", e);
+                        return;
+                    },
+                };
+
+                runtime.block_on(async {
+                    use tokio::signal::unix::{signal, SignalKind};
+
+                    let mut hangup = match signal(SignalKind::hangup()) {
+                        Ok(hangup) => hangup,
+                        Err(e) => {
+                            println!("Failed to listen for SIGHUP: {}", e);
+                            return;
+                        },
+                    };
+
+                    while hangup.recv().await.is_some() {
+                        println!("Config reload requested");
+                        requested.store(true, Ordering::SeqCst);
+                    }
+                });
+            });
+        }
+
+        return signal;
+    }
+
+    // Returns true once per signal received
+    pub fn take(&self) -> bool {
+        return self.requested.swap(false, Ordering::SeqCst);
+    }
+}
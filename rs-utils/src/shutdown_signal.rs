@@ -0,0 +1,67 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+/*
+  Flag raised on SIGINT or SIGTERM (Ctrl+C only on Windows).
+  Servers are expected to poll it from their main loops and wind down on their own.
+ */
+#[derive(Clone)]
+pub struct ShutdownSignal {
+    requested: Arc<AtomicBool>,
+}
+
+impl ShutdownSignal {
+    pub fn install() -> ShutdownSignal {
+        let signal = ShutdownSignal {
+            requested: Arc::new(AtomicBool::new(false)),
+        };
+
+        let requested = signal.requested.clone();
+
+        thread::spawn(move || {
+            let runtime = match tokio::runtime::Builder::new_current_thread().enable_all().build() {
+                Ok(runtime) => runtime,
+                Err(e) => {
+                    println!("Failed to set up signal handling: {}", e);
+                    return;
+                },
+            };
+
+            runtime.block_on(ShutdownSignal::wait_for_signal());
+
+            println!("Shutdown requested");
+            requested.store(true, Ordering::SeqCst);
+        });
+
+        return signal;
+    }
+
+    pub fn is_requested(&self) -> bool {
+        return self.requested.load(Ordering::SeqCst);
+    }
+
+    #[cfg(unix)]
+    async fn wait_for_signal() {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = match signal(SignalKind::terminate()) {
+            Ok(terminate) => terminate,
+            Err(e) => {
+                println!("Failed to listen for SIGTERM: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+                return;
+            },
+        };
+
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {},
+            _ = terminate.recv() => {},
+        };
+    }
+
+    #[cfg(not(unix))]
+    async fn wait_for_signal() {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}